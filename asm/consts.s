@@ -14,6 +14,58 @@
 # during interrupts and exceptions, reserve space for 32 registers, eight bytes wide
 .equ  IRQ_REGISTER_FRAME_SIZE,   (32 * 8)
 
+# each trap frame holds the 32 registers followed by the trap's CSRs and bookkeeping.
+# the layout must match IRQContext in ../src/irq.rs
+.equ  IRQ_FRAME_EPC,             (32)  # mepc on entry
+.equ  IRQ_FRAME_STATUS,          (33)  # mstatus on entry
+.equ  IRQ_FRAME_CAUSE,           (34)  # mcause on entry
+.equ  IRQ_FRAME_TVAL,            (35)  # mtval on entry
+.equ  IRQ_FRAME_PREVIOUS,        (36)  # address of the frame this trap interrupted, or 0
+.equ  IRQ_FRAME_CLOBBERED,       (37)  # mepc left by a nested trap's exit, or 0 if none
+.equ  IRQ_FRAME_SIZE,            (38 * 8)
+
+# per-CPU trap variables sit at the top of the IRQ stack, below mscratch.
+# these offsets are relative to mscratch, and must be zeroed at boot
+.equ  IRQ_VAR_DEPTH,             (-8)  # number of traps currently being handled
+.equ  IRQ_VAR_SCRATCH0,          (-16) # somewhere to keep t0 while building a frame
+.equ  IRQ_VAR_SCRATCH1,          (-24) # somewhere to keep t1 while building a frame
+.equ  IRQ_VAR_FRAME,             (-32) # address of the innermost trap frame, or 0
+.equ  IRQ_VARS_SIZE,             (4 * 8)
+
+# the outermost trap frame sits at a fixed position below the trap variables,
+# followed by an emergency stack for reporting runaway trap recursion.
+# the hypervisor's trap handler stack starts below that
+.equ  IRQ_OUTER_FRAME_OFFSET,    (IRQ_VARS_SIZE + IRQ_FRAME_SIZE)
+.equ  IRQ_EMERGENCY_STACK_SIZE,  (4 * 1024)
+.equ  IRQ_STACK_RESERVED,        (IRQ_OUTER_FRAME_OFFSET + IRQ_EMERGENCY_STACK_SIZE)
+
+# the lowest page of each IRQ stack is a guard page, locked as no-access with PMP entry 15
+# if it's free. a canary in the word above it is checked on every trap entry and exit.
+# update ../src/irq.rs if these change
+.equ  IRQ_STACK_GUARD_SIZE,      (PAGE_SIZE)
+.equ  IRQ_STACK_GUARD_NAPOT,     ((IRQ_STACK_GUARD_SIZE / 8) - 1) # pmpaddr low bits for a NAPOT region this size
+.equ  IRQ_STACK_GUARD_CFG,       ((1 << 7) | (3 << 3))            # locked, NAPOT, no access
+.equ  IRQ_STACK_CANARY,          (0xd105ca7ac0ffee00)
+
+# allow traps to nest this deep, eg: a guest memory access faulting during an SBI call.
+# a nested trap's handler needs at least this much IRQ stack left
+.equ  IRQ_MAX_DEPTH,             (3)
+.equ  IRQ_NESTED_MIN_FREE,       (8 * 1024)
+
+# early console used by the last-resort crash report before the device tree is parsed.
+# this defaults to the SiFive FU540's UART at 0x10010000, as before. set EARLY_CONSOLE_TYPE
+# and EARLY_CONSOLE_BASE to suit other boards, eg: for Qemu's virt machine,
+# EARLY_CONSOLE_NS16550 at 0x10000000. update ../src/crashdump.rs if the types change
+.equ  EARLY_CONSOLE_NONE,        (0)
+.equ  EARLY_CONSOLE_SIFIVE,      (1)
+.equ  EARLY_CONSOLE_NS16550,     (2)
+.equ  EARLY_CONSOLE_TYPE,        (EARLY_CONSOLE_SIFIVE)
+.equ  EARLY_CONSOLE_BASE,        (0x10010000)
+
+# maximum number of CPU cores the hypervisor can track by hart ID
+# update ../src/cpu.rs MAX_CPUS if this changes
+.equ  HV_MAX_CPUS,               (64)
+
 # the hypervisor is laid out as follows in physical memory on bootup, ascending:
 # (all addresses should be 4KB word aligned, and defined in the target ld script)
 #   __hypervisor_start = base of hypervisor
@@ -24,7 +76,8 @@
 #   .
 #   . per-CPU slabs of physical memory: each CPU core has...
 #   .   exeception / interrupt stack
-#   .   page of private variables
+#   .   page of private variables, owned by the hypervisor
+#   .   page of platform variables, owned by this platform code
 #   .   private heap space
 
 # describe per-CPU slab
@@ -36,4 +89,12 @@
 .equ HV_CPU_PRIVATE_VARS_BASE,  (HV_CPU_STACK_BASE + HV_CPU_STACK_SIZE)
 .equ HV_CPU_PRIVATE_VARS_SIZE,  (PAGE_SIZE)
 .equ HV_CPU_HEAP_BASE,          (HV_CPU_PRIVATE_PAGE_BASE + HV_CPU_PRIVATE_VARS_BASE)
-.equ HV_CPU_HEAP_AREA_SIZE,     (HV_CPU_SLAB_SIZE - HV_CPU_STACK_SIZE - HV_CPU_PRIVATE_VARS_SIZE)
+.equ HV_CPU_HEAP_AREA_SIZE,     (HV_CPU_SLAB_SIZE - HV_CPU_STACK_SIZE - HV_CPU_PRIVATE_VARS_SIZE - HV_CPU_PLATFORM_VARS_SIZE)
+
+# the platform's own per-CPU variables, eg: its linear ID and trap statistics, sit in
+# their own page above the hypervisor's page of private variables, so that the hypervisor
+# can use all of its page. zeroed at boot
+.equ HV_CPU_PLATFORM_VARS_SIZE,   (PAGE_SIZE)
+.equ HV_CPU_PLATFORM_VARS_OFFSET, (HV_CPU_PRIVATE_VARS_SIZE) # from base of private vars
+.equ HV_CPU_PLATFORM_VAR_CPU_ID,  (0)  # this core's linear CPU core ID, see ../src/cpu.rs
+.equ HV_CPU_PLATFORM_VAR_STATS,   (8)  # trap statistics counters, see ../src/stats.rs
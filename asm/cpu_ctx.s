@@ -15,7 +15,15 @@
 # save contents of physical CPU's supervisor CSRs and registers
 # stacked by IRQ handler into per virtual-core data structure
 # => a0 = pointer to SupervisorState structure to hold registers
+# <= a0 = 0 for success, or 1 if called from a nested trap
 platform_save_supervisor_cpu_state:
+  # the outermost trap's frame and the trap CSRs only hold the supervisor's
+  # state while handling the outermost trap. refuse if this trap is nested
+  csrrs t0, mscratch, x0
+  ld    t0, IRQ_VAR_DEPTH(t0)
+  li    t1, 1
+  bne   t0, t1, cpu_state_nested
+
   # preserve all supervisor CSRs
   csrrs t0, sstatus, x0
 # csrrs t1, sedeleg, x0   # TODO: needs N extension
@@ -54,7 +62,7 @@ platform_save_supervisor_cpu_state:
   addi  t0, a0, 112
 
   csrrs t1, mscratch, x0
-  addi  t1, t1, -(IRQ_REGISTER_FRAME_SIZE)
+  addi  t1, t1, -(IRQ_OUTER_FRAME_OFFSET)
   # t0 = base of register save block, t1 = base of IRQ saved registers
   # skip over x0
   addi  t1, t1, 8
@@ -71,13 +79,22 @@ from_stack_copy_loop:
   addi  t2, t2, -1
   bnez  t2, from_stack_copy_loop
 
+  mv    a0, x0
   ret
 
 # load saved supervisor CSRs and general-purpose registers from memory
 # to the IRQ stack and physical CPU CSRs so when we return to the
 # supervisor, the new context becomes active 
 # => a0 = pointer to SupervisorState structure from which to load registers
+# <= a0 = 0 for success, or 1 if called from a nested trap
 platform_load_supervisor_cpu_state:
+  # the outermost trap's frame and the trap CSRs only hold the supervisor's
+  # state while handling the outermost trap. refuse if this trap is nested
+  csrrs t0, mscratch, x0
+  ld    t0, IRQ_VAR_DEPTH(t0)
+  li    t1, 1
+  bne   t0, t1, cpu_state_nested
+
   # restore supervisor CSRs
   ld    t0, 0(a0)
 # ld    t1, 8(a0)       # sedeleg needs N extension
@@ -121,7 +138,7 @@ platform_load_supervisor_cpu_state:
   addi  t0, a0, 112
 
   csrrs t1, mscratch, x0
-  addi  t1, t1, -(IRQ_REGISTER_FRAME_SIZE)
+  addi  t1, t1, -(IRQ_OUTER_FRAME_OFFSET)
   # t0 = base of register save block, t1 = base of IRQ saved registers
   # skip over x0
   addi  t1, t1, 8
@@ -138,4 +155,9 @@ to_stack_copy_loop:
   addi  t2, t2, -1
   bnez  t2, to_stack_copy_loop
 
-  ret
\ No newline at end of file
+  mv    a0, x0
+  ret
+
+cpu_state_nested:
+  li    a0, 1
+  ret
@@ -12,6 +12,8 @@
 .align 8
 
 .global _start
+.global platform_hart_id_table
+.global platform_cpu_core_id_counter
 
 # hypervisor constants, such as global variable and lock locations
 # check this file for static hypervisor data layout
@@ -50,12 +52,25 @@ _start:
   # in order to scale to many cores, not waste too much memory, and to cope with non-linear
   # CPU ID / hart ID, each core will take memory using an atomic counter.
   # thus, memory is allocated on a first come, first served basis.
-  la        t1, cpu_core_id_counter
+  la        t1, platform_cpu_core_id_counter
   li        t2, 1
   amoadd.w  t3, t2, (t1)
   mv        a0, t3
   # now a0 = runtime-assigned linear CPU core ID, counting from 0
 
+  # park cores beyond those the hypervisor can track, so that every running
+  # core has a linear CPU core ID that can index per-CPU tables
+  li        t1, HV_MAX_CPUS
+  bgeu      t3, t1, infinite_loop
+
+  # record this core's hart ID against its linear CPU core ID so that other
+  # cores can find it, eg: to send it an inter-processor interrupt
+  la        t1, platform_hart_id_table
+  slli      t2, t3, 3
+  add       t1, t1, t2
+  csrrs     t2, mhartid, x0
+  sd        t2, (t1)
+
   # use t3 this as a multiplier from the end of the hypervisor, using shifts to keep things easy
   la        t1, __hypervisor_end
   slli      t3, t3, HV_CPU_SLAB_SHIFT
@@ -70,11 +85,33 @@ _start:
   # t4 = top of the stack, t2 = stack size, t1 = stack base from slab base
   csrrw     x0, mscratch, t4
 
+  # no traps are being handled yet
+  sd        x0, IRQ_VAR_DEPTH(t4)
+  sd        x0, IRQ_VAR_FRAME(t4)
+
+  # zero this CPU's platform variables, which sit in the page above its private variables
+  li        t5, HV_CPU_PLATFORM_VARS_OFFSET
+  add       t5, t5, t4
+  li        t6, HV_CPU_PLATFORM_VARS_SIZE
+  add       t6, t6, t5
+zero_platform_vars_loop:
+  sd        x0, (t5)
+  addi      t5, t5, 8
+  bltu      t5, t6, zero_platform_vars_loop
+
+  # record this core's linear CPU core ID where it can be quickly found
+  li        t5, HV_CPU_PLATFORM_VARS_OFFSET + HV_CPU_PLATFORM_VAR_CPU_ID
+  add       t5, t5, t4
+  sd        a0, (t5)
+
   # use the lower half of the exception stack to bring up the hypervisor
   # set the boot stack pointer to halfway down the IRQ stack
   srli      t1, t2, 1
   sub       sp, t4, t1
 
+  # guard the bottom of the IRQ stack against overflow (corrupts t0-t2)
+  call      irq_stack_guard_init
+
   # set up early exception/interrupt handling (corrupts t0)
   # leave hardware interrupts disabled for now
   call      irq_early_init
@@ -127,9 +164,16 @@ infinite_loop:
 
 # variables
 .align 8
-cpu_core_id_counter:
+platform_cpu_core_id_counter:
 .word 0
 
+# table of hart IDs, indexed by linear CPU core ID. -1 = no core
+.align 8
+platform_hart_id_table:
+.rept HV_MAX_CPUS
+.dword -1
+.endr
+
 .align 8
 clear_bss_finished:
 .word 0
\ No newline at end of file
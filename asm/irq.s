@@ -11,6 +11,8 @@
 .align 8
 
 .global irq_early_init
+.global irq_stack_guard_init
+.global platform_guru_meditation
 
 # hypervisor constants, such as stack and lock locations
 .include "src/platform-riscv/asm/consts.s"
@@ -59,6 +61,53 @@ irq_early_init_post_delegation:
   csrrsi x0, mstatus, 1 << 3
   ret
 
+# protect this CPU core's IRQ stack from overflowing into the memory below it.
+# write the canary above the stack's guard page, and if PMP entry 15 is implemented
+# and unused, lock it over the guard page so that any access to it faults
+# <= corrupts t0, t1, t2
+irq_stack_guard_init:
+  csrrs t0, mscratch, x0
+  li    t1, HV_CPU_STACK_SIZE
+  sub   t0, t0, t1          # t0 = base of the IRQ stack, and its guard page
+  li    t1, IRQ_STACK_GUARD_SIZE
+  add   t1, t1, t0          # t1 = lowest word of the stack above the guard page
+  li    t2, IRQ_STACK_CANARY
+  sd    t2, (t1)
+
+  # on RV64, PMP entry 15's settings are in the top byte of pmpcfg2. skip if it's in use
+  csrrs t1, pmpcfg2, x0
+  srli  t1, t1, 56
+  bne   x0, t1, irq_stack_guard_done
+
+  # check pmpaddr15 is implemented by writing the guard page's NAPOT address and reading it back
+  srli  t2, t0, 2
+  ori   t2, t2, IRQ_STACK_GUARD_NAPOT
+  csrrw x0, pmpaddr15, t2
+  csrrs t1, pmpaddr15, x0
+  bne   t1, t2, irq_stack_guard_unavailable
+
+  li    t1, IRQ_STACK_GUARD_CFG
+  slli  t1, t1, 56
+  csrrs x0, pmpcfg2, t1
+  sfence.vma x0, x0
+  ret
+
+irq_stack_guard_unavailable:
+  csrrw x0, pmpaddr15, x0   # rely on the canary alone
+irq_stack_guard_done:
+  ret
+
+# macro to check the IRQ stack's canary is intact, jumping to
+# irq_stack_overflow if not. corrupts the given two registers
+.macro CHECK_CANARY r1, r2
+  csrrs \r1, mscratch, x0
+  li    \r2, HV_CPU_STACK_SIZE - IRQ_STACK_GUARD_SIZE
+  sub   \r1, \r1, \r2
+  ld    \r1, (\r1)
+  li    \r2, IRQ_STACK_CANARY
+  bne   \r1, \r2, irq_stack_overflow
+.endm
+
 # macro to generate store instructions to push given 'reg' register
 .macro PUSH_REG reg
   # sw  x\reg, (\reg * 4)(sp) # RV32
@@ -74,17 +123,76 @@ irq_early_init_post_delegation:
 
 .align 8
 # Entry point for machine-level handler of interrupts and exceptions
-# interrupts are automatically disabled on entry.
-# right now, IRQs are non-reentrant. if an IRQ handler is interrupted, the previous one will
-# be discarded. do not enable hardware interrupts. any exceptions will be unfortunate.
+# interrupts are automatically disabled on entry. do not enable hardware interrupts.
+#
+# traps may nest up to IRQ_MAX_DEPTH deep, eg: a guest memory read faulting during an
+# SBI call. the outermost trap's frame sits at a fixed position at the top of the IRQ stack,
+# and a nested trap's frame is built below the interrupted handler's sp. each frame links to
+# the one it interrupted. a nested trap overwrites mepc, mcause, mtval and parts of mstatus,
+# so the interrupted trap's copies are restored on its exit, unless the hypervisor has since
+# written a new mepc. if traps nest too deep, or a nested trap's sp is off the IRQ stack,
+# the handler switches to an emergency stack and reports the frames rather than corrupt them
 machine_irq_handler:
   # get exception handler stack from mscratch by swapping it for interrupted code's sp
   # the handler stack descends from mscratch, the per-CPU variables ascend from it
   csrrw  sp, mscratch, sp
   # now: sp = top of IRQ stack. mscratch = interrupted code's sp
+  sd    t0, IRQ_VAR_SCRATCH0(sp)
+  sd    t1, IRQ_VAR_SCRATCH1(sp)
+
+  # increment the trap depth, and pick where to build the frame
+  ld    t0, IRQ_VAR_DEPTH(sp)
+  addi  t1, t0, 1
+  sd    t1, IRQ_VAR_DEPTH(sp)
+  beq   x0, t0, irq_outermost_frame
+
+  # t0 = number of traps already being handled
+  li    t1, IRQ_MAX_DEPTH
+  bgtu  t0, t1, irq_halt          # trapped while reporting runaway recursion. give up
+  beq   t0, t1, irq_recursion_frame
+
+  # nested trap: the interrupted handler's sp must be on the IRQ stack, below the
+  # reserved area, with enough room left for this trap's frame and handler
+  csrrs t0, mscratch, x0
+  sub   t0, sp, t0                # t0 = distance of interrupted sp down from the top of the IRQ stack
+  li    t1, IRQ_STACK_RESERVED
+  bltu  t0, t1, irq_recursion_frame
+  li    t1, HV_CPU_STACK_SIZE - IRQ_NESTED_MIN_FREE
+  bgtu  t0, t1, irq_recursion_frame
+  csrrs t0, mscratch, x0
+  andi  t0, t0, -16               # keep the frame 16-byte aligned
+  addi  t0, t0, -(IRQ_FRAME_SIZE)
+  j     irq_build_frame
+
+irq_outermost_frame:
+  addi  t0, sp, -(IRQ_OUTER_FRAME_OFFSET)
+  j     irq_build_frame
+
+irq_recursion_frame:
+  # build the frame at the top of the emergency stack
+  addi  t0, sp, -(IRQ_OUTER_FRAME_OFFSET + IRQ_FRAME_SIZE)
+
+irq_build_frame:
+  # t0 = base of the new frame. stack the interrupted code's sp as x2 (sp)
+  csrrs t1, mscratch, x0
+  sd    t1, (2 * 8)(t0)
+
+  # link the new frame to the one it interrupted
+  ld    t1, IRQ_VAR_FRAME(sp)
+  sd    t1, (IRQ_FRAME_PREVIOUS * 8)(t0)
+  sd    x0, (IRQ_FRAME_CLOBBERED * 8)(t0)
+  sd    t0, IRQ_VAR_FRAME(sp)
+
+  # right now mscratch is corrupt with the interrupted code's sp.
+  # this means hypervisor functions relying on mscratch will break, so restore it.
+  csrrw x0, mscratch, sp
+
+  # switch to the new frame and recover t0 and t1
+  mv    t1, sp
+  mv    sp, t0
+  ld    t0, IRQ_VAR_SCRATCH0(t1)
+  ld    t1, IRQ_VAR_SCRATCH1(t1)
 
-  # reserve space to preserve all 32 GP registers
-  addi  sp, sp, -(IRQ_REGISTER_FRAME_SIZE)
   # skip x0 (zero) and x2 (sp), stack all other registers
   PUSH_REG 1
   .set reg, 3
@@ -93,16 +201,6 @@ machine_irq_handler:
     .set reg, reg + 1
   .endr
 
-  # stack the interrupted code's sp as x2 (sp) in register block
-  csrrs t0, mscratch, x0
-  # sw  t0, (2 * 4)(sp) # RV32
-  sd    t0, (2 * 8)(sp)
-
-  # right now mscratch is corrupt with the interrupted code's sp.
-  # this means hypervisor functions relying on mscratch will break, so restore it.
-  addi  t0, sp, IRQ_REGISTER_FRAME_SIZE
-  csrrw x0, mscratch, t0
-
   # for syscalls, riscv sets epc to the address of the syscall instruction.
   # in which case, we need to advance epc 4 bytes to the next instruction.
   # (all instructions are 4 bytes long, for RV32 and RV64)
@@ -112,16 +210,100 @@ machine_irq_handler:
   # context isn't scheduled in, epc will be correct.
   csrrs t0, mcause, x0
   li    t1, 9             # mcause = 9 for environment call from supervisor-to-hypervisor
+  beq   t0, t1, irq_skip_ecall
+  li    t1, 10            # mcause = 10 for environment call from a virtualized supervisor
   bne   t0, t1, continue  # ... all usermode ecalls are handled at the supervisor level
-  csrrs t2, mepc, x0      # ... and the hypervisor doesn't make ecalls into itself
+irq_skip_ecall:           # ... and the hypervisor doesn't make ecalls into itself
+  csrrs t2, mepc, x0
   addi  t2, t2, 4
   csrrw x0, mepc, t2
 
 continue:
-  # pass current sp to exception/hw handler as a pointer. this'll allow
-  # the higher-level hypervisor access and modify any of the stacked registers
+  # stack the trap CSRs so they can be restored if a nested trap overwrites them
+  csrrs t0, mepc, x0
+  sd    t0, (IRQ_FRAME_EPC * 8)(sp)
+  csrrs t0, mstatus, x0
+  sd    t0, (IRQ_FRAME_STATUS * 8)(sp)
+  csrrs t0, mcause, x0
+  sd    t0, (IRQ_FRAME_CAUSE * 8)(sp)
+  csrrs t0, mtval, x0
+  sd    t0, (IRQ_FRAME_TVAL * 8)(sp)
+
+  # stop now if the IRQ stack has overflowed
+  CHECK_CANARY t0, t1
+
+  # pass the frame to exception/hw handler as a pointer. this'll allow
+  # the higher-level hypervisor access and modify any of the stacked registers.
+  # s1 is stacked above, so use it to keep hold of the frame across the call
   add   a0, sp, x0
+  add   s1, sp, x0
+
+  # is this the emergency frame? if so, report it and stop
+  csrrs t0, mscratch, x0
+  addi  t1, t0, -(IRQ_OUTER_FRAME_OFFSET + IRQ_FRAME_SIZE)
+  beq   t1, sp, irq_recursion
+
+  # the outermost handler runs below the emergency stack. nested handlers
+  # run below their frames, which are below the interrupted handler's stack
+  addi  t1, t0, -(IRQ_OUTER_FRAME_OFFSET)
+  bne   t1, sp, irq_call_handler
+  li    t1, IRQ_EMERGENCY_STACK_SIZE
+  sub   sp, sp, t1
+
+irq_call_handler:
   call  hypervisor_irq_handler
+  add   sp, s1, x0
+
+  # decrement the trap depth and unlink this frame
+  csrrs t0, mscratch, x0
+  ld    t1, IRQ_VAR_DEPTH(t0)
+  addi  t1, t1, -1
+  sd    t1, IRQ_VAR_DEPTH(t0)
+  ld    t2, (IRQ_FRAME_PREVIOUS * 8)(sp)
+  sd    t2, IRQ_VAR_FRAME(t0)
+
+  # returning to a lower privilege mode leaves every trap handler on this core,
+  # eg: the hypervisor switched in another context. start afresh on the next trap.
+  # only the outermost trap can do this: a nested trap would return to the
+  # supervisor with the hypervisor's registers, so report it and stop
+  csrrs t3, mstatus, x0
+  li    t4, 3 << 11       # mstatus.MPP = 3 for machine mode
+  and   t3, t3, t4
+  beq   t3, t4, irq_still_machine
+  bne   x0, t1, irq_nested_escape
+  sd    x0, IRQ_VAR_DEPTH(t0)
+  sd    x0, IRQ_VAR_FRAME(t0)
+  j     irq_check_clobbered
+
+irq_still_machine:
+  # if this trap was nested, tell the interrupted trap what mepc we're leaving behind
+  beq   x0, t1, irq_check_clobbered
+  csrrs t3, mepc, x0
+  sd    t3, (IRQ_FRAME_CLOBBERED * 8)(t2)
+
+irq_check_clobbered:
+  # if a nested trap overwrote this trap's CSRs, and the hypervisor hasn't
+  # since written a new mepc, put back this trap's CSRs
+  ld    t3, (IRQ_FRAME_CLOBBERED * 8)(sp)
+  beq   x0, t3, irq_restore_registers
+  csrrs t4, mepc, x0
+  bne   t3, t4, irq_restore_registers
+  ld    t3, (IRQ_FRAME_EPC * 8)(sp)
+  csrrw x0, mepc, t3
+  ld    t3, (IRQ_FRAME_CAUSE * 8)(sp)
+  csrrw x0, mcause, t3
+  ld    t3, (IRQ_FRAME_TVAL * 8)(sp)
+  csrrw x0, mtval, t3
+  # only restore mstatus's previous privilege (MPP, bits 11-12) and interrupt enable (MPIE, bit 7)
+  li    t4, (1 << 12) | (1 << 11) | (1 << 7)
+  csrrc x0, mstatus, t4
+  ld    t3, (IRQ_FRAME_STATUS * 8)(sp)
+  and   t3, t3, t4
+  csrrs x0, mstatus, t3
+
+irq_restore_registers:
+  # make sure the handler didn't overflow the IRQ stack
+  CHECK_CANARY t3, t4
 
   # restore all stacked registers, skipping zero (x0) and sp (x2)
   .set reg, 31
@@ -135,11 +317,38 @@ continue:
   PULL_REG 2
   mret
 
+# traps nested too deep, or a nested trap's sp was bad. a0 = emergency frame.
+# report the frames and don't come back
+irq_recursion:
+  call  platform_irq_recursion
+
+# a nested trap's handler tried to return to a lower privilege mode. sp = its frame.
+# report it from the emergency stack and don't come back
+irq_nested_escape:
+  add   a0, sp, x0
+  csrrs t0, mscratch, x0
+  addi  sp, t0, -(IRQ_OUTER_FRAME_OFFSET + IRQ_FRAME_SIZE)
+  call  platform_irq_nested_escape
+  j     platform_guru_meditation
+
+# the IRQ stack's canary has been overwritten. sp = frame of the trap being handled.
+# report it from the emergency stack and don't come back
+irq_stack_overflow:
+  add   a0, sp, x0
+  csrrs t0, mscratch, x0
+  addi  sp, t0, -(IRQ_OUTER_FRAME_OFFSET + IRQ_FRAME_SIZE)
+  call  platform_irq_stack_overflow
+  j     platform_guru_meditation
+
+# trapped while reporting runaway recursion. fall back to the early console
+irq_halt:
+  j     platform_guru_meditation
+
 
 .align 8
-# use this interrupt handler to debug failures during system bring-up
-# on boards that use SiFive's UART serial controller.
-# this doesn't return, so it's not for normal use. it outputs:
+# last-resort crash report for use during system bring-up, before the device tree
+# has been parsed, or when the Rust crash dump path can't run. this writes to the
+# early console, if one is defined, and doesn't return. it outputs:
 #
 # I SoC CPU core ID
 # C Cause of the trap
@@ -148,7 +357,7 @@ continue:
 # R Return address register at time of trap
 # S Stack pointer at time of trap
 #
-sifive_guru_mediation:
+platform_guru_meditation:
   mv    t6, ra
 
   call print_newline
@@ -172,6 +381,7 @@ sifive_guru_mediation:
   call print_hex
 
 halt:
+  wfi
   j halt
 
 # t0 = value to write, t1 = one character label, scratches t1, t2, t3
@@ -207,14 +417,43 @@ print_newline:
   addi  sp, sp, 8
   ret
 
-# t1 = character to write, scratches t4, t5
+# t1 = character to write to the early console, scratches t4, t5
 print_char:
-  li    t4, 0x10010000
-  lw    t4, (t4)
-  bne   x0, t4, print_char
-  li    t4, 0x10010000
+  la    t5, platform_early_console_type
+  ld    t5, (t5)
+  li    t4, EARLY_CONSOLE_SIFIVE
+  beq   t4, t5, print_char_sifive
+  li    t4, EARLY_CONSOLE_NS16550
+  beq   t4, t5, print_char_ns16550
+  ret                       # no early console, so nowhere to write
+
+print_char_sifive:
+  la    t4, platform_early_console_base
+  ld    t4, (t4)
+  lw    t5, (t4)            # bit 31 of txdata is set while the transmit FIFO is full
+  bltz  t5, print_char_sifive
   sw    t1, (t4)
   ret
 
+print_char_ns16550:
+  la    t4, platform_early_console_base
+  ld    t4, (t4)
+  lbu   t5, 5(t4)           # line status register...
+  andi  t5, t5, 1 << 5      # ...bit 5 is set when the transmit holding register is empty
+  beq   x0, t5, print_char_ns16550
+  sb    t1, (t4)
+  ret
+
 chars:
-.ascii "0123456789abcdef"
\ No newline at end of file
+.ascii "0123456789abcdef"
+
+# location and type of the early console. these default to the values in consts.s
+# and are updated by the crash dump code once the device tree has been parsed
+.section .data
+.align 8
+.global platform_early_console_base
+.global platform_early_console_type
+platform_early_console_base:
+.dword EARLY_CONSOLE_BASE
+platform_early_console_type:
+.dword EARLY_CONSOLE_TYPE
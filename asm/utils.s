@@ -7,10 +7,15 @@
 .align 8
 
 .global platform_cpu_private_variables
+.global platform_cpu_id
+.global platform_cpu_stats
+.global platform_cpu_stats_of
+.global platform_cpu_stats_size
 .global platform_cpu_heap_base
 .global platform_cpu_heap_size
 .global platform_set_supervisor_return
 .global platform_read_u32_as_prev_mode
+.global platform_probe_sstc
 
 # hypervisor constants, such as stack and lock locations
 .include "src/platform-riscv/asm/consts.s"
@@ -22,11 +27,45 @@ platform_cpu_private_variables:
   csrrs a0, mscratch, x0
   ret
 
-# return base address of this CPU's heap - right above private vars 
+# return this CPU's linear CPU core ID, recorded at boot
+# <= a0 = linear CPU core ID (corrupts t0)
+platform_cpu_id:
+  csrrs a0, mscratch, x0  # private vars start above CPU IRQ stack
+  li    t0, HV_CPU_PLATFORM_VARS_OFFSET + HV_CPU_PLATFORM_VAR_CPU_ID
+  add   a0, a0, t0
+  ld    a0, (a0)
+  ret
+
+# return pointer to this CPU's trap statistics counters, in its platform variables
+# <= a0 = pointer to counters (corrupts t0)
+platform_cpu_stats:
+  csrrs a0, mscratch, x0  # private vars start above CPU IRQ stack
+  li    t0, HV_CPU_PLATFORM_VARS_OFFSET + HV_CPU_PLATFORM_VAR_STATS
+  add   a0, a0, t0
+  ret
+
+# return pointer to the given CPU's trap statistics counters, found from its memory slab
+# => a0 = linear CPU core ID
+# <= a0 = pointer to counters (corrupts t0)
+platform_cpu_stats_of:
+  slli  a0, a0, HV_CPU_SLAB_SHIFT
+  la    t0, __hypervisor_end
+  add   a0, a0, t0
+  li    t0, HV_CPU_PRIVATE_VARS_BASE + HV_CPU_PLATFORM_VARS_OFFSET + HV_CPU_PLATFORM_VAR_STATS
+  add   a0, a0, t0
+  ret
+
+# return the space available for each CPU's trap statistics counters
+# <= a0 = size in bytes
+platform_cpu_stats_size:
+  li    a0, HV_CPU_PLATFORM_VARS_SIZE - HV_CPU_PLATFORM_VAR_STATS
+  ret
+
+# return base address of this CPU's heap - right above private and platform vars
 # <= a0 = pointer to heap base (corrupts t0)
 platform_cpu_heap_base:
   csrrs a0, mscratch, x0  # private vars start above CPU IRQ stack
-  li    t0, HV_CPU_PRIVATE_VARS_SIZE
+  li    t0, HV_CPU_PRIVATE_VARS_SIZE + HV_CPU_PLATFORM_VARS_SIZE
   add   a0, a0, t0
   ret
 
@@ -48,12 +87,19 @@ platform_set_supervisor_return:
   csrrs x0, mstatus, t0
   ret
 
-# read a u32 from memory as the previous privilege mode. if this read fails,
-# it will generate a permisison or access fault as the previous privilege mode
+# read a u32 from memory as the previous privilege mode. if the read faults,
+# the fault is caught here rather than passed to the trap handler: the trap CSRs
+# it overwrote are put back and the fault's cause is returned, so that the caller
+# can decide what to do, eg: forward the fault to the previous privilege mode
 # => a0 = address to read
-# <= a0 = u32 read in
+#    a1 = pointer to u32 to hold the value read in
+# <= a0 = 0 for success, or mcause of the fault if the read failed
 platform_read_u32_as_prev_mode:
   csrrs t5, mstatus, x0           # t5 = mstatus in case the read faults
+  csrrci x0, mstatus, 1 << 3      # don't let an interrupt reach the fault catcher
+  csrrs a2, mepc, x0              # keep the trap CSRs a fault would overwrite
+  csrrs a3, mcause, x0
+  csrrs a4, mtval, x0
   la    t6, trap_read_u32_fault   # t6 = address of our temporary fault catcher
   csrrw t6, mtvec, t6             # swap t6 and original fault handler
 
@@ -75,28 +121,61 @@ platform_read_u32_as_prev_mode_byte_loop:
   lbu   t2, (t2)                  # read in the byte without sign extending
   or    t0, t0, t2                # paste the byte's bits onto low byte of u32 register
   bne   x0, t1, platform_read_u32_as_prev_mode_byte_loop
-  mv    a0, t0                    # fetched 32-bit word expected in a0
-
-  # sign extend a0 if necessary to keep rust happy
-  # TODO: not sure why this is neccesary. some FFI issue?
-  li    t0, 1 << 31
-  and   t0, t0, a0
-  beq   x0, t0, platform_read_u32_as_prev_mode_done
-  li    t0, 0xffffffff00000000
-  or    a0, a0, t0
   j     platform_read_u32_as_prev_mode_done
 
 platform_read_u32_as_prev_mode_aligned:
-  lw   a0, (a0)                   # do the 32-bit aligned read into a0
+  lw    t0, (a0)                  # do the 32-bit aligned read into t0
 
 platform_read_u32_as_prev_mode_done:
   csrrw x0, mstatus, t5           # restore previous status
   csrrw x0, mtvec, t6             # restore the original fault handler
+  sw    t0, (a1)                  # store the value now MPRV is clear
+  mv    a0, x0
   ret
 
-# pin the blame on the previous mode (in mstatus.mpp) by swapping in previous mstatus
-# this means the fault will appear from that mode
+# the read faulted. we're still in machine mode, on the caller's stack, so
+# put back the state the fault changed and return its cause to the caller
+.align 2
 trap_read_u32_fault:
-  csrrw x0, mstatus, t5           # restore the mstatus with the previous mpp, and no MPRV + MXR set
+  csrrs t0, mcause, x0            # t0 = why the read failed
+  csrrw x0, mstatus, t5           # restore the interrupted trap's mpp and mpie, and clear MPRV + MXR
   csrrw x0, mtvec, t6             # restore the original fault handler
-  jalr  x0, t6                    # jump to fault handler to deal with error
+  csrrw x0, mepc, a2              # restore the trap CSRs the fault overwrote
+  csrrw x0, mcause, a3
+  csrrw x0, mtval, a4
+  mv    a0, t0
+  ret
+
+# check whether this CPU core implements the Sstc extension by setting menvcfg.STCE
+# (bit 63) and reading it back. menvcfg is put back as it was. cores without menvcfg
+# raise an illegal instruction exception, which is caught here
+# <= a0 = 1 if Sstc is implemented, or 0 if not
+platform_probe_sstc:
+  csrrs t5, mstatus, x0           # t5 = mstatus in case the probe faults
+  csrrci x0, mstatus, 1 << 3      # don't let an interrupt reach the fault catcher
+  csrrs a2, mepc, x0              # keep the trap CSRs a fault would overwrite
+  csrrs a3, mcause, x0
+  csrrs a4, mtval, x0
+  la    t6, trap_probe_sstc_fault # t6 = address of our temporary fault catcher
+  csrrw t6, mtvec, t6             # swap t6 and original fault handler
+
+  li    t0, 1 << 63
+  csrrs t1, 0x30a, t0             # t1 = original menvcfg, and try to set STCE
+  csrrs t2, 0x30a, x0             # t2 = menvcfg with STCE set, if it's implemented
+  csrrw x0, 0x30a, t1             # put back the original menvcfg
+  and   a0, t2, t0
+  snez  a0, a0
+  j     platform_probe_sstc_done
+
+# menvcfg isn't implemented. put back the trap CSRs the fault overwrote
+.align 2
+trap_probe_sstc_fault:
+  mv    a0, x0
+  csrrw x0, mepc, a2
+  csrrw x0, mcause, a3
+  csrrw x0, mtval, a4
+
+platform_probe_sstc_done:
+  csrrw x0, mstatus, t5           # restore previous status
+  csrrw x0, mtvec, t6             # restore the original fault handler
+  ret
@@ -0,0 +1,323 @@
+/* diosix RV64 advanced platform-level interrupt controller (APLIC) driver
+ *
+ * The APLIC gathers wired interrupts from devices. In MSI mode, it forwards
+ * them as messages to the CPU cores' IMSIC interrupt files. In direct mode,
+ * it delivers them to the cores itself, much like a PLIC.
+ *
+ * Derived from the RISC-V Advanced Interrupt Architecture specification:
+ * https://github.com/riscv/riscv-aia
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::ptr::{read_volatile, write_volatile};
+use spin::Mutex;
+use alloc::vec::Vec;
+use super::physmem;
+use super::imsic;
+
+lazy_static!
+{
+    /* acquire PINNED_APLIC lock before accessing the system's machine-level APLIC domain */
+    static ref PINNED_APLIC: Mutex<Option<Aplic>> = Mutex::new(None);
+}
+
+/* APLIC memory-mapped registers, relative to the domain's base address */
+const APLIC_DOMAINCFG:      usize = 0x0000;
+const APLIC_SOURCECFG_BASE: usize = 0x0004;     /* one 32-bit word per source, from source 1 */
+const APLIC_MMSIADDRCFG:    usize = 0x1bc0;     /* machine-level IMSIC address, low word... */
+const APLIC_MMSIADDRCFGH:   usize = 0x1bc4;     /* ...and high word */
+const APLIC_SMSIADDRCFG:    usize = 0x1bc8;     /* supervisor-level IMSIC address, low word... */
+const APLIC_SMSIADDRCFGH:   usize = 0x1bcc;     /* ...and high word */
+const APLIC_SETIPNUM:       usize = 0x1cdc;     /* write a source number to make it pending */
+const APLIC_CLRIPNUM:       usize = 0x1ddc;     /* write a source number to clear its pending bit */
+const APLIC_SETIENUM:       usize = 0x1edc;     /* write a source number to enable it */
+const APLIC_CLRIENUM:       usize = 0x1fdc;     /* write a source number to disable it */
+const APLIC_TARGET_BASE:    usize = 0x3004;     /* one 32-bit word per source, from source 1 */
+const APLIC_IDC_BASE:       usize = 0x4000;     /* direct mode: per-hart delivery control... */
+const APLIC_IDC_STRIDE:     usize = 32;         /* ...with each hart's registers this far apart */
+const APLIC_IDC_IDELIVERY:  usize = 0x00;
+const APLIC_IDC_ITHRESHOLD: usize = 0x08;
+const APLIC_IDC_CLAIMI:     usize = 0x1c;
+
+/* domaincfg fields */
+const DOMAINCFG_IE:         u32 = 1 << 8;       /* enable interrupts from this domain */
+const DOMAINCFG_DM:         u32 = 1 << 2;       /* delivery mode: 1 = MSI, 0 = direct */
+
+/* msiaddrcfgh fields */
+const MSIADDRCFGH_LHXS_SHIFT:   usize = 20;     /* low hart index shift */
+const MSIADDRCFGH_LHXW_SHIFT:   usize = 12;     /* low hart index width */
+const MSIADDRCFGH_PPN_MASK:     usize = 0xfff;  /* high bits of the base page number */
+
+/* target register fields. in MSI mode, the hart index is in bits 18-31,
+the guest file in bits 12-17, and the interrupt identity in bits 0-10.
+in direct mode, the hart index is in bits 18-31, and the priority in bits 0-7 */
+const TARGET_HART_SHIFT:    usize = 18;
+const TARGET_GUEST_SHIFT:   usize = 12;
+const TARGET_EIID_MASK:     usize = 0x7ff;
+const TARGET_PRIO_MASK:     usize = 0xff;
+
+/* claimi holds the claimed source number in bits 16-25 */
+const CLAIMI_SOURCE_SHIFT:  usize = 16;
+const CLAIMI_SOURCE_MASK:   usize = 0x3ff;
+
+/* APLICs support up to 1023 sources, numbered from 1 */
+pub const APLIC_MAX_SOURCES: usize = 1024;
+
+/* how a source's wire signals an interrupt */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SourceMode
+{
+    Inactive = 0,       /* ignore the source */
+    Detached = 1,       /* only raised by software */
+    RisingEdge = 4,
+    FallingEdge = 5,
+    LevelHigh = 6,
+    LevelLow = 7
+}
+
+/* describe one interrupt domain of an APLIC */
+#[derive(Debug, Clone)]
+pub struct Aplic
+{
+    base: physmem::PhysMemBase,     /* base MMIO address of the domain */
+    size: physmem::PhysMemSize,     /* size of the domain's MMIO area in bytes */
+    nr_sources: usize,              /* number of interrupt sources */
+    msi: bool,                      /* true to deliver interrupts as MSIs to IMSICs */
+    harts: Vec<usize>               /* direct mode: mhartid of each hart index */
+}
+
+impl Aplic
+{
+    /* create a new APLIC domain object
+       => base = base MMIO address of the domain
+          size = size of the domain's MMIO area in bytes
+          nr_sources = number of interrupt sources wired to the domain
+          msi = true to forward interrupts to IMSICs, false for direct delivery
+          harts = in direct mode, the mhartid of each CPU core the domain delivers to, in hart index order
+       <= APLIC object */
+    pub fn new(base: physmem::PhysMemBase, size: physmem::PhysMemSize, nr_sources: usize, msi: bool, harts: Vec<usize>) -> Aplic
+    {
+        Aplic
+        {
+            base,
+            size,
+            nr_sources: if nr_sources >= APLIC_MAX_SOURCES { APLIC_MAX_SOURCES - 1 } else { nr_sources },
+            msi,
+            harts
+        }
+    }
+
+    /* register this APLIC as the pinned controller, allowing other platform code to find it */
+    pub fn pin(&self)
+    {
+        let mut pinned = PINNED_APLIC.lock();
+        *pinned = Some(self.clone());
+    }
+
+    /* return information about this domain */
+    pub fn get_mmio_base(&self) -> physmem::PhysMemBase { self.base }
+    pub fn get_mmio_size(&self) -> physmem::PhysMemSize { self.size }
+    pub fn get_nr_sources(&self) -> usize { self.nr_sources }
+    pub fn is_msi(&self) -> bool { self.msi }
+
+    /* in direct mode, return the hart index of the given CPU core within the domain, if any */
+    pub fn hart_index(&self, hart: usize) -> Option<usize>
+    {
+        self.harts.iter().position(|h| *h == hart)
+    }
+
+    /* set up the domain, with all sources inactive and disabled. in MSI mode, point the domain
+       at the machine and supervisor-level IMSICs. call this once, from one CPU core
+       => machine = machine-level IMSIC to deliver messages to, if any
+          supervisor = supervisor-level IMSIC to deliver messages to, if any */
+    pub fn init(&self, machine: Option<&imsic::Imsic>, supervisor: Option<&imsic::Imsic>)
+    {
+        self.write_reg(APLIC_DOMAINCFG, 0);
+
+        for source in 1..(self.nr_sources + 1)
+        {
+            self.write_reg(APLIC_CLRIENUM, source as u32);
+            self.configure(source, SourceMode::Inactive);
+        }
+
+        if self.msi == true
+        {
+            if let Some(m) = machine
+            {
+                self.set_msi_address(APLIC_MMSIADDRCFG, APLIC_MMSIADDRCFGH, m, 0);
+            }
+            if let Some(s) = supervisor
+            {
+                self.set_msi_address(APLIC_SMSIADDRCFG, APLIC_SMSIADDRCFGH, s, s.get_guest_index_bits());
+            }
+            self.write_reg(APLIC_DOMAINCFG, DOMAINCFG_IE | DOMAINCFG_DM);
+        }
+        else
+        {
+            self.write_reg(APLIC_DOMAINCFG, DOMAINCFG_IE);
+        }
+    }
+
+    /* set how a source's wire signals an interrupt
+       => source = interrupt source number, from 1 to the number of sources
+          mode = how the source signals an interrupt
+       <= true for success, or false for a bad source number */
+    pub fn configure(&self, source: usize, mode: SourceMode) -> bool
+    {
+        if self.valid_source(source) == false { return false; }
+        self.write_reg(APLIC_SOURCECFG_BASE + ((source - 1) * 4), mode as u32);
+        true
+    }
+
+    /* in MSI mode, send a source's interrupts to an interrupt file
+       => source = interrupt source number
+          hart_index = hart index of the CPU core's interrupt file in the IMSIC
+          guest = guest file number, or 0 for the core's own file
+          eiid = interrupt identity to send
+       <= true for success, or false for bad parameters */
+    pub fn route_msi(&self, source: usize, hart_index: usize, guest: usize, eiid: usize) -> bool
+    {
+        if self.valid_source(source) == false || self.msi == false { return false; }
+        let target = (hart_index << TARGET_HART_SHIFT) | (guest << TARGET_GUEST_SHIFT) | (eiid & TARGET_EIID_MASK);
+        self.write_reg(APLIC_TARGET_BASE + ((source - 1) * 4), target as u32);
+        true
+    }
+
+    /* in direct mode, deliver a source's interrupts to a CPU core
+       => source = interrupt source number
+          hart_index = hart index of the CPU core within the domain
+          priority = priority of the source, 1 being highest
+       <= true for success, or false for bad parameters */
+    pub fn route_direct(&self, source: usize, hart_index: usize, priority: usize) -> bool
+    {
+        if self.valid_source(source) == false || self.msi == true { return false; }
+        let target = (hart_index << TARGET_HART_SHIFT) | (priority & TARGET_PRIO_MASK);
+        self.write_reg(APLIC_TARGET_BASE + ((source - 1) * 4), target as u32);
+        true
+    }
+
+    /* allow or stop a source from raising interrupts
+       => source = interrupt source number
+       <= true for success, or false for a bad source number */
+    pub fn enable(&self, source: usize) -> bool { self.update_source(APLIC_SETIENUM, source) }
+    pub fn disable(&self, source: usize) -> bool { self.update_source(APLIC_CLRIENUM, source) }
+
+    /* set or clear a source's pending bit, eg: to raise an interrupt from a detached source
+       => source = interrupt source number
+       <= true for success, or false for a bad source number */
+    pub fn set_pending(&self, source: usize) -> bool { self.update_source(APLIC_SETIPNUM, source) }
+    pub fn clear_pending(&self, source: usize) -> bool { self.update_source(APLIC_CLRIPNUM, source) }
+
+    /* in direct mode, prepare a CPU core to receive interrupts from this domain
+       => hart_index = hart index of the CPU core within the domain */
+    pub fn init_hart_direct(&self, hart_index: usize)
+    {
+        self.write_reg(idc_reg(hart_index, APLIC_IDC_ITHRESHOLD), 0);
+        self.write_reg(idc_reg(hart_index, APLIC_IDC_IDELIVERY), 1);
+    }
+
+    /* in direct mode, claim the highest-priority pending interrupt for a CPU core.
+       claiming also completes the interrupt: level-triggered sources will be raised
+       again if they are still asserted
+       => hart_index = hart index of the CPU core within the domain
+       <= source number, or None if nothing is pending */
+    pub fn claim_direct(&self, hart_index: usize) -> Option<usize>
+    {
+        match (self.read_reg(idc_reg(hart_index, APLIC_IDC_CLAIMI)) as usize >> CLAIMI_SOURCE_SHIFT) & CLAIMI_SOURCE_MASK
+        {
+            0 => None,
+            source => Some(source)
+        }
+    }
+
+    /* check an interrupt source is within range */
+    fn valid_source(&self, source: usize) -> bool
+    {
+        source > 0 && source <= self.nr_sources
+    }
+
+    /* write a source number to one of the set/clear-by-number registers */
+    fn update_source(&self, reg: usize, source: usize) -> bool
+    {
+        if self.valid_source(source) == false { return false; }
+        self.write_reg(reg, source as u32);
+        true
+    }
+
+    /* describe the location of an IMSIC's interrupt files. the MSI address for hart index n
+       is the base address with n shifted up by the size of each core's group of files
+       => low, high = offsets of the msiaddrcfg register pair to write
+          imsic = IMSIC to describe
+          guest_index_bits = number of bits selecting a core's guest files */
+    fn set_msi_address(&self, low: usize, high: usize, imsic: &imsic::Imsic, guest_index_bits: usize)
+    {
+        let base = match imsic.get_files().iter().min_by_key(|f| f.index)
+        {
+            Some(f) => f.address,
+            None => return
+        };
+
+        /* number of bits needed to hold the highest hart index */
+        let max_index = imsic.get_files().iter().map(|f| f.index).max().unwrap_or(0);
+        let hart_index_bits = (0usize.leading_zeros() - max_index.leading_zeros()) as usize;
+
+        /* the hart index width is shared by both levels, and only set in mmsiaddrcfgh */
+        let width = match low
+        {
+            APLIC_MMSIADDRCFG => hart_index_bits << MSIADDRCFGH_LHXW_SHIFT,
+            _ => 0
+        };
+
+        let ppn = base >> 12;
+        self.write_reg(low, ppn as u32);
+        self.write_reg(high, ((guest_index_bits << MSIADDRCFGH_LHXS_SHIFT) | width |
+                              ((ppn >> 32) & MSIADDRCFGH_PPN_MASK)) as u32);
+    }
+
+    /* access a 32-bit APLIC register at the given offset from the base address */
+    fn read_reg(&self, offset: usize) -> u32
+    {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write_reg(&self, offset: usize, value: u32)
+    {
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+    }
+}
+
+/* return the offset of a hart's direct-mode interrupt delivery control register */
+fn idc_reg(hart_index: usize, reg: usize) -> usize
+{
+    APLIC_IDC_BASE + (hart_index * APLIC_IDC_STRIDE) + reg
+}
+
+/* in direct mode, claim the highest-priority pending interrupt for the running CPU core
+   using the pinned APLIC, or None if nothing's pending or the core isn't in the domain.
+   in MSI mode, interrupts are claimed from the core's IMSIC file instead */
+pub fn claim() -> Option<usize>
+{
+    match &*PINNED_APLIC.lock()
+    {
+        Some(aplic) if aplic.is_msi() == false => aplic.claim_direct(aplic.hart_index(read_csr!(mhartid))?),
+        _ => None
+    }
+}
+
+/* stop an interrupt source from interrupting any CPU core using the pinned APLIC */
+pub fn disable(source: usize)
+{
+    if let Some(aplic) = &*PINNED_APLIC.lock()
+    {
+        aplic.disable(source);
+    }
+}
+
+/* return true if an APLIC has been pinned for platform code to use */
+pub fn is_pinned() -> bool
+{
+    PINNED_APLIC.lock().is_some()
+}
@@ -8,13 +8,21 @@
 #[allow(dead_code)] 
 
 use core::fmt;
+use core::ops::Index;
+use core::ptr::read_volatile;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
 use alloc::string::String;
+use alloc::vec::Vec;
 use super::physmem::PhysMemBase;
+use super::vclock::{self, VirtualClock};
+use super::rtc::{self, VirtualRtc};
+use super::timer::TimerValue;
+use super::timerqueue::{self, TimerOwner, TimerHandle};
 
 extern "C"
 {
-    fn platform_save_supervisor_cpu_state(state: &mut SupervisorState);
-    fn platform_load_supervisor_cpu_state(state: &SupervisorState);
+    fn platform_save_supervisor_cpu_state(state: &mut SupervisorState) -> usize;
+    fn platform_load_supervisor_cpu_state(state: &SupervisorState) -> usize;
 
     fn platform_save_supervisor_fp32_state(regs:  &mut FP32Registers);
     fn platform_save_supervisor_fp64_state(regs:  &mut FP64Registers);
@@ -22,17 +30,102 @@ extern "C"
     fn platform_load_supervisor_fp64_state(regs:  &FP64Registers);
 
     fn platform_set_supervisor_return();
+    fn platform_probe_sstc() -> usize;
+
+    /* linear CPU core ID to hart ID table, and number of cores that have booted */
+    static platform_hart_id_table: [usize; MAX_CPUS];
+    static platform_cpu_core_id_counter: u32;
+    fn platform_cpu_id() -> CPUcount;
+}
+
+/* maximum number of CPU cores that can be tracked by hart ID. see ../asm/consts.s HV_MAX_CPUS */
+pub const MAX_CPUS: usize = 64;
+
+/* a value kept for each possible CPU core, indexed by linear CPU core ID. wrap each value
+in a lock or use an atomic type if other cores can access it */
+pub struct PerCpu<T>
+{
+    values: Vec<T>
+}
+
+impl<T> PerCpu<T>
+{
+    /* create a value for each of the MAX_CPUS possible CPU cores
+       => init = function returning a core's initial value, called once per core */
+    pub fn new<F>(init: F) -> PerCpu<T> where F: Fn() -> T
+    {
+        let mut values = Vec::with_capacity(MAX_CPUS);
+        for _ in 0..MAX_CPUS
+        {
+            values.push(init());
+        }
+        PerCpu { values }
+    }
+
+    /* return the running CPU core's value */
+    pub fn this(&self) -> &T
+    {
+        &self.values[get_cpu_id()]
+    }
+}
+
+/* look up a core's value by its linear CPU core ID */
+impl<T> Index<CPUcount> for PerCpu<T>
+{
+    type Output = T;
+
+    fn index(&self, cpu: CPUcount) -> &T
+    {
+        &self.values[cpu]
+    }
 }
 
+/* value in the hart ID table for a CPU core that hasn't booted */
+const NO_HART_ID: usize = !0;
+
 /* flags within CPUFeatures, derived from misa */
 const CPUFEATURES_DP_FPU: usize          = 1 << 3;  /* extension D: Double-Precision Floating-Point */
 const CPUFEATURES_SP_FPU: usize          = 1 << 5;  /* extension F: Single-Precision Floating-Point */
+const CPUFEATURES_HYPERVISOR: usize      = 1 << 7;  /* extension H: Hypervisor */
 const CPUFEATURES_SUPERVISOR_MODE: usize = 1 << 18; /* supervisor mode is implemented */
 const CPUFEATURES_USER_MODE: usize       = 1 << 20; /* user mode is implemented */
 
 /* ensure supervisor code starts in supervisor mode by setting mpp=1 in mstatus */
 const MSTATUS_MPP_SUPERVISOR: Reg = 1 << 11;
 
+/* supervisor interrupt pending bits in sip and mip. the timer and external bits
+are read-only in sip, so the hypervisor must set and clear them via mip */
+pub const SIP_SSIP: Reg = 1 << 1; /* software interrupt */
+pub const SIP_STIP: Reg = 1 << 5; /* timer interrupt */
+pub const SIP_SEIP: Reg = 1 << 9; /* external interrupt */
+const SIP_VIRTUAL_MASK: Reg = SIP_SSIP | SIP_STIP | SIP_SEIP;
+
+/* Sstc extension: setting menvcfg.STCE lets supervisors program their own timer
+interrupt through stimecmp (0x14d). mcounteren.TM must be set for them to access it */
+const MENVCFG_STCE:  Reg = 1 << 63;
+const MCOUNTEREN_TM: Reg = 1 << 1;
+
+/* stimecmp value that never raises a timer interrupt */
+const STIMECMP_DISABLED: Reg = !0;
+
+/* set when every CPU core is known to implement the Sstc extension */
+static SSTC_PRESENT: AtomicBool = AtomicBool::new(false);
+
+/* whether each CPU core has been found to implement Sstc when probed */
+const SSTC_UNPROBED: u8 = 0;
+const SSTC_ABSENT:   u8 = 1;
+const SSTC_FOUND:    u8 = 2;
+
+lazy_static!
+{
+    /* results of probing each CPU core for Sstc, indexed by linear CPU core ID */
+    static ref SSTC_PROBED: PerCpu<AtomicU8> = PerCpu::new(|| AtomicU8::new(SSTC_UNPROBED));
+
+    /* the running supervisor's stimecmp on each CPU core, in the supervisor's view of time,
+    when its clock doesn't match the host timer and its accesses to stimecmp are emulated */
+    static ref EMULATED_STIMECMP: PerCpu<AtomicU64> = PerCpu::new(|| AtomicU64::new(STIMECMP_DISABLED as u64));
+}
+
 /* control bits for detecting dirty state of FP registers in mstatus */
 const MSTATUS_FS_SHIFT: Reg = 13; /* FS field starts at bit 13 in mstatus */
 const MSTATUS_FS_MASK:  Reg = 0b11; /* FS field is 2 bits wide */
@@ -76,6 +169,135 @@ pub struct SupervisorState
 
     /* standard register set (skip x0) */
     registers: [Reg; 31],
+
+    /* supervisor timer compare, if the Sstc extension is in use, in the supervisor's
+    view of time. this is saved and restored separately from the low-level CSRs and registers */
+    stimecmp: Reg,
+
+    /* the supervisor's view of time */
+    clock: VirtualClock,
+
+    /* the supervisor's virtual wall-clock RTC */
+    rtc: VirtualRtc,
+
+    /* the hypervisor's identifier for this virtual CPU core, and its queued timer deadline,
+    if any, so that the deadline can be moved when the supervisor's clock changes */
+    timer_owner: Option<usize>,
+    queued: Option<TimerHandle>
+}
+
+impl SupervisorState
+{
+    /* raise or withdraw virtual interrupts for this supervisor. the change
+       takes effect when this state is next loaded into a CPU core, so interrupts
+       can be queued for descheduled virtual CPU cores
+       => bits = mask of SIP_SSIP, SIP_STIP and SIP_SEIP bits to change
+          pending = true to raise the interrupts, false to withdraw them */
+    pub fn set_virtual_irq_pending(&mut self, bits: Reg, pending: bool)
+    {
+        let bits = bits & SIP_VIRTUAL_MASK;
+        match pending
+        {
+            true => self.sip = self.sip | bits,
+            false => self.sip = self.sip & !bits
+        }
+    }
+
+    /* return the mask of SIP_SSIP, SIP_STIP and SIP_SEIP bits pending for this supervisor */
+    pub fn get_virtual_irq_pending(&self) -> Reg
+    {
+        self.sip & SIP_VIRTUAL_MASK
+    }
+
+    /* return a copy of this supervisor's clock */
+    pub fn get_clock(&self) -> VirtualClock { self.clock }
+
+    /* replace this supervisor's clock. this takes effect when the state is next loaded */
+    pub fn set_clock(&mut self, clock: VirtualClock) { self.clock = clock; }
+
+    /* freeze and unfreeze this supervisor's clock, such as while its capsule is paused.
+       these take effect when the state is next loaded. a paused clock never reaches the
+       supervisor's timer deadline, so any queued deadline is cancelled on pausing, and
+       requeued on this CPU core on resuming, shifted by the time spent paused
+       => host_now = host timer's current exact value */
+    pub fn pause_clock(&mut self, host_now: u64)
+    {
+        self.clock.pause(host_now);
+        if let Some(handle) = self.queued.take()
+        {
+            timerqueue::cancel(handle);
+        }
+    }
+
+    pub fn resume_clock(&mut self, host_now: u64)
+    {
+        self.clock.resume(host_now);
+        if let Some(id) = self.timer_owner
+        {
+            self.queue_timer_deadline(id);
+        }
+    }
+
+    /* return a copy of, or replace, this supervisor's virtual RTC. when a guest sets the time
+       through one of its virtual CPU cores, the hypervisor can copy that core's RTC to the others */
+    pub fn get_rtc(&self) -> VirtualRtc { self.rtc }
+    pub fn set_rtc(&mut self, rtc: VirtualRtc) { self.rtc = rtc; }
+
+    /* return the exact host timer value at which this supervisor's own timer interrupt fires,
+       or None if it isn't using the Sstc extension to program one, or its clock is paused.
+       the interrupt is only raised while the supervisor is running, so the hypervisor
+       should schedule it by then */
+    pub fn get_timer_deadline(&self) -> Option<u64>
+    {
+        match (sstc_present(), self.stimecmp)
+        {
+            (true, STIMECMP_DISABLED) | (false, _) => None,
+            (true, deadline) => self.clock.to_host(deadline as u64)
+        }
+    }
+
+    /* with Sstc, the hardware only raises a supervisor's timer interrupt while the supervisor
+       is running. once its state is saved, it's the hypervisor's job to make sure the virtual
+       core is running again by its deadline. call this after save_supervisor_cpu_state() to
+       queue the deadline on this CPU core as the virtual core's timer, replacing any it had.
+       when it expires, the hypervisor should schedule the virtual core, and it should cancel
+       the deadline using the returned handle if the core runs again before then
+       => id = hypervisor's identifier for this virtual CPU core
+       <= handle of the queued deadline, or None if the supervisor has no deadline or there's no timer */
+    pub fn queue_timer_deadline(&mut self, id: usize) -> Option<TimerHandle>
+    {
+        /* the previous deadline may have been queued on another CPU core */
+        if let Some(handle) = self.queued.take()
+        {
+            timerqueue::cancel(handle);
+        }
+
+        let owner = TimerOwner::VirtualCPU(id);
+        self.timer_owner = Some(id);
+        self.queued = match self.get_timer_deadline()
+        {
+            Some(deadline) => timerqueue::replace(owner, TimerValue::Exact(deadline)),
+            None =>
+            {
+                timerqueue::cancel_owner(owner);
+                None
+            }
+        };
+        self.queued
+    }
+}
+
+/* raise or withdraw virtual interrupts for the supervisor running on this CPU core
+   => bits = mask of SIP_SSIP, SIP_STIP and SIP_SEIP bits to change
+      pending = true to raise the interrupts, false to withdraw them */
+pub fn set_running_virtual_irq_pending(bits: Reg, pending: bool)
+{
+    let bits = bits & SIP_VIRTUAL_MASK;
+    match pending
+    {
+        true => set_csr!(mip, bits),
+        false => clear_csr!(mip, bits)
+    }
 }
 
 /* supported floating-point registers, if present, are 32 or 64 bits wide.
@@ -125,7 +347,12 @@ pub fn init_supervisor_cpu_state(cpu_nr: CPUcount, cpu_total: CPUcount, entry: E
         satp: 0,
         mepc: entry,
         mstatus: MSTATUS_MPP_SUPERVISOR,
-        registers: [0; 31]
+        registers: [0; 31],
+        stimecmp: STIMECMP_DISABLED,
+        clock: VirtualClock::new(),
+        rtc: VirtualRtc::new(),
+        timer_owner: None,
+        queued: None
     };
 
     /* supervisor CPU entry conditions (as per SBI and Diosix specification)
@@ -168,12 +395,34 @@ pub fn init_supervisor_fp_state() -> SupervisorFPState
 }
 
 /* save the supervisor CPU state to memory. only call from an IRQ context
-   as it relies on the IRQ stacked registers. 
-   => state = state area to use to store supervisor state */
-pub fn save_supervisor_cpu_state(state: &mut SupervisorState)
+   as it relies on the IRQ stacked registers. with Sstc, follow this with
+   state.queue_timer_deadline() so that the supervisor's timer isn't missed
+   => state = state area to use to store supervisor state
+   <= true for success, or false if called while handling a nested trap,
+      when the supervisor's state can't be reached */
+pub fn save_supervisor_cpu_state(state: &mut SupervisorState) -> bool
 {
     /* stores base CSRs and x1-x31 registers to memory */
-    unsafe { platform_save_supervisor_cpu_state(state); }
+    if unsafe { platform_save_supervisor_cpu_state(state) } != 0
+    {
+        return false;
+    }
+
+    /* keep any change the supervisor made to its time of day */
+    state.rtc = rtc::running();
+
+    /* stimecmp only holds the supervisor's deadline if it was allowed to program it.
+    otherwise, its accesses to stimecmp were emulated */
+    if sstc_present() == true
+    {
+        state.stimecmp = match read_csr!(0x30a) & MENVCFG_STCE /* menvcfg */
+        {
+            0 => EMULATED_STIMECMP.this().load(Ordering::SeqCst) as Reg,
+            _ => read_csr!(0x14d) /* stimecmp */
+        };
+    }
+
+    true
 }
 
 /* save the supervisor floating-point CPU state to memory
@@ -206,11 +455,50 @@ pub fn save_supervisor_fp_state(fp_state: &mut SupervisorFPState)
    as it relies on the IRQ stacked registers. returning to supervisor mode
    will pick up the new supervisor context.
    => state = supervisor CPU state to load from memory to registers
-      fp_state = supervisor FP state to load from memory to registers*/
-pub fn load_supervisor_cpu_fp_state(state: &SupervisorState, fp_state: &SupervisorFPState)
+      fp_state = supervisor FP state to load from memory to registers
+   <= true for success, or false if called while handling a nested trap,
+      when the supervisor's context can't be switched */
+pub fn load_supervisor_cpu_fp_state(state: &SupervisorState, fp_state: &SupervisorFPState) -> bool
 {
     /* loads base CSRs and x1-x31 into registers from memory */
-    unsafe { platform_load_supervisor_cpu_state(state); }
+    if unsafe { platform_load_supervisor_cpu_state(state) } != 0
+    {
+        return false;
+    }
+
+    /* rdtime emulation and timer SBI calls use the supervisor's clock from now on */
+    vclock::load(&state.clock);
+    rtc::load(&state.rtc);
+
+    /* with Sstc, the supervisor's timer interrupt is raised by hardware from stimecmp
+       rather than by the hypervisor via mip. make sure this core allows the supervisor
+       to program it, and restore the supervisor's deadline. this is only possible if the
+       supervisor's clock matches the host timer: otherwise, trap its time reads and
+       stimecmp accesses, and emulate them so that they can be translated */
+    if sstc_present() == true
+    {
+        match state.clock.is_identity()
+        {
+            true =>
+            {
+                set_csr!(0x30a, MENVCFG_STCE); /* menvcfg */
+                set_csr!(mcounteren, MCOUNTEREN_TM);
+                write_csr!(0x14d, state.stimecmp); /* stimecmp */
+            },
+            false =>
+            {
+                clear_csr!(0x30a, MENVCFG_STCE); /* menvcfg */
+                clear_csr!(mcounteren, MCOUNTEREN_TM);
+                EMULATED_STIMECMP.this().store(state.stimecmp as u64, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /* the supervisor's pending interrupts can't all be restored via sip, so do it here.
+       this delivers any interrupts queued while the supervisor was descheduled,
+       and withdraws any left pending by the previous supervisor */
+    set_running_virtual_irq_pending(SIP_VIRTUAL_MASK & !state.sip, false);
+    set_running_virtual_irq_pending(state.sip, true);
 
     /* only load floating-point registers from memory if FPU is present */
     if (read_csr!(mstatus) >> MSTATUS_FS_SHIFT) & MSTATUS_FS_MASK != MSTATUS_FS_OFF
@@ -223,6 +511,8 @@ pub fn load_supervisor_cpu_fp_state(state: &SupervisorState, fp_state: &Supervis
         let mstatus = read_csr!(mstatus) & !(MSTATUS_FS_MASK << MSTATUS_FS_SHIFT);
         write_csr!(mstatus, mstatus | (MSTATUS_FS_CLEAN << MSTATUS_FS_SHIFT));
     }
+
+    true
 }
 
 /* load the supervisor floating-point state from memory
@@ -276,6 +566,91 @@ pub fn features_priv_check(required: PrivilegeMode) -> bool
     }
 }
 
+/* return true if this CPU core implements the hypervisor (H) extension */
+pub fn hypervisor_extension_present() -> bool
+{
+    features() & CPUFEATURES_HYPERVISOR != 0
+}
+
+/* record that every CPU core implements the Sstc extension, as described by the
+   system's device tree or found by probing, so that supervisors can program their own
+   timer interrupts. each core enables it the next time it loads a supervisor's state,
+   if probing finds it on that core */
+pub fn enable_sstc()
+{
+    SSTC_PRESENT.store(true, Ordering::SeqCst);
+}
+
+/* return true if supervisors program their timer interrupts via the Sstc extension on this CPU core */
+pub fn sstc_present() -> bool
+{
+    SSTC_PRESENT.load(Ordering::SeqCst) == true && probe_sstc() == true
+}
+
+/* check whether this CPU core implements the Sstc extension by setting menvcfg.STCE
+   and reading it back. the result is remembered so the core is only probed once
+   <= true if Sstc is implemented, or false if not */
+pub fn probe_sstc() -> bool
+{
+    let probed = SSTC_PROBED.this();
+    match probed.load(Ordering::SeqCst)
+    {
+        SSTC_FOUND => true,
+        SSTC_ABSENT => false,
+        _ =>
+        {
+            let found = unsafe { platform_probe_sstc() } != 0;
+            probed.store(if found == true { SSTC_FOUND } else { SSTC_ABSENT }, Ordering::SeqCst);
+            found
+        }
+    }
+}
+
+/* program the running supervisor's timer interrupt via the Sstc extension
+   => target = exact timer value at which to raise the interrupt
+   <= true for success, or false if Sstc isn't in use, or can't be used
+      because the supervisor's clock doesn't match the host timer */
+pub fn set_supervisor_timer_target(target: u64) -> bool
+{
+    if sstc_present() == false || vclock::running().is_identity() == false
+    {
+        return false;
+    }
+
+    write_csr!(0x14d, target as Reg); /* stimecmp */
+    true
+}
+
+/* emulate the running supervisor's access to stimecmp when Sstc is present but the
+   supervisor's clock doesn't match the host timer, so its accesses trap
+   => value = new stimecmp value, in the supervisor's view of time, or None to just read it
+   <= (previous stimecmp value, exact host timer value at which to raise the supervisor's
+      timer interrupt if the value was written and can be reached), or None if
+      the supervisor's stimecmp accesses aren't being emulated */
+pub fn emulate_stimecmp(value: Option<u64>) -> Option<(u64, Option<u64>)>
+{
+    if sstc_present() == false || vclock::running().is_identity() == true
+    {
+        return None;
+    }
+
+    let emulated = EMULATED_STIMECMP.this();
+    let previous = emulated.load(Ordering::SeqCst);
+    match value
+    {
+        Some(v) =>
+        {
+            emulated.store(v, Ordering::SeqCst);
+            match v as Reg
+            {
+                STIMECMP_DISABLED => Some((previous, None)),
+                _ => Some((previous, vclock::running().to_host(v)))
+            }
+        },
+        None => Some((previous, None))
+    }
+}
+
 /* return the privilege level of the code running before we entered the machine level */
 pub fn previous_privilege() -> PrivilegeMode
 {
@@ -288,6 +663,49 @@ pub fn previous_privilege() -> PrivilegeMode
     }
 }
 
+/* return the number of CPU cores that have booted and been assigned a linear CPU core ID */
+pub fn nr_booted_cpus() -> CPUcount
+{
+    let count = unsafe { read_volatile(&platform_cpu_core_id_counter) } as CPUcount;
+    if count > MAX_CPUS { MAX_CPUS } else { count }
+}
+
+/* convert a runtime-assigned linear CPU core ID into its hart ID
+   => id = linear CPU core ID, counting from 0
+   <= hart ID of the core, or None if no such core has booted */
+pub fn cpu_id_to_hart_id(id: CPUcount) -> Option<usize>
+{
+    if id >= MAX_CPUS { return None; }
+    match unsafe { read_volatile(&platform_hart_id_table[id]) }
+    {
+        NO_HART_ID => None,
+        hart => Some(hart)
+    }
+}
+
+/* convert a hart ID into its runtime-assigned linear CPU core ID
+   => hart = hart ID, as found in mhartid
+   <= linear CPU core ID, or None if no such core has booted */
+pub fn hart_id_to_cpu_id(hart: usize) -> Option<CPUcount>
+{
+    for id in 0..nr_booted_cpus()
+    {
+        if cpu_id_to_hart_id(id) == Some(hart)
+        {
+            return Some(id);
+        }
+    }
+    None
+}
+
+/* return the running CPU core's linear CPU core ID. this is recorded in the core's
+   platform variables at boot. cores beyond MAX_CPUS are parked at boot, so this is
+   always less than MAX_CPUS and can be used to index per-CPU tables */
+pub fn get_cpu_id() -> CPUcount
+{
+    unsafe { platform_cpu_id() }
+}
+
 /* returns the running CPU core's ISA width in bits */
 pub fn get_isa_width() -> usize
 {
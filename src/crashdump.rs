@@ -0,0 +1,235 @@
+/* diosix RV64 fatal trap crash dumps
+ *
+ * Write out the state of a CPU core that hit an unrecoverable
+ * trap to the serial port found in the device tree. Before
+ * the device tree is parsed, fall back to the low-level
+ * guru meditation code, which uses the early console
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::fmt;
+use core::fmt::Write;
+use core::ptr::read_volatile;
+use core::time::Duration;
+use spin::Mutex;
+use super::serial;
+use super::cpu;
+use super::stats;
+use super::irq::{self, IRQContext, REG_FP};
+
+extern "C"
+{
+    static mut platform_early_console_base: usize;
+    static mut platform_early_console_type: usize;
+    fn platform_guru_meditation() -> !;
+}
+
+lazy_static!
+{
+    /* acquire CRASH_CONSOLE lock to write a crash dump. this is kept separate from
+    the debug console so that a core that crashed while logging can still report */
+    static ref CRASH_CONSOLE: Mutex<Option<serial::SerialPort>> = Mutex::new(None);
+}
+
+/* early console types, which must match EARLY_CONSOLE_* in asm/consts.s */
+const EARLY_CONSOLE_SIFIVE:     usize = 1;
+const EARLY_CONSOLE_NS16550:    usize = 2;
+
+/* give up walking the stack after this many frames */
+const BACKTRACE_MAX_DEPTH: usize = 32;
+
+/* previous privilege mode in mstatus is in bits 11-12. machine mode = 3 */
+const MSTATUS_MPP_SHIFT: usize = 11;
+const MSTATUS_MPP_MACHINE: usize = 3;
+
+/* register names in the RISC-V calling convention, indexed by register number */
+const REG_NAMES: [&str; 32] =
+[
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6"
+];
+
+/* format text straight out to a serial port without allocating memory */
+struct Console<'a>
+{
+    port: &'a serial::SerialPort
+}
+
+impl fmt::Write for Console<'_>
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result
+    {
+        match self.port.write(s)
+        {
+            true => Ok(()),
+            false => Err(fmt::Error)
+        }
+    }
+}
+
+/* use the given serial port for crash dumps, and as the early console
+   for the low-level guru meditation code
+   => port = serial port found by the device tree */
+pub fn pin_console(port: &serial::SerialPort)
+{
+    let compat = port.get_compatibility();
+    let early_type = if compat.contains("16550a") == true
+    {
+        Some(EARLY_CONSOLE_NS16550)
+    }
+    else if compat.contains("sifive") == true
+    {
+        Some(EARLY_CONSOLE_SIFIVE)
+    }
+    else
+    {
+        None
+    };
+
+    if let Some(t) = early_type
+    {
+        unsafe
+        {
+            platform_early_console_base = port.get_mmio_base();
+            platform_early_console_type = t;
+        }
+    }
+
+    *(CRASH_CONSOLE.lock()) = serial::SerialPort::new(port.get_mmio_base(), port.get_mmio_size(), compat);
+}
+
+/* report an unrecoverable trap, and any traps it interrupted, then stop this CPU core
+   => context = stacked registers of the fatal trap
+      reason = short description of what went wrong */
+pub fn fatal(context: &IRQContext, reason: &str) -> !
+{
+    /* give up on the lock rather than deadlock if another core crashed mid-dump */
+    if let Some(guard) = CRASH_CONSOLE.try_lock()
+    {
+        if let Some(port) = &*guard
+        {
+            let mut out = Console { port };
+            let _ = write!(out, "\n*** Guru meditation on CPU {}: {} ***\n", cpu::get_cpu_id(), reason);
+
+            let mut frame = Some(context);
+            while let Some(f) = frame
+            {
+                let _ = dump(&mut out, f);
+                frame = f.get_previous();
+            }
+
+            let _ = write!(out, "*** CPU {} (hart {}) halted ***\n", cpu::get_cpu_id(), read_csr!(mhartid));
+            halt();
+        }
+    }
+
+    /* no console, so try the early console */
+    unsafe { platform_guru_meditation() }
+}
+
+/* report a CPU core that has stopped responding, along with its trap counters so that
+   it's possible to tell whether it's still taking traps. unlike fatal(), this is called
+   by another core, typically the watchdog's, which carries on afterwards
+   => cpu = linear CPU core ID of the unresponsive core
+      stalled = how long since it last showed signs of life */
+pub fn unresponsive(cpu: cpu::CPUcount, stalled: Duration)
+{
+    if let Some(guard) = CRASH_CONSOLE.try_lock()
+    {
+        if let Some(port) = &*guard
+        {
+            let mut out = Console { port };
+            let _ = write!(out, "\n*** Watchdog: CPU {} unresponsive for {} ms ***\n", cpu, stalled.as_millis());
+
+            if let Some(stats) = stats::snapshot(cpu)
+            {
+                let _ = write!(out, "{} traps taken\n", stats.total_traps());
+                for (cause, count) in stats.causes.iter().filter(|(_, count)| *count > 0)
+                {
+                    let _ = write!(out, "{:?}: {}\n", cause, count);
+                }
+            }
+        }
+    }
+}
+
+/* write out a trap frame, including a backtrace if it interrupted the hypervisor */
+fn dump(out: &mut Console, context: &IRQContext) -> fmt::Result
+{
+    write!(out, "hart {} trap depth {}: cause 0x{:x} epc 0x{:x} tval 0x{:x}\n",
+        read_csr!(mhartid), context.depth(), context.cause, context.epc, context.tval)?;
+
+    /* x0 is never stacked, so always show it as zero */
+    for reg in 0..32
+    {
+        let value = if reg == 0 { 0 } else { context.registers[reg] };
+        write!(out, "{:>4}: {:016x}", REG_NAMES[reg], value)?;
+        write!(out, "{}", if reg % 4 == 3 { "\n" } else { "  " })?;
+    }
+
+    write!(out, "mstatus 0x{:x} mie 0x{:x} mip 0x{:x} mtvec 0x{:x} mscratch 0x{:x}\n",
+        context.status, read_csr!(mie), read_csr!(mip), read_csr!(mtvec), read_csr!(mscratch))?;
+    write!(out, "medeleg 0x{:x} mideleg 0x{:x} satp 0x{:x}\n",
+        read_csr!(medeleg), read_csr!(mideleg), read_csr!(satp))?;
+    write!(out, "sstatus 0x{:x} sepc 0x{:x} scause 0x{:x} stval 0x{:x} stvec 0x{:x}\n",
+        read_csr!(sstatus), read_csr!(sepc), read_csr!(scause), read_csr!(stval), read_csr!(stvec))?;
+
+    /* only walk the stack if the trap interrupted the hypervisor */
+    if (context.status >> MSTATUS_MPP_SHIFT) & 0b11 == MSTATUS_MPP_MACHINE
+    {
+        backtrace(out, context)?;
+    }
+
+    Ok(())
+}
+
+/* best-effort walk of the hypervisor's stack using frame pointers. the return address is
+   stored 8 bytes below the frame pointer, and the caller's frame pointer 16 bytes below.
+   this relies on the code being built with frame pointers, and stops at the first frame
+   pointer that isn't within this CPU core's IRQ stack */
+fn backtrace(out: &mut Console, context: &IRQContext) -> fmt::Result
+{
+    let (stack_base, stack_top) = irq::irq_stack_bounds();
+
+    write!(out, "backtrace:\n  0x{:x}\n", context.epc)?;
+
+    let mut fp = context.registers[REG_FP];
+    for _ in 0..BACKTRACE_MAX_DEPTH
+    {
+        if fp & 0x7 != 0 || fp <= stack_base + 16 || fp > stack_top
+        {
+            break;
+        }
+
+        let (ra, next_fp) = unsafe { (read_volatile((fp - 8) as *const usize), read_volatile((fp - 16) as *const usize)) };
+        if ra == 0
+        {
+            break;
+        }
+        write!(out, "  0x{:x}\n", ra)?;
+
+        /* the stack grows down, so callers' frames must be higher up */
+        if next_fp <= fp
+        {
+            break;
+        }
+        fp = next_fp;
+    }
+
+    Ok(())
+}
+
+/* stop this CPU core permanently */
+fn halt() -> !
+{
+    write_csr!(mie, 0);
+    loop
+    {
+        unsafe { llvm_asm!("wfi" :::: "volatile") };
+    }
+}
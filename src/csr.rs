@@ -42,3 +42,15 @@ macro_rules! clear_csr
         }
     };
 }
+
+/* set_csr(csr name, mask of bits to set) updates csr by setting bits selected by mask */
+macro_rules! set_csr
+{
+    ($csr:expr, $value:expr) =>
+    {
+        unsafe
+        {
+            llvm_asm!(concat!("csrrs x0, ", stringify!($csr), ", $0") :: "r"($value) :: "volatile");
+        }
+    };
+}
@@ -13,12 +13,25 @@ use devicetree::{DeviceTree, DeviceTreeBlob, DeviceTreeError, DeviceTreeProperty
 use super::serial;
 use super::physmem;
 use super::timer;
+use super::timerqueue;
 use super::errata;
 use super::cpu;
+use super::plic;
+use super::vplic;
+use super::crashdump;
+use super::aplic;
+use super::imsic;
+use super::ipi;
+use super::rtc;
+use super::watchdog;
 
+use core::sync::atomic::{AtomicBool, Ordering};
 use alloc::string::String;
 use alloc::vec::Vec;
 
+/* true if the next debug output starts a new line, and so should be timestamped */
+static DEBUG_LINE_START: AtomicBool = AtomicBool::new(true);
+
 /* set of basic devices for the hypervisor to use. at first, this was an elaborate
 hashmap of objects describing components and peripherals but it seemed overkill. 
 all we really want to do is provide the system primitives to the hypervisor:
@@ -35,6 +48,13 @@ pub struct Devices
     system_ram: Vec<physmem::RAMArea>,          /* list of physical RAM chunks */
     debug_console: Option<serial::SerialPort>,  /* place to send debug logging, if possible */
     scheduler_timer: Option<timer::Timer>,      /* periodic timer for the scheduler */ 
+    timebase: Option<timer::Timebase>,          /* scheduler timer's frequency and how it was found */
+    plic: Option<plic::Plic>,                   /* external interrupt controller */
+    aplic: Option<aplic::Aplic>,                /* AIA wired interrupt controller, machine-level domain */
+    imsic: Option<imsic::Imsic>,                /* AIA machine-level interrupt files */
+    supervisor_imsic: Option<imsic::Imsic>,     /* AIA supervisor-level and guest interrupt files */
+    rtc: Option<rtc::Rtc>,                      /* wall-clock real-time clock */
+    watchdog: Option<watchdog::SiFiveWatchdog>, /* hardware watchdog, started on request */
 
     /* known errata we need to deal with */
     errata_known: u64,                          /* bitfield of errata we know about */
@@ -55,6 +75,145 @@ impl Devices
 
         let (errata_known, errata_fixed) = errata::from_model(parsed.get_property(&format!("/"), &format!("model"))?.as_text()?);
 
+        /* find the advanced interrupt architecture's controllers, if present. the IMSICs
+        must be found first so that the APLIC can be pointed at them */
+        let (imsic, imsic_phandle, supervisor_imsic) = get_imsics(&parsed);
+        if let Some(i) = &imsic
+        {
+            i.pin(); /* pin the machine-level interrupt files for other platform code */
+        }
+
+        let aplic = match get_machine_aplic(&parsed, imsic_phandle)
+        {
+            Some(a) =>
+            {
+                a.init(imsic.as_ref(), supervisor_imsic.as_ref());
+                a.pin(); /* pin this domain for other platform code */
+                Some(a)
+            },
+            None => None
+        };
+
+        /* let supervisors program their own timer interrupts if all the cores can. the
+        device tree may not list Sstc, so also check if this core implements it */
+        if all_cpus_have_extension(&parsed, "sstc") == true || cpu::probe_sstc() == true
+        {
+            cpu::enable_sstc();
+        }
+
+        /* use the first RTC found in the tree for the time of day. find it before the
+        timer as it may be needed to measure the timer's frequency */
+        let rtc = match find_compatible(&parsed, RTC_COMPATIBLE).first()
+        {
+            Some(path) => match get_reg_regions(&parsed, path)
+            {
+                Ok(regions) if regions.len() > 0 =>
+                {
+                    let r = rtc::Rtc::new(regions[0].0, regions[0].1);
+                    r.pin(); /* pin this clock for other platform code */
+                    Some(r)
+                },
+                _ => None
+            },
+            None => None
+        };
+
+        /* map each hart to its timer registers in every CLINT. a CLINT or ACLINT that can't be
+        parsed would leave cores without timers or IPIs, so fail. one that doesn't say which
+        harts it serves is assumed to give each hart the slot matching its hart ID */
+        let clints = get_clints(&parsed);
+        for path in clints.iter()
+        {
+            map_clint_harts(&parsed, path)?;
+        }
+
+        /* if the device tree doesn't say how fast the timers tick, measure it later */
+        let dt_tbf = get_timebase_frequency(&parsed);
+        let tbf = match dt_tbf
+        {
+            Ok(f) => f,
+            Err(_) => timer::TIMEBASE_GUESS
+        };
+
+        /* an ACLINT splits the CLINT's timers and software interrupts into separate devices */
+        let mut mtimers = Vec::new();
+        for path in find_compatible(&parsed, ACLINT_MTIMER_COMPATIBLE).iter()
+        {
+            mtimers.push(get_aclint_mtimer(&parsed, path, tbf)?);
+        }
+        for path in find_compatible(&parsed, ACLINT_MSWI_COMPATIBLE).iter()
+        {
+            map_aclint_mswi(&parsed, path)?;
+        }
+        for path in find_compatible(&parsed, ACLINT_SSWI_COMPATIBLE).iter()
+        {
+            map_aclint_sswi(&parsed, path)?;
+        }
+
+        /* use the first CLINT found in the tree for our system timer, or the first MTIMER */
+        let found_timer = match clints.first()
+        {
+            Some(path) => Some(get_system_timer(&parsed, &path, tbf)?),
+            None => mtimers.first().cloned()
+        };
+
+        /* bring up the debug console early: it may be needed to measure the timer's frequency,
+        and to warn the user if that can't be done */
+        let debug_console = match setup_debug_console(&parsed)
+        {
+            Ok(dc) =>
+            {
+                crashdump::pin_console(&dc); /* also use it to report fatal traps */
+                Some(dc) /* use a suitable serial or debug port for output */
+            },
+            Err(_) => None /* no serial console, no way to warn the user :-( */
+        };
+
+        /* calibrate the timer against the RTC if its frequency is unknown, or against the
+        debug console's baud clock if there's no RTC, or fall back to a guess */
+        let (scheduler_timer, timebase) = match (found_timer, dt_tbf)
+        {
+            (Some(t), Ok(frequency)) => (Some(t), Some(timer::Timebase
+            {
+                frequency,
+                confidence: timer::TimebaseConfidence::DeviceTree
+            })),
+            (Some(t), Err(_)) =>
+            {
+                let guess = timer::Timebase
+                {
+                    frequency: timer::TIMEBASE_GUESS,
+                    confidence: timer::TimebaseConfidence::Guess
+                };
+
+                let measured = match (&rtc, &debug_console, get_debug_console_speed(&parsed))
+                {
+                    (Some(r), _, _) => timer::calibrate(&t, || Some(r.now())),
+                    (None, Some(dc), Some(speed)) => calibrate_against_uart(&t, dc, speed),
+                    (None, _, _) => None
+                };
+
+                let timebase = measured.unwrap_or(guess);
+                if timebase.confidence == timer::TimebaseConfidence::Guess
+                {
+                    /* timekeeping and scheduling will be off if the guess is wrong, so make some noise */
+                    if let Some(dc) = &debug_console
+                    {
+                        dc.write(&format!("WARNING: can't measure timer frequency, assuming {} Hz. \
+                                           set timebase-frequency in the device tree to fix this\n",
+                                           timebase.frequency));
+                    }
+                }
+                (Some(t.with_frequency(timebase.frequency)), Some(timebase))
+            },
+            (None, _) => (None, None)
+        };
+
+        if let Some(t) = &scheduler_timer
+        {
+            t.pin(); /* pin this timer for other platform code */
+        }
+
         /* fill out the minimum default devices expected by the hypervisor from parsed DTB */
         let d = Devices
         {
@@ -78,11 +237,7 @@ impl Devices
                 count
             },
             
-            debug_console: match setup_debug_console(&parsed)
-            {
-                Ok(dc) => Some(dc), /* use a suitable serial or debug port for output */
-                Err(_) => None /* no serial console, no way to warn the user :-( */
-            },
+            debug_console,
 
             system_ram:
             {
@@ -99,19 +254,22 @@ impl Devices
                 chunks
             },
 
-            scheduler_timer:
+            scheduler_timer,
+            timebase,
+
+            plic:
             {
-                /* use the first CLINT found in the tree for our system timer */
-                if let Some(path) = parsed.iter(&format!("/soc/clint@"), 2).next()
+                /* use the first PLIC found in the tree for external interrupts */
+                if let Some(path) = find_compatible(&parsed, PLIC_COMPATIBLE).first()
                 {
-                    match get_system_timer(&parsed, &path)
+                    match get_plic(&parsed, &path)
                     {
-                        Ok(t) =>
+                        Ok(p) =>
                         {
-                            t.pin(); /* pin this timer for other platform code */
-                            Some(t)
+                            p.pin(); /* pin this controller for other platform code */
+                            Some(p)
                         },
-                        Err(_) => None /* would be nice to flag up error */
+                        Err(_) => None
                     }
                 }
                 else
@@ -120,6 +278,30 @@ impl Devices
                 }
             },
 
+            rtc,
+
+            watchdog:
+            {
+                /* use the first hardware watchdog found in the tree. don't start it yet */
+                match find_compatible(&parsed, WATCHDOG_COMPATIBLE).first()
+                {
+                    Some(path) => match get_reg_regions(&parsed, path)
+                    {
+                        Ok(regions) if regions.len() > 0 =>
+                        {
+                            let w = watchdog::SiFiveWatchdog::new(regions[0].0, regions[0].1);
+                            w.pin(); /* pin this watchdog for other platform code */
+                            Some(w)
+                        },
+                        _ => None
+                    },
+                    None => None
+                }
+            },
+
+            aplic,
+            imsic,
+            supervisor_imsic,
             parsed,
             errata_known,
             errata_fixed
@@ -128,12 +310,25 @@ impl Devices
         Ok(d)
     }
 
-    /* write msg string out to the debug serial port */
+    /* write msg string out to the debug serial port. if there's an RTC, each new line
+       of output starts with the date and time */
     pub fn write_debug_string(&self, msg: &str)
     {
         if let Some(con) = &self.debug_console
         {
-            con.write(msg);
+            for line in msg.split_inclusive('\n')
+            {
+                if DEBUG_LINE_START.load(Ordering::SeqCst) == true
+                {
+                    if let Some(date_time) = rtc::get_pinned_date_time()
+                    {
+                        con.write(&format!("[{}] ", date_time));
+                    }
+                }
+
+                con.write(line);
+                DEBUG_LINE_START.store(line.ends_with('\n'), Ordering::SeqCst);
+            }
         }
     }
 
@@ -172,6 +367,32 @@ impl Devices
         }
     }
 
+    /* enable this CPU core's timer interrupt in tickless mode: the timer isn't
+    armed until a deadline is queued, such as by scheduler_timer_next_in() */
+    pub fn scheduler_timer_start_tickless(&self)
+    {
+        if let Some(s) = self.scheduler_timer
+        {
+            s.start_tickless();
+
+            /* don't lose any deadlines queued before the timer was started */
+            if let Some(deadline) = timerqueue::next_deadline()
+            {
+                s.next_at(deadline);
+            }
+        }
+    }
+
+    /* cancel the scheduler's deadline on this CPU core, leaving other deadlines queued.
+    call this before idling the core so that only real deadlines wake it */
+    pub fn scheduler_timer_stop(&self)
+    {
+        if self.scheduler_timer.is_some()
+        {
+            timerqueue::cancel_owner(timerqueue::TimerOwner::Scheduler);
+        }
+    }
+
     /* return the timer's current value, or None if no timer
     this is a clock-on-the-wall timer in that its value always
     increases and never resets (though may rollover to 0) */
@@ -185,12 +406,15 @@ impl Devices
     }
 
     /* interrupt this CPU core with a tiemr IRQ after duration number
-    of ticks or sub-seconds have passed */
+    of ticks or sub-seconds have passed. this replaces the scheduler's
+    deadline in this core's timer queue, leaving other deadlines queued */
     pub fn scheduler_timer_next_in(&self, duration: timer::TimerValue)
     {
-        if let Some(s) = self.scheduler_timer
+        if self.scheduler_timer.is_some()
         {
-            s.next_in(duration);
+            timerqueue::cancel_owner(timerqueue::TimerOwner::Scheduler);
+            timerqueue::add_in(timerqueue::TimerOwner::Scheduler, duration);
+            watchdog::pet(); /* each scheduler tick shows this core is alive */
         }
     }
 
@@ -216,15 +440,231 @@ impl Devices
     
 
     /* interrupt this CPU core when its timer values passes
-    the target number of ticks or sub-seconds */
+    the target number of ticks or sub-seconds. this replaces the
+    scheduler's deadline in this core's timer queue */
     pub fn scheduler_timer_at(&self, target: timer::TimerValue)
     {
-        if let Some(s) = self.scheduler_timer
+        if self.scheduler_timer.is_some()
+        {
+            timerqueue::replace(timerqueue::TimerOwner::Scheduler, target);
+            watchdog::pet(); /* each scheduler tick shows this core is alive */
+        }
+    }
+
+    /* start the watchdog, using the hardware watchdog if there is one. each CPU core
+    pets the watchdog when it sets its next scheduler tick
+       => timeout = duration a core may go without a scheduler tick before it's checked
+          action = what to do with a core that doesn't respond to the check in time
+       <= true for success, or false if there's no timer, or the action
+          is a reset and there's no hardware watchdog to carry it out */
+    pub fn watchdog_start(&self, timeout: timer::TimerValue, action: watchdog::WatchdogAction) -> bool
+    {
+        watchdog::start(timeout, action)
+    }
+
+    /* return the system's hardware watchdog, if present */
+    pub fn get_watchdog(&self) -> Option<&watchdog::SiFiveWatchdog> { self.watchdog.as_ref() }
+
+    /* return the scheduler timer's frequency and how confident we are of it, or None for no timer */
+    pub fn get_timebase(&self) -> Option<timer::Timebase> { self.timebase }
+
+    /* return the system's real-time clock, if present */
+    pub fn get_rtc(&self) -> Option<&rtc::Rtc> { self.rtc.as_ref() }
+
+    /* return the UTC time in nanoseconds since 00:00:00 on 1 January 1970, or None if no RTC */
+    pub fn get_rtc_now(&self) -> Option<u64>
+    {
+        match &self.rtc
+        {
+            Some(r) => Some(r.now()),
+            None => None
+        }
+    }
+
+    /* return the UTC calendar date and time, or None if no RTC */
+    pub fn get_date_time(&self) -> Option<rtc::DateTime>
+    {
+        match &self.rtc
+        {
+            Some(r) => Some(r.get_date_time()),
+            None => None
+        }
+    }
+
+    /* return the system's external interrupt controller, if present */
+    pub fn get_plic(&self) -> Option<&plic::Plic> { self.plic.as_ref() }
+
+    /* prepare this CPU core to receive machine-level external interrupts from the PLIC
+       <= true for success, or false if there's no PLIC or no context for this core */
+    pub fn plic_init_hart(&self) -> bool
+    {
+        if let Some(p) = &self.plic
+        {
+            return p.init_hart();
+        }
+        false
+    }
+
+    /* allow an external interrupt source to interrupt this CPU core
+       => source = PLIC interrupt source number
+          priority = non-zero priority level for the source
+       <= true for success, or false for failure */
+    pub fn plic_enable(&self, source: usize, priority: u32) -> bool
+    {
+        if let Some(p) = &self.plic
+        {
+            if let Some(context) = p.this_context(cpu::PrivilegeMode::Machine)
+            {
+                return p.set_priority(source, priority) && p.enable(context, source);
+            }
+        }
+        false
+    }
+
+    /* stop an external interrupt source from interrupting this CPU core
+       => source = PLIC interrupt source number
+       <= true for success, or false for failure */
+    pub fn plic_disable(&self, source: usize) -> bool
+    {
+        if let Some(p) = &self.plic
+        {
+            if let Some(context) = p.this_context(cpu::PrivilegeMode::Machine)
+            {
+                return p.disable(context, source);
+            }
+        }
+        false
+    }
+
+    /* claim the highest-priority pending external interrupt for this CPU core.
+       call plic_complete() with the source number once the interrupt is handled
+       <= source number of the interrupt, or None for nothing pending */
+    pub fn plic_claim(&self) -> Option<usize>
+    {
+        if let Some(p) = &self.plic
+        {
+            if let Some(context) = p.this_context(cpu::PrivilegeMode::Machine)
+            {
+                return p.claim(context);
+            }
+        }
+        None
+    }
+
+    /* signal to the PLIC that this CPU core has handled the given external interrupt source */
+    pub fn plic_complete(&self, source: usize)
+    {
+        if let Some(p) = &self.plic
+        {
+            if let Some(context) = p.this_context(cpu::PrivilegeMode::Machine)
+            {
+                p.complete(context, source);
+            }
+        }
+    }
+
+    /* return the advanced interrupt architecture's controllers, if present */
+    pub fn get_aplic(&self) -> Option<&aplic::Aplic> { self.aplic.as_ref() }
+    pub fn get_imsic(&self) -> Option<&imsic::Imsic> { self.imsic.as_ref() }
+    pub fn get_supervisor_imsic(&self) -> Option<&imsic::Imsic> { self.supervisor_imsic.as_ref() }
+
+    /* prepare this CPU core to receive machine-level external interrupts via the AIA
+       <= true for success, or false if there's no AIA or it can't reach this core */
+    pub fn aia_init_hart(&self) -> bool
+    {
+        match (&self.aplic, &self.imsic)
+        {
+            (_, Some(i)) => i.init_hart(),
+            (Some(a), None) => match a.hart_index(read_csr!(mhartid))
+            {
+                Some(index) =>
+                {
+                    a.init_hart_direct(index);
+                    set_csr!(mie, 1 << 11); /* machine-level external interrupts */
+                    true
+                },
+                None => false
+            },
+            (None, None) => false
+        }
+    }
+
+    /* allow a wired interrupt source to interrupt this CPU core via the AIA
+       => source = APLIC interrupt source number
+          mode = how the source's wire signals an interrupt
+       <= the number aia_claim() will return for this source's interrupts: an interrupt
+          identity in MSI mode, or the source number in direct mode. None for failure */
+    pub fn aia_enable(&self, source: usize, mode: aplic::SourceMode) -> Option<usize>
+    {
+        let a = self.aplic.as_ref()?;
+        let hart = read_csr!(mhartid);
+
+        if a.is_msi() == true
+        {
+            let i = self.imsic.as_ref()?;
+            let index = i.find_file(hart)?.index;
+            let eiid = imsic::allocate_id(i.get_nr_ids())?;
+            if a.configure(source, mode) && a.route_msi(source, index, 0, eiid) && i.enable(eiid) && a.enable(source)
+            {
+                return Some(eiid);
+            }
+            imsic::free_id(eiid);
+            return None;
+        }
+
+        let index = a.hart_index(hart)?;
+        match a.configure(source, mode) && a.route_direct(source, index, 1) && a.enable(source)
+        {
+            true => Some(source),
+            false => None
+        }
+    }
+
+    /* stop a wired interrupt source from interrupting via the AIA
+       => source = APLIC interrupt source number
+          id = number returned by aia_enable() for this source
+       <= true for success, or false for failure */
+    pub fn aia_disable(&self, source: usize, id: usize) -> bool
+    {
+        match &self.aplic
         {
-            s.next_at(target);
+            Some(a) =>
+            {
+                let disabled = a.disable(source) && a.configure(source, aplic::SourceMode::Inactive);
+                if let (true, Some(i)) = (a.is_msi(), &self.imsic)
+                {
+                    i.disable(id);
+                    imsic::free_id(id);
+                }
+                disabled
+            },
+            None => false
         }
     }
 
+    /* claim the highest-priority pending AIA interrupt for this CPU core. there's no need
+       to signal completion: claiming is enough for both MSI and direct delivery
+       <= interrupt identity in MSI mode or source number in direct mode, or None for nothing pending */
+    pub fn aia_claim(&self) -> Option<usize>
+    {
+        match (&self.aplic, &self.imsic)
+        {
+            (_, Some(_)) => imsic::claim_from(cpu::PrivilegeMode::Machine),
+            (Some(a), None) => a.claim_direct(a.hart_index(read_csr!(mhartid))?),
+            (None, None) => None
+        }
+    }
+
+    /* return the physical address of a guest interrupt file, for mapping into a guest's
+       physical address space as its own supervisor-level interrupt file
+       => hart = mhartid of the CPU core the guest file belongs to
+          guest = guest file number, from imsic::allocate_guest_file() on that core
+       <= physical address of the file's MMIO page, or None if unavailable */
+    pub fn aia_guest_file_address(&self, hart: usize, guest: usize) -> Option<physmem::PhysMemBase>
+    {
+        self.supervisor_imsic.as_ref()?.guest_file_address(hart, guest)
+    }
+
     /* create a virtualized environment based on the host's peripherals for guest supervisors.
        => cpus = number of CPU cores in this virtual envuironment
           boot_cpu_id = ID of CPU core that can or will boot the system
@@ -249,14 +689,14 @@ impl Devices
         dt.edit_property(&cpu_root_path, &format!("#address-cells"), DeviceTreeProperty::UnsignedInt32(1));
         dt.edit_property(&cpu_root_path, &format!("#size-cells"), DeviceTreeProperty::UnsignedInt32(0));
 
-        match self.parsed.get_property(&format!("/cpus"), &format!("timebase-frequency"))
+        /* use the host timer's frequency, whether it was given by the device tree or measured */
+        if let Some(timebase) = self.timebase
         {
-            Ok(prop) => if let Ok(freq) = prop.as_u32()
+            dt.edit_property(&cpu_root_path, &format!("timebase-frequency"), match timebase.frequency
             {
-                dt.edit_property(&cpu_root_path, &format!("timebase-frequency"),
-                    DeviceTreeProperty::UnsignedInt32(freq));
-            },
-            Err(_) => () /* TODO: should we guess the timebase frequency instead? */
+                f if f > u32::MAX as u64 => DeviceTreeProperty::MultipleUnsignedInt32_32(vec!(((f >> 32) as u32, f as u32))),
+                f => DeviceTreeProperty::UnsignedInt32(f as u32)
+            });
         }
 
         for cpu in 0..cpus
@@ -273,8 +713,12 @@ impl Devices
                 w => panic!("Cannot derive virtualized environment. Unsupported ISA width {}", w)
             }
 
-            /* get the lower case ISA string */
-            let isa = (cpu::CPUDescription).isa_to_string().to_lowercase();
+            /* get the lower case ISA string, plus any multi-letter extensions supervisors can use */
+            let mut isa = (cpu::CPUDescription).isa_to_string().to_lowercase();
+            if cpu::sstc_present() == true
+            {
+                isa.push_str("_sstc");
+            }
             dt.edit_property(&cpu_node_path, &format!("riscv,isa"), DeviceTreeProperty::Text(isa));
 
             /* create an interrupt controller for this CPU core */
@@ -282,6 +726,40 @@ impl Devices
             dt.edit_property(&intc_node_path, &format!("#interrupt-cells"), DeviceTreeProperty::UnsignedInt32(1));
             dt.edit_property(&intc_node_path, &format!("interrupt-controller"), DeviceTreeProperty::Empty);
             dt.edit_property(&intc_node_path, &format!("compatible"), DeviceTreeProperty::Text(format!("riscv,cpu-intc")));
+            dt.edit_property(&intc_node_path, &format!("phandle"), DeviceTreeProperty::UnsignedInt32(virtual_intc_phandle(cpu)));
+        }
+
+        /* describe the system-on-chip bus and its virtual PLIC. each vCPU gets one
+        context that raises the supervisor-level external interrupt on that vCPU */
+        let soc_node_path = format!("/soc");
+        dt.edit_property(&soc_node_path, &format!("#address-cells"), DeviceTreeProperty::UnsignedInt32(2));
+        dt.edit_property(&soc_node_path, &format!("#size-cells"), DeviceTreeProperty::UnsignedInt32(2));
+        dt.edit_property(&soc_node_path, &format!("compatible"), DeviceTreeProperty::Text(format!("simple-bus")));
+        dt.edit_property(&soc_node_path, &format!("ranges"), DeviceTreeProperty::Empty);
+
+        let plic_node_path = format!("{}/plic@{:x}", &soc_node_path, vplic::VIRTUAL_PLIC_BASE);
+        dt.edit_property(&plic_node_path, &format!("compatible"), DeviceTreeProperty::Text(format!("riscv,plic0")));
+        dt.edit_property(&plic_node_path, &format!("reg"),
+            DeviceTreeProperty::MultipleUnsignedInt64_64(vec!((vplic::VIRTUAL_PLIC_BASE as u64, vplic::VIRTUAL_PLIC_SIZE as u64))));
+        dt.edit_property(&plic_node_path, &format!("#interrupt-cells"), DeviceTreeProperty::UnsignedInt32(1));
+        dt.edit_property(&plic_node_path, &format!("interrupt-controller"), DeviceTreeProperty::Empty);
+        dt.edit_property(&plic_node_path, &format!("riscv,ndev"), DeviceTreeProperty::UnsignedInt32(vplic::VIRTUAL_PLIC_NR_SOURCES as u32));
+        dt.edit_property(&plic_node_path, &format!("phandle"), DeviceTreeProperty::UnsignedInt32(VIRTUAL_PLIC_PHANDLE));
+        dt.edit_property(&plic_node_path, &format!("interrupts-extended"), DeviceTreeProperty::MultipleUnsignedInt32_32(
+            (0..cpus).map(|cpu| (virtual_intc_phandle(cpu), plic::INTC_SUPERVISOR_EXTERNAL)).collect()));
+
+        /* give the guest a virtual RTC if there's a real one to base it on. its registers are emulated
+        by rtc::emulate() from guest page faults, which are only raised for guests running under two-stage
+        address translation, so the hypervisor must opt in with rtc::enable_guest_rtcs(). its interrupt
+        line is reserved, though alarms aren't supported */
+        if self.rtc.is_some() == true && rtc::guest_rtcs_enabled() == true
+        {
+            let rtc_node_path = format!("{}/rtc@{:x}", &soc_node_path, rtc::VIRTUAL_RTC_BASE);
+            dt.edit_property(&rtc_node_path, &format!("compatible"), DeviceTreeProperty::Text(format!("google,goldfish-rtc")));
+            dt.edit_property(&rtc_node_path, &format!("reg"),
+                DeviceTreeProperty::MultipleUnsignedInt64_64(vec!((rtc::VIRTUAL_RTC_BASE as u64, rtc::VIRTUAL_RTC_SIZE as u64))));
+            dt.edit_property(&rtc_node_path, &format!("interrupt-parent"), DeviceTreeProperty::UnsignedInt32(VIRTUAL_PLIC_PHANDLE));
+            dt.edit_property(&rtc_node_path, &format!("interrupts"), DeviceTreeProperty::UnsignedInt32(rtc::VIRTUAL_RTC_IRQ as u32));
         }
 
         /* direct console IO through the SBI interface, run OS in single-user mode */
@@ -297,16 +775,36 @@ impl Devices
     }
 }
 
+/* phandles used in virtualized environments' device trees. the virtual
+PLIC comes first and each vCPU's local interrupt controller follows */
+const VIRTUAL_PLIC_PHANDLE: u32 = 1;
+
+/* return the phandle of the given vCPU's local interrupt controller */
+fn virtual_intc_phandle(cpu: usize) -> u32
+{
+    VIRTUAL_PLIC_PHANDLE + 1 + cpu as u32
+}
+
 /* find a suitable serial port for the debug console and create the SerialPort object for it,
 or return an error code */
 fn setup_debug_console(dt: &DeviceTree) -> Result<serial::SerialPort, DeviceTreeError>
+{
+    match get_debug_console_path(dt)
+    {
+        Some(path) => create_debug_console(&dt, &path),
+        None => Err(DeviceTreeError::NotFound)
+    }
+}
+
+/* return the device tree path of the serial port to use for the debug console, or None if none found */
+fn get_debug_console_path(dt: &DeviceTree) -> Option<String>
 {
     /* check if the firmware has chosen a specific device for debug output */
     if let Ok(node) = dt.get_property(&format!("/chosen"), &format!("stdout-path"))
     {
         if let Ok(path) = node.as_text()
         {
-            return create_debug_console(&dt, &path);
+            return Some(path);
         }
     }
 
@@ -318,12 +816,88 @@ fn setup_debug_console(dt: &DeviceTree) -> Result<serial::SerialPort, DeviceTree
         {
             if let Ok(alias_path) = node.as_text()
             {
-                return create_debug_console(&dt, &alias_path);
+                return Some(alias_path);
             }
         }
     }
 
-    Err(DeviceTreeError::NotFound)
+    None
+}
+
+/* return the debug console's baud rate in bits per second, or None if the device tree doesn't say.
+   this is taken from the serial node's current-speed property, or from the options,
+   such as 115200n8, that may follow a colon in /chosen/stdout-path */
+fn get_debug_console_speed(dt: &DeviceTree) -> Option<u64>
+{
+    let path = get_debug_console_path(dt)?;
+    let mut parts = path.splitn(2, ':');
+    let node = String::from(parts.next()?);
+
+    if let Ok(speed) = dt.get_property(&node, &format!("current-speed"))
+    {
+        if let Ok(speed) = speed.as_u32()
+        {
+            if speed > 0
+            {
+                return Some(speed as u64);
+            }
+        }
+    }
+
+    let options = parts.next()?;
+    let digits: String = options.chars().take_while(|c| c.is_ascii_digit()).collect();
+    match digits.parse::<u64>()
+    {
+        Ok(speed) if speed > 0 => Some(speed),
+        _ => None
+    }
+}
+
+/* bits sent on the wire per byte by a serial port: one start bit, eight data bits, one stop bit */
+const UART_BITS_PER_BYTE: u64 = 10;
+
+/* bytes to send before measuring so that the serial port's transmit FIFO is full,
+and each further byte has to wait for one to leave the wire */
+const UART_FIFO_FILL: usize = 64;
+
+/* measure a timer's frequency against the rate at which a serial port sends bytes.
+   NUL bytes are sent, which terminals ignore. the baud clock is divided down from
+   the serial port's own clock, which may not divide exactly, so the result is at best
+   of low confidence
+   => timer = timer to measure
+      uart = serial port to send bytes through
+      speed = serial port's baud rate in bits per second
+   <= measured frequency and confidence, or None if the serial port or timer failed */
+fn calibrate_against_uart(timer: &timer::Timer, uart: &serial::SerialPort, speed: u64) -> Option<timer::Timebase>
+{
+    for _ in 0..UART_FIFO_FILL
+    {
+        if uart.send_byte(0) == false
+        {
+            return None;
+        }
+    }
+
+    /* the reference clock is the time taken to send each byte, in nanoseconds */
+    let sent = core::cell::Cell::new(0u64);
+    let timebase = timer::calibrate(timer, ||
+    {
+        match uart.send_byte(0)
+        {
+            true =>
+            {
+                sent.set(sent.get() + 1);
+                Some(timer::scale(sent.get() * UART_BITS_PER_BYTE, timer::BILLION, speed, timer::Rounding::Down))
+            },
+            false => None
+        }
+    })?;
+
+    Some(timer::Timebase
+    {
+        frequency: timebase.frequency,
+        confidence: timer::TimebaseConfidence::Low
+    })
 }
 
 /* create a SerialPort object from the given devicetree node, or return an error */
@@ -408,16 +982,13 @@ fn get_ram_chunk(dt: &DeviceTree, path: &String) -> Result<physmem::RAMArea, Dev
     }
 }
 
-/* return a new timer from the given device tree CLINT node, or error for failure */
-fn get_system_timer(dt: &DeviceTree, path: &String) -> Result<timer::Timer, DeviceTreeError>
+/* return a new timer from the given device tree CLINT node, or error for failure
+   => dt = device tree to search
+      path = path of the CLINT's node
+      tbf = timer's frequency in Hz
+   <= timer object, or error for failure */
+fn get_system_timer(dt: &DeviceTree, path: &String, tbf: u64) -> Result<timer::Timer, DeviceTreeError>
 {
-    /* get timebase frequency: assume it's a 32-bit value? :( */
-    let tbf = match dt.get_property(&format!("/cpus"), &format!("timebase-frequency"))
-    {
-        Ok(f) => f.as_u32()? as u64,
-        Err(e) => return Err(e)
-    };
-
     /* get the width of the CLINT's addresses and sizes */
     let parent = devicetree::get_parent(path);
     let cells = dt.get_address_size_cells(&parent);
@@ -436,4 +1007,576 @@ fn get_system_timer(dt: &DeviceTree, path: &String) -> Result<timer::Timer, Devi
         2 => Ok(timer::Timer::new(tbf, reg.as_multi_u64()?[0] as usize)),
         _ => Err(DeviceTreeError::WidthUnsupported)
     }
-}
\ No newline at end of file
+}
+
+/* return the rate at which the CPU cores' timers tick, in Hz. this is usually given in /cpus
+   though may instead be in each CPU core's node. it may be a 32-bit or 64-bit value */
+fn get_timebase_frequency(dt: &DeviceTree) -> Result<u64, DeviceTreeError>
+{
+    if let Ok(freq) = get_timebase_frequency_property(dt, &format!("/cpus"))
+    {
+        return Ok(freq);
+    }
+
+    /* all the cores should tick at the same rate, so use the first one found */
+    for node in dt.iter(&format!("/cpus/cpu"), 2)
+    {
+        if let Ok(freq) = get_timebase_frequency_property(dt, &node)
+        {
+            return Ok(freq);
+        }
+    }
+
+    Err(DeviceTreeError::NotFound)
+}
+
+/* return the timebase-frequency property of the given node as a 32-bit or 64-bit value */
+fn get_timebase_frequency_property(dt: &DeviceTree, path: &String) -> Result<u64, DeviceTreeError>
+{
+    let cells = dt.get_property(path, &format!("timebase-frequency"))?.as_multi_u32()?;
+    match cells.len()
+    {
+        1 => Ok(cells[0] as u64),
+        2 => Ok(((cells[0] as u64) << 32) | cells[1] as u64),
+        _ => Err(DeviceTreeError::WidthUnsupported)
+    }
+}
+
+/* return a list of (base address, size) pairs from a device's reg property */
+fn get_reg_regions(dt: &DeviceTree, path: &String) -> Result<Vec<(usize, usize)>, DeviceTreeError>
+{
+    let parent = devicetree::get_parent(path);
+    let cells = dt.get_address_size_cells(&parent);
+    let reg = dt.get_property(path, &format!("reg"))?;
+
+    /* only cope with addresses and sizes of the same width */
+    let values: Vec<usize> = match (cells.address, cells.size)
+    {
+        (1, 1) => reg.as_multi_u32()?.iter().map(|v| *v as usize).collect(),
+        (2, 2) => reg.as_multi_u64()?.iter().map(|v| *v as usize).collect(),
+        (_, _) => return Err(DeviceTreeError::WidthUnsupported)
+    };
+
+    Ok(values.chunks(2).filter(|c| c.len() == 2).map(|c| (c[0], c[1])).collect())
+}
+
+/* device tree compatible strings for supported real-time clocks */
+const RTC_COMPATIBLE: &'static [&'static str] = &[ "google,goldfish-rtc" ];
+const WATCHDOG_COMPATIBLE: &'static [&'static str] = &[ "sifive,wdog0" ];
+
+/* device tree compatible strings for the separate parts of an ACLINT */
+const ACLINT_MTIMER_COMPATIBLE: &'static [&'static str] = &[ "riscv,aclint-mtimer" ];
+const ACLINT_MSWI_COMPATIBLE:   &'static [&'static str] = &[ "riscv,aclint-mswi" ];
+const ACLINT_SSWI_COMPATIBLE:   &'static [&'static str] = &[ "riscv,aclint-sswi" ];
+
+/* by default, an ACLINT MTIMER's mtime register follows its mtimecmp registers at this offset */
+const ACLINT_MTIMER_MTIME: usize = 0x7ff8;
+
+/* find the mtimecmp and mtime registers of an ACLINT MTIMER. if its reg property has two
+   regions, the eight-byte one is mtime and the other is the mtimecmp registers, in either
+   order. if there's only one region, assume the default layout
+   <= (address of the first mtimecmp register, address of mtime), or error for failure */
+fn get_aclint_mtimer_registers(dt: &DeviceTree, path: &String) -> Result<(usize, usize), DeviceTreeError>
+{
+    let regions = get_reg_regions(dt, path)?;
+    match regions.len()
+    {
+        1 => Ok((regions[0].0, regions[0].0 + ACLINT_MTIMER_MTIME)),
+        2 => match regions[0].1
+        {
+            8 => Ok((regions[1].0, regions[0].0)),
+            _ => Ok((regions[0].0, regions[1].0))
+        },
+        _ => Err(DeviceTreeError::WidthUnsupported)
+    }
+}
+
+/* return a new timer from the given ACLINT MTIMER node, and record where each hart's
+   registers are. each entry in its interrupts-extended property is a hart's timer interrupt,
+   in the order of the harts' mtimecmp registers. without one, slots match hart IDs
+   => dt = device tree to search
+      path = path of the MTIMER's node
+      tbf = timer's frequency in Hz
+   <= timer object, or error for failure */
+fn get_aclint_mtimer(dt: &DeviceTree, path: &String, tbf: u64) -> Result<timer::Timer, DeviceTreeError>
+{
+    let (mtimecmp, mtime) = get_aclint_mtimer_registers(dt, path)?;
+
+    for (slot, hart) in get_hart_slots(dt, path, timer::INTC_MACHINE_TIMER, 1)?
+    {
+        timer::add_hart_timer(timer::HartTimer::from_aclint(hart, mtimecmp, mtime, slot));
+    }
+
+    Ok(timer::Timer::new_aclint(tbf, mtimecmp, mtime))
+}
+
+/* record where each hart's MSIP register is in the given ACLINT MSWI node. harts' MTIMERs
+   must be found first. each entry in its interrupts-extended property is a hart's software
+   interrupt, in the order of the harts' 32-bit MSIP registers. without one, slots match hart IDs */
+fn map_aclint_mswi(dt: &DeviceTree, path: &String) -> Result<(), DeviceTreeError>
+{
+    let base = match get_reg_regions(dt, path)?.first()
+    {
+        Some((base, _)) => *base,
+        None => return Err(DeviceTreeError::WidthUnsupported)
+    };
+
+    for (slot, hart) in get_hart_slots(dt, path, timer::INTC_MACHINE_SOFTWARE, 1)?
+    {
+        timer::set_hart_msip(hart, base + (slot * 4));
+    }
+    Ok(())
+}
+
+/* record where each hart's SETSSIP register is in the given ACLINT SSWI node. each entry
+   in its interrupts-extended property is a hart's supervisor software interrupt, in the
+   order of the harts' 32-bit SETSSIP registers. without one, slots match hart IDs */
+fn map_aclint_sswi(dt: &DeviceTree, path: &String) -> Result<(), DeviceTreeError>
+{
+    let base = match get_reg_regions(dt, path)?.first()
+    {
+        Some((base, _)) => *base,
+        None => return Err(DeviceTreeError::WidthUnsupported)
+    };
+
+    for (slot, hart) in get_hart_slots(dt, path, timer::INTC_SUPERVISOR_SOFTWARE, 1)?
+    {
+        ipi::add_supervisor_swi(hart, base + (slot * 4));
+    }
+    Ok(())
+}
+
+/* device tree compatible strings for supported CLINTs */
+const CLINT_COMPATIBLE: &'static [&'static str] = &[ "riscv,clint0", "sifive,clint0" ];
+
+/* return the paths of all the CLINTs in the device tree */
+fn get_clints(dt: &DeviceTree) -> Vec<String>
+{
+    let mut clints = find_compatible(dt, CLINT_COMPATIBLE);
+    for path in dt.iter(&format!("/soc/clint@"), 2)
+    {
+        if clints.contains(&path) == false
+        {
+            clints.push(path);
+        }
+    }
+    clints
+}
+
+/* record where each hart's timer and MSIP registers are in the given CLINT. the CLINT's
+   interrupts-extended property lists a pair of MSIP and timer interrupts for each hart,
+   in the order of the harts' slots in the CLINT, so a hart's slot isn't always its hart ID.
+   without one, slots match hart IDs
+   => dt = device tree to search
+      path = path of the CLINT's node
+   <= number of harts found, or error for failure */
+fn map_clint_harts(dt: &DeviceTree, path: &String) -> Result<usize, DeviceTreeError>
+{
+    let parent = devicetree::get_parent(path);
+    let cells = dt.get_address_size_cells(&parent);
+    let reg = dt.get_property(path, &format!("reg"))?;
+    let base = match cells.address
+    {
+        1 => reg.as_multi_u32()?[0] as usize,
+        2 => reg.as_multi_u64()?[0] as usize,
+        _ => return Err(DeviceTreeError::WidthUnsupported)
+    };
+
+    let slots = get_hart_slots(dt, path, timer::INTC_MACHINE_TIMER, 2)?;
+    for (slot, hart) in slots.iter()
+    {
+        timer::add_hart_timer(timer::HartTimer::from_clint(*hart, base, *slot));
+    }
+
+    Ok(slots.len())
+}
+
+/* device tree compatible strings for supported PLICs */
+const PLIC_COMPATIBLE: &'static [&'static str] = &[ "riscv,plic0", "sifive,plic-1.0.0" ];
+
+/* return the paths of the device tree nodes in /soc compatible with any of the given strings */
+fn find_compatible(dt: &DeviceTree, compatible: &[&str]) -> Vec<String>
+{
+    let mut found = Vec::new();
+    for path in dt.iter(&format!("/soc/"), 2)
+    {
+        if let Ok(prop) = dt.get_property(&path, &format!("compatible"))
+        {
+            if let Ok(text) = prop.as_text()
+            {
+                if compatible.iter().any(|c| text.contains(c)) == true
+                {
+                    found.push(path);
+                }
+            }
+        }
+    }
+    found
+}
+
+/* return true if every CPU core in the device tree lists the given multi-letter extension,
+   either in its riscv,isa-extensions string list, or in its riscv,isa string,
+   eg: rv64imafdch_zicsr_sstc lists sstc. false if there are no cores */
+fn all_cpus_have_extension(dt: &DeviceTree, extension: &str) -> bool
+{
+    let mut found = false;
+    for node in dt.iter(&format!("/cpus/cpu"), 2)
+    {
+        /* newer trees list each extension as a separate string */
+        if let Ok(prop) = dt.get_property(&node, &format!("riscv,isa-extensions"))
+        {
+            match prop.as_text()
+            {
+                Ok(list) if list.split('\0').any(|e| e.to_lowercase() == extension) == true =>
+                {
+                    found = true;
+                    continue;
+                },
+                _ => return false
+            }
+        }
+
+        /* skip nodes that aren't CPU cores, such as /cpus/cpu-map */
+        let isa = match dt.get_property(&node, &format!("riscv,isa"))
+        {
+            Ok(prop) => match prop.as_text()
+            {
+                Ok(isa) => isa.to_lowercase(),
+                Err(_) => return false
+            },
+            Err(_) => continue
+        };
+
+        /* multi-letter extensions follow the single-letter ones, separated by underscores */
+        if isa.split('_').skip(1).any(|e| e == extension) == false
+        {
+            return false;
+        }
+        found = true;
+    }
+
+    found
+}
+
+/* return a list of (node path, hart ID) pairs for each CPU core in the device tree.
+the first ID in a core's reg property is its hart ID. nodes without one are skipped */
+fn get_cpu_hart_ids(dt: &DeviceTree) -> Vec<(String, usize)>
+{
+    let cells = dt.get_address_size_cells(&format!("/cpus"));
+    let mut harts = Vec::new();
+
+    for node in dt.iter(&format!("/cpus/cpu"), 2)
+    {
+        let hart = match (dt.get_property(&node, &format!("reg")), cells.address)
+        {
+            (Ok(reg), 1) => match reg.as_multi_u32()
+            {
+                Ok(ids) if ids.len() > 0 => ids[0] as usize,
+                _ => continue
+            },
+            (Ok(reg), 2) => match reg.as_multi_u64()
+            {
+                Ok(ids) if ids.len() > 0 => ids[0] as usize,
+                _ => continue
+            },
+            (_, _) => continue
+        };
+
+        harts.push((node, hart));
+    }
+
+    harts
+}
+
+/* return a list of (phandle, hart ID) pairs that map each CPU core's local
+interrupt controller to the core's hart ID. devices wired to these controllers
+reference them by phandle in their interrupts-extended properties */
+fn get_cpu_intc_phandles(dt: &DeviceTree) -> Vec<(u32, usize)>
+{
+    let mut phandles = Vec::new();
+
+    for (node, hart) in get_cpu_hart_ids(dt)
+    {
+        if let Ok(prop) = dt.get_property(&format!("{}/interrupt-controller", node), &format!("phandle"))
+        {
+            if let Ok(phandle) = prop.as_u32()
+            {
+                phandles.push((phandle, hart));
+            }
+        }
+    }
+
+    phandles
+}
+
+/* decode a device's interrupts-extended property, which is a list of (phandle, interrupt number)
+pairs that describe how the device is wired to each CPU core's local interrupt controller.
+   => dt = device tree to search
+      path = path of the device's node
+   <= list of (hart ID, interrupt number) pairs in the order they appear, with
+      None for entries that aren't connected to a known CPU core, or error for failure */
+fn get_interrupts_extended(dt: &DeviceTree, path: &String) -> Result<Vec<Option<(usize, u32)>>, DeviceTreeError>
+{
+    let phandles = get_cpu_intc_phandles(dt);
+    let cells = dt.get_property(path, &format!("interrupts-extended"))?.as_multi_u32()?;
+
+    /* assumes each CPU core's interrupt controller has #interrupt-cells = 1 */
+    let mut entries = Vec::new();
+    for pair in cells.chunks(2)
+    {
+        if pair.len() != 2
+        {
+            return Err(DeviceTreeError::WidthUnsupported);
+        }
+
+        entries.push(match phandles.iter().find(|(phandle, _)| *phandle == pair[0])
+        {
+            Some((_, hart)) => Some((*hart, pair[1])),
+            None => None
+        });
+    }
+
+    Ok(entries)
+}
+
+/* return the (slot, hart ID) pairs of the harts wired to the given interrupt of a CLINT or
+   ACLINT device. if the device has no interrupts-extended property, assume every CPU core
+   in the tree is served, with a slot matching its hart ID
+   => dt = device tree to search
+      path = path of the device's node
+      irq = interrupt number of the harts' local interrupt controllers to look for
+      per_hart = number of interrupts-extended entries for each slot
+   <= list of slots and their harts, or error for failure */
+fn get_hart_slots(dt: &DeviceTree, path: &String, irq: u32, per_hart: usize) -> Result<Vec<(usize, usize)>, DeviceTreeError>
+{
+    if dt.get_property(path, &format!("interrupts-extended")).is_err() == true
+    {
+        return Ok(get_cpu_hart_ids(dt).iter().map(|(_, hart)| (*hart, *hart)).collect());
+    }
+
+    let mut slots = Vec::new();
+    for (index, entry) in get_interrupts_extended(dt, path)?.iter().enumerate()
+    {
+        if let Some((hart, wired)) = entry
+        {
+            if *wired == irq
+            {
+                slots.push((index / per_hart, *hart));
+            }
+        }
+    }
+    Ok(slots)
+}
+
+/* return a new PLIC object from the given device tree node, or error for failure */
+fn get_plic(dt: &DeviceTree, path: &String) -> Result<plic::Plic, DeviceTreeError>
+{
+    /* get the width of the PLIC's addresses and sizes */
+    let parent = devicetree::get_parent(path);
+    let cells = dt.get_address_size_cells(&parent);
+
+    /* get the base address and size of the PLIC's MMIO area from its reg property */
+    let reg = dt.get_property(path, &format!("reg"))?;
+    let (base, size) = match cells.address
+    {
+        1 => (reg.as_multi_u32()?[0] as usize, reg.as_multi_u32()?[1] as usize),
+        2 => (reg.as_multi_u64()?[0] as usize, reg.as_multi_u64()?[1] as usize),
+        _ => return Err(DeviceTreeError::WidthUnsupported)
+    };
+
+    let nr_sources = dt.get_property(path, &format!("riscv,ndev"))?.as_u32()? as usize;
+
+    /* each entry in interrupts-extended is a context, numbered in order. the interrupt
+    number says which privilege mode of the CPU core the context interrupts */
+    let mut contexts = Vec::new();
+    for (index, entry) in get_interrupts_extended(dt, path)?.iter().enumerate()
+    {
+        if let Some((hart, irq)) = entry
+        {
+            let mode = match *irq
+            {
+                plic::INTC_MACHINE_EXTERNAL => cpu::PrivilegeMode::Machine,
+                plic::INTC_SUPERVISOR_EXTERNAL => cpu::PrivilegeMode::Supervisor,
+                _ => continue /* unconnected contexts are typically marked with -1 */
+            };
+
+            contexts.push(plic::PlicContext { hart: *hart, mode, index });
+        }
+    }
+
+    Ok(plic::Plic::new(base, size, nr_sources, contexts))
+}
+
+/* compatible strings for AIA controllers */
+const IMSIC_COMPATIBLE: &'static [&'static str] = &[ "riscv,imsics" ];
+const APLIC_COMPATIBLE: &'static [&'static str] = &[ "riscv,aplic" ];
+
+/* find the machine and supervisor-level IMSICs in the device tree
+   <= (machine-level IMSIC, its phandle, supervisor-level IMSIC), with None for those not found */
+fn get_imsics(dt: &DeviceTree) -> (Option<imsic::Imsic>, Option<u32>, Option<imsic::Imsic>)
+{
+    let mut machine = None;
+    let mut machine_phandle = None;
+    let mut supervisor = None;
+
+    for path in find_compatible(dt, IMSIC_COMPATIBLE)
+    {
+        match get_imsic(dt, &path)
+        {
+            Ok(i) => match i.get_mode()
+            {
+                cpu::PrivilegeMode::Machine =>
+                {
+                    machine_phandle = match dt.get_property(&path, &format!("phandle"))
+                    {
+                        Ok(p) => p.as_u32().ok(),
+                        Err(_) => None
+                    };
+                    machine = Some(i);
+                },
+                cpu::PrivilegeMode::Supervisor => supervisor = Some(i),
+                _ => ()
+            },
+            Err(_) => ()
+        }
+    }
+
+    (machine, machine_phandle, supervisor)
+}
+
+/* return a new IMSIC object from the given device tree node, or error for failure */
+fn get_imsic(dt: &DeviceTree, path: &String) -> Result<imsic::Imsic, DeviceTreeError>
+{
+    let parent = devicetree::get_parent(path);
+    let cells = dt.get_address_size_cells(&parent);
+
+    /* the files are laid out from the base of the first reg entry */
+    let reg = dt.get_property(path, &format!("reg"))?;
+    let base = match cells.address
+    {
+        1 => reg.as_multi_u32()?[0] as usize,
+        2 => reg.as_multi_u64()?[0] as usize,
+        _ => return Err(DeviceTreeError::WidthUnsupported)
+    };
+
+    let nr_ids = dt.get_property(path, &format!("riscv,num-ids"))?.as_u32()? as usize;
+    let optional = |name: &str, default: usize| match dt.get_property(path, &format!("{}", name))
+    {
+        Ok(p) => match p.as_u32()
+        {
+            Ok(v) => v as usize,
+            Err(_) => default
+        },
+        Err(_) => default
+    };
+
+    /* each entry in interrupts-extended is a CPU core's file, in hart index order.
+    the interrupt number says which privilege level the files belong to */
+    let entries = get_interrupts_extended(dt, path)?;
+    let guest_index_bits = optional("riscv,guest-index-bits", 0);
+    /* by default, enough bits to give each entry its own hart index: ceil(log2(entries)) */
+    let hart_index_bits = optional("riscv,hart-index-bits", match entries.len()
+    {
+        0 | 1 => 0,
+        n => (0usize.leading_zeros() - (n - 1).leading_zeros()) as usize
+    });
+    let group_index_shift = optional("riscv,group-index-shift", 24);
+
+    let mut mode = None;
+    let mut files = Vec::new();
+    for (index, entry) in entries.iter().enumerate()
+    {
+        if let Some((hart, irq)) = entry
+        {
+            mode = match *irq
+            {
+                plic::INTC_MACHINE_EXTERNAL => Some(cpu::PrivilegeMode::Machine),
+                plic::INTC_SUPERVISOR_EXTERNAL => Some(cpu::PrivilegeMode::Supervisor),
+                _ => continue
+            };
+
+            /* each core's files are (1 << guest index bits) pages long, and groups of cores are
+            spaced by the group index shift. the low bits of the hart index select the core */
+            let group = index >> hart_index_bits;
+            let member = index & ((1 << hart_index_bits) - 1);
+            let address = base + (group << group_index_shift) + (member << (guest_index_bits + 12));
+            files.push(imsic::ImsicFile { hart: *hart, index, address });
+        }
+    }
+
+    match mode
+    {
+        Some(m) => Ok(imsic::Imsic::new(m, nr_ids, guest_index_bits, files)),
+        None => Err(DeviceTreeError::NotFound)
+    }
+}
+
+/* find the APLIC domain that delivers machine-level interrupts. in MSI mode, this is the domain
+   whose msi-parent is the machine-level IMSIC. in direct mode, it's wired to the cores' machine
+   external interrupt lines
+   => dt = device tree to search
+      imsic_phandle = phandle of the machine-level IMSIC, if any
+   <= APLIC object, or None if not found */
+fn get_machine_aplic(dt: &DeviceTree, imsic_phandle: Option<u32>) -> Option<aplic::Aplic>
+{
+    for path in find_compatible(dt, APLIC_COMPATIBLE)
+    {
+        let parent = devicetree::get_parent(&path);
+        let cells = dt.get_address_size_cells(&parent);
+        let reg = match dt.get_property(&path, &format!("reg"))
+        {
+            Ok(r) => r,
+            Err(_) => continue
+        };
+        let (base, size) = match cells.address
+        {
+            1 => match reg.as_multi_u32() { Ok(r) => (r[0] as usize, r[1] as usize), Err(_) => continue },
+            2 => match reg.as_multi_u64() { Ok(r) => (r[0] as usize, r[1] as usize), Err(_) => continue },
+            _ => continue
+        };
+        let nr_sources = match dt.get_property(&path, &format!("riscv,num-sources"))
+        {
+            Ok(p) => match p.as_u32() { Ok(n) => n as usize, Err(_) => continue },
+            Err(_) => continue
+        };
+
+        /* MSI mode */
+        if let Ok(p) = dt.get_property(&path, &format!("msi-parent"))
+        {
+            if let (Ok(phandle), Some(wanted)) = (p.as_u32(), imsic_phandle)
+            {
+                if phandle == wanted
+                {
+                    return Some(aplic::Aplic::new(base, size, nr_sources, true, Vec::new()));
+                }
+            }
+            continue;
+        }
+
+        /* direct mode: each entry in interrupts-extended is a hart index */
+        if let Ok(entries) = get_interrupts_extended(dt, &path)
+        {
+            let mut harts = Vec::new();
+            let mut machine = false;
+            for entry in entries
+            {
+                match entry
+                {
+                    Some((hart, irq)) =>
+                    {
+                        machine = machine || irq == plic::INTC_MACHINE_EXTERNAL;
+                        harts.push(hart);
+                    },
+                    None => harts.push(!0) /* unconnected hart index */
+                }
+            }
+
+            if machine == true
+            {
+                return Some(aplic::Aplic::new(base, size, nr_sources, false, harts));
+            }
+        }
+    }
+
+    None
+}
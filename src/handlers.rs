@@ -0,0 +1,231 @@
+/* diosix RV64 registerable interrupt and exception handlers
+ *
+ * Platform subsystems and drivers can hook IRQ causes and external
+ * interrupt sources here rather than editing a central match.
+ * The hypervisor passes each IRQ from irq::dispatch() to handle(),
+ * which runs the registered handlers and returns whatever isn't
+ * claimed for the hypervisor to deal with as before. External
+ * interrupt sources that no handler deals with are left claimed
+ * and listed in the returned IRQ so they can be routed to guests.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::vec::Vec;
+use spin::Mutex;
+use super::irq::{IRQ, IRQCause, IRQContext};
+use super::plic;
+use super::aplic;
+use super::imsic;
+use super::rtc;
+
+lazy_static!
+{
+    /* acquire HANDLERS lock to access the list of handlers, which is kept in descending
+    priority order. the default chain is set up the first time the list is used */
+    static ref HANDLERS: Mutex<Vec<Entry>> = Mutex::new(default_chain());
+}
+
+/* each registered handler gets a unique ID number */
+static NEXT_HANDLER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/* priority of the platform's built-in handlers. these run after all others */
+pub const PRIORITY_DEFAULT: usize = 0;
+
+/* an IRQ handler is a function that is given the IRQ and the interrupted code's stacked
+   registers, which it can modify. it returns true if it dealt with the IRQ */
+pub type Handler = fn(irq: &IRQ, context: &mut IRQContext) -> bool;
+
+/* identify a registered handler */
+pub type HandlerID = usize;
+
+/* what a handler can be registered against */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum HandlerSource
+{
+    Cause(IRQCause),    /* an exception or interrupt cause */
+    External(usize)     /* an external interrupt source number from the PLIC or APLIC, or an IMSIC identity */
+}
+
+/* describe a registered handler */
+#[derive(Copy, Clone)]
+struct Entry
+{
+    id: HandlerID,
+    source: HandlerSource,
+    priority: usize,
+    claims: bool,
+    handler: Handler
+}
+
+/* register a handler
+   => source = IRQ cause or external interrupt source to handle
+      priority = handlers with higher priorities run first. handlers with equal
+                 priorities are run in the order they were registered
+      claims = true to stop running other handlers for this IRQ, and hide it from the
+               hypervisor, if this handler deals with it. false to always pass it on
+      handler = function to call
+   <= ID of the registered handler */
+pub fn register(source: HandlerSource, priority: usize, claims: bool, handler: Handler) -> HandlerID
+{
+    let id = NEXT_HANDLER_ID.fetch_add(1, Ordering::SeqCst);
+    let mut handlers = HANDLERS.lock();
+
+    /* insert after all handlers of equal or higher priority */
+    let position = handlers.iter().position(|e| e.priority < priority).unwrap_or(handlers.len());
+    handlers.insert(position, Entry { id, source, priority, claims, handler });
+    id
+}
+
+/* remove a registered handler
+   => id = ID of handler to remove
+   <= true if removed, or false if no such handler */
+pub fn unregister(id: HandlerID) -> bool
+{
+    let mut handlers = HANDLERS.lock();
+    match handlers.iter().position(|e| e.id == id)
+    {
+        Some(position) =>
+        {
+            handlers.remove(position);
+            true
+        },
+        None => false
+    }
+}
+
+/* run the handlers registered for an IRQ
+   => irq = IRQ described by irq::dispatch()
+      context = interrupted code's stacked registers
+   <= the IRQ if no handler claimed it, for the hypervisor to deal with,
+      or None for no further action needed. for a machine external interrupt,
+      the IRQ's external list holds any claimed sources nothing dealt with,
+      which the hypervisor must complete with complete_external() */
+pub fn handle(mut irq: IRQ, context: &mut IRQContext) -> Option<IRQ>
+{
+    if run(HandlerSource::Cause(irq.cause), &irq, context) == true
+    {
+        return None;
+    }
+
+    if irq.cause == IRQCause::MachineInterrupt && external_handlers_registered() == true
+    {
+        irq.external = dispatch_external(&irq, context);
+        if irq.external.len() == 0
+        {
+            return None;
+        }
+    }
+
+    Some(irq)
+}
+
+/* complete an external interrupt source that was left claimed by handle()
+   for the hypervisor. sources claimed from the AIA are completed when claimed
+   => source = source number or interrupt identity from the IRQ's external list */
+pub fn complete_external(source: usize)
+{
+    if plic::is_pinned() == true
+    {
+        plic::complete(source);
+    }
+}
+
+/* run the handlers registered for the given source, in priority order
+   <= true if a claiming handler dealt with the IRQ */
+fn run(source: HandlerSource, irq: &IRQ, context: &mut IRQContext) -> bool
+{
+    /* don't hold the lock while handlers run so they can register and unregister handlers */
+    let chain: Vec<Entry> = HANDLERS.lock().iter().filter(|e| e.source == source).cloned().collect();
+
+    for entry in chain
+    {
+        if (entry.handler)(irq, context) == true && entry.claims == true
+        {
+            return true;
+        }
+    }
+
+    false
+}
+
+/* return true if any handler is registered for an external interrupt source */
+fn external_handlers_registered() -> bool
+{
+    HANDLERS.lock().iter().any(|e| match e.source
+    {
+        HandlerSource::External(_) => true,
+        _ => false
+    })
+}
+
+/* create the default chain of built-in handlers */
+fn default_chain() -> Vec<Entry>
+{
+    let mut chain = Vec::new();
+
+    /* emulate guests' accesses to their virtual RTCs */
+    for cause in [IRQCause::LoadGuestPageFault, IRQCause::StoreGuestPageFault].iter()
+    {
+        chain.push(Entry
+        {
+            id: NEXT_HANDLER_ID.fetch_add(1, Ordering::SeqCst),
+            source: HandlerSource::Cause(*cause),
+            priority: PRIORITY_DEFAULT,
+            claims: true,
+            handler: rtc::emulate
+        });
+    }
+
+    chain
+}
+
+/* claim each pending machine-level external interrupt source from the PLIC, or from the AIA
+   if there's no PLIC, and run its handlers. sources dealt with by a handler are completed.
+   a source that no handler claims is left claimed, and isn't masked, so that the hypervisor
+   can route it to a guest's virtual PLIC and complete it when the guest does
+   <= list of claimed sources that no handler dealt with */
+fn dispatch_external(irq: &IRQ, context: &mut IRQContext) -> Vec<usize>
+{
+    let mut unhandled = Vec::new();
+    while let Some(source) = claim_external()
+    {
+        /* a level-triggered source stays pending in the AIA while it's asserted */
+        if unhandled.contains(&source) == true
+        {
+            break;
+        }
+
+        if run(HandlerSource::External(source), irq, context) == true
+        {
+            complete_external(source);
+        }
+        else
+        {
+            unhandled.push(source);
+        }
+    }
+
+    unhandled
+}
+
+/* claim the highest-priority pending external interrupt for this CPU core from whichever
+   controller is present. MSIs are claimed from the core's IMSIC file through mtopei,
+   and wired interrupts from the PLIC, or the APLIC in direct mode
+   <= source number or interrupt identity, or None if nothing is pending */
+fn claim_external() -> Option<usize>
+{
+    if plic::is_pinned() == true
+    {
+        return plic::claim();
+    }
+
+    match imsic::is_pinned()
+    {
+        true => imsic::claim(),
+        false => aplic::claim()
+    }
+}
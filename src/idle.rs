@@ -0,0 +1,92 @@
+/* diosix RV64 tickless CPU core idling
+ *
+ * Rather than waking an idle CPU core with a periodic scheduler
+ * tick, put it to sleep with WFI until its earliest queued timer
+ * deadline, another core interrupts it, or an external interrupt
+ * arrives. Only those interrupts are enabled while the core sleeps,
+ * and the reason it woke is reported back to the caller
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use alloc::vec::Vec;
+use super::timerqueue;
+use super::ipi;
+use super::smp;
+use super::watchdog;
+
+/* machine-level interrupt enable and pending bits in mie and mip */
+const MIE_MSIE: usize = 1 << 3;
+const MIE_MTIE: usize = 1 << 7;
+const MIE_MEIE: usize = 1 << 11;
+
+/* machine-level global interrupt enable bit in mstatus */
+const MSTATUS_MIE: usize = 1 << 3;
+
+/* why an idle CPU core woke up */
+#[derive(Debug, Clone)]
+pub enum WakeReason
+{
+    Timer(Vec<timerqueue::ExpiredTimer>),   /* these deadlines fell due, earliest first */
+    IPI,                                    /* another core interrupted this one, and its requests were processed */
+    External,                               /* an external interrupt is pending and must be claimed by the caller */
+    Spurious                                /* woke for no visible reason, which WFI is allowed to do */
+}
+
+/* sleep this CPU core until its next queued timer deadline, an IPI, or an external interrupt.
+   the scheduler should cancel its own periodic deadline first, so that only real deadlines,
+   such as virtual CPU cores' timers, wake the core. if nothing is queued, the core sleeps
+   until interrupted. interrupts aren't taken as traps while the core sleeps: instead, the
+   cause is dealt with here where possible and reported. mie and mstatus are restored afterwards
+   <= reason the core woke */
+pub fn idle() -> WakeReason
+{
+    /* only wake for the timer if there's a deadline to wake for */
+    let wake_on = match timerqueue::next_deadline()
+    {
+        Some(_) => MIE_MSIE | MIE_MTIE | MIE_MEIE,
+        None => MIE_MSIE | MIE_MEIE
+    };
+
+    /* stop pending interrupts being taken as traps, and enable only those that should wake us.
+    WFI still wakes on a pending, enabled interrupt with mstatus.MIE clear */
+    let saved_status = read_csr!(mstatus);
+    clear_csr!(mstatus, MSTATUS_MIE);
+    let saved_mie = read_csr!(mie);
+    write_csr!(mie, wake_on);
+
+    unsafe { llvm_asm!("wfi" :::: "volatile") };
+
+    let pending = read_csr!(mip) & wake_on;
+    write_csr!(mie, saved_mie);
+    if saved_status & MSTATUS_MIE != 0
+    {
+        set_csr!(mstatus, MSTATUS_MIE);
+    }
+
+    /* deal with the causes in the same order of priority as the hardware: external, software, timer */
+    if pending & MIE_MEIE != 0
+    {
+        return WakeReason::External;
+    }
+
+    if pending & MIE_MSIE != 0
+    {
+        ipi::clear_ipi();
+        smp::process();
+        watchdog::alive(); /* answers any liveness check */
+        return WakeReason::IPI;
+    }
+
+    if pending & MIE_MTIE != 0
+    {
+        /* this reprograms the timer for the next deadline, clearing the pending interrupt */
+        let expired = timerqueue::expired();
+        watchdog::handle_expired(&expired);
+        return WakeReason::Timer(expired);
+    }
+
+    WakeReason::Spurious
+}
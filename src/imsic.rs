@@ -0,0 +1,433 @@
+/* diosix RV64 incoming message-signaled interrupt controller (IMSIC) driver
+ *
+ * Each CPU core has an interrupt file per privilege level, plus guest
+ * interrupt files on H-extension hardware. Devices and other cores raise
+ * interrupts by writing an interrupt identity (EIID) to a file's MMIO page.
+ * The core's own file is controlled through the indirect CSRs.
+ *
+ * Derived from the RISC-V Advanced Interrupt Architecture specification:
+ * https://github.com/riscv/riscv-aia
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::ptr::write_volatile;
+use spin::Mutex;
+use alloc::vec::Vec;
+use super::physmem;
+use super::cpu;
+use super::cpu::PrivilegeMode;
+
+lazy_static!
+{
+    /* acquire PINNED_IMSIC lock before accessing the system's machine-level IMSIC */
+    static ref PINNED_IMSIC: Mutex<Option<Imsic>> = Mutex::new(None);
+
+    /* acquire ALLOCATED_IDS lock to allocate interrupt identities. bit n set = EIID n in use.
+    identities are allocated system-wide so a device can be steered to any core using the same EIID */
+    static ref ALLOCATED_IDS: Mutex<[u64; IMSIC_MAX_IDS / 64]> =
+    {
+        let mut ids = [0; IMSIC_MAX_IDS / 64];
+        ids[0] = 1; /* EIID 0 is reserved to mean no interrupt */
+        Mutex::new(ids)
+    };
+
+    /* acquire a core's GUEST_FILES lock to allocate its guest interrupt files,
+    indexed by linear CPU core ID. bit n set = guest file n in use */
+    static ref GUEST_FILES: cpu::PerCpu<Mutex<u64>> = cpu::PerCpu::new(|| Mutex::new(1)); /* guest file 0 means no guest file */
+}
+
+/* interrupt files support identities 1 to 2047 */
+pub const IMSIC_MAX_IDS: usize = 2048;
+
+/* each interrupt file occupies a 4KB page. writing an EIID to
+the little-endian seteipnum register at the start of the page raises it */
+const IMSIC_FILE_SIZE:      usize = 4096;
+const IMSIC_SETEIPNUM_LE:   usize = 0x0;
+
+/* indirect CSR access: select a register with ?iselect and access it with ?ireg */
+const MISELECT: usize = 0x350;
+const SISELECT: usize = 0x150;
+
+/* interrupt file registers accessed through the indirect CSRs */
+const IMSIC_EIDELIVERY:     usize = 0x70;   /* 1 = deliver interrupts from this file */
+const IMSIC_EITHRESHOLD:    usize = 0x72;   /* mask identities at or above this value, 0 = no mask */
+const IMSIC_EIP_BASE:       usize = 0x80;   /* pending bits, 64 per register on RV64... */
+const IMSIC_EIE_BASE:       usize = 0xc0;   /* enable bits, 64 per register on RV64... */
+                                            /* ...using only the even-numbered registers */
+
+/* the top pending and enabled identity is in bits 16-26 of ?topei */
+const TOPEI_ID_SHIFT:       usize = 16;
+const TOPEI_ID_MASK:        usize = 0x7ff;
+
+/* machine-level external interrupt enable bit in mie */
+const MIE_MEIE: usize = 1 << 11;
+
+/* H extension: guest interrupt file select field (VGEIN) in bits 12-17 of hstatus */
+const HSTATUS_VGEIN_SHIFT:  usize = 12;
+const HSTATUS_VGEIN_MASK:   usize = 0x3f;
+
+/* describe a CPU core's interrupt file */
+#[derive(Debug, Clone, Copy)]
+pub struct ImsicFile
+{
+    pub hart: usize,                    /* mhartid of the CPU core this file interrupts */
+    pub index: usize,                   /* the core's hart index within this IMSIC */
+    pub address: physmem::PhysMemBase   /* physical address of the file's MMIO page */
+}
+
+/* describe a set of interrupt files for one privilege level */
+#[derive(Debug, Clone)]
+pub struct Imsic
+{
+    mode: PrivilegeMode,            /* privilege level of the files */
+    nr_ids: usize,                  /* number of interrupt identities supported per file */
+    guest_index_bits: usize,        /* guest files per core = (1 << guest_index_bits) - 1 */
+    files: Vec<ImsicFile>           /* interrupt file of each CPU core */
+}
+
+impl Imsic
+{
+    /* create a new IMSIC object
+       => mode = privilege level of the interrupt files
+          nr_ids = number of identities supported, not including 0
+          guest_index_bits = number of address bits selecting a core's guest files
+          files = each CPU core's interrupt file
+       <= IMSIC object */
+    pub fn new(mode: PrivilegeMode, nr_ids: usize, guest_index_bits: usize, files: Vec<ImsicFile>) -> Imsic
+    {
+        Imsic
+        {
+            mode,
+            nr_ids: if nr_ids >= IMSIC_MAX_IDS { IMSIC_MAX_IDS - 1 } else { nr_ids },
+            guest_index_bits,
+            files
+        }
+    }
+
+    /* register this IMSIC as the pinned controller, allowing other platform code to find it */
+    pub fn pin(&self)
+    {
+        let mut pinned = PINNED_IMSIC.lock();
+        *pinned = Some(self.clone());
+    }
+
+    /* return information about this controller */
+    pub fn get_mode(&self) -> PrivilegeMode { self.mode }
+    pub fn get_nr_ids(&self) -> usize { self.nr_ids }
+    pub fn get_files(&self) -> &Vec<ImsicFile> { &self.files }
+    pub fn get_guest_index_bits(&self) -> usize { self.guest_index_bits }
+
+    /* return the interrupt file of the given CPU core, if any */
+    pub fn find_file(&self, hart: usize) -> Option<&ImsicFile>
+    {
+        self.files.iter().find(|f| f.hart == hart)
+    }
+
+    /* return the physical address of one of a CPU core's guest interrupt files.
+       these follow the core's supervisor-level file
+       => hart = mhartid of the CPU core
+          guest = guest file number, counting from 1
+       <= physical address of the file's MMIO page, or None if no such file */
+    pub fn guest_file_address(&self, hart: usize, guest: usize) -> Option<physmem::PhysMemBase>
+    {
+        if guest == 0 || guest >= (1 << self.guest_index_bits)
+        {
+            return None;
+        }
+
+        match self.find_file(hart)
+        {
+            Some(f) => Some(f.address + (guest * IMSIC_FILE_SIZE)),
+            None => None
+        }
+    }
+
+    /* raise an interrupt in a CPU core's file by sending it a message
+       => hart = mhartid of the CPU core to interrupt
+          id = interrupt identity to raise
+       <= true for success, or false if no such core or bad identity */
+    pub fn send(&self, hart: usize, id: usize) -> bool
+    {
+        if self.valid_id(id) == false
+        {
+            return false;
+        }
+
+        match self.find_file(hart)
+        {
+            Some(f) =>
+            {
+                unsafe { write_volatile((f.address + IMSIC_SETEIPNUM_LE) as *mut u32, id as u32) };
+                true
+            },
+            None => false
+        }
+    }
+
+    /* prepare the running CPU core's machine-level file to deliver interrupts.
+       all identities are masked until they are enabled
+       <= true for success, or false if this isn't a machine-level IMSIC */
+    pub fn init_hart(&self) -> bool
+    {
+        match self.mode
+        {
+            PrivilegeMode::Machine => (),
+            _ => return false
+        }
+
+        for reg in 0..((self.nr_ids / 64) + 1)
+        {
+            write_ireg(MISELECT, IMSIC_EIE_BASE + (reg * 2), 0);
+            write_ireg(MISELECT, IMSIC_EIP_BASE + (reg * 2), 0);
+        }
+
+        write_ireg(MISELECT, IMSIC_EITHRESHOLD, 0);
+        write_ireg(MISELECT, IMSIC_EIDELIVERY, 1);
+        set_csr!(mie, MIE_MEIE);
+        true
+    }
+
+    /* allow or stop an interrupt identity from interrupting the running CPU core
+       => id = interrupt identity
+       <= true for success, or false for a bad identity */
+    pub fn enable(&self, id: usize) -> bool { self.update_enable(id, true) }
+    pub fn disable(&self, id: usize) -> bool { self.update_enable(id, false) }
+
+    /* check an interrupt identity is within range */
+    fn valid_id(&self, id: usize) -> bool
+    {
+        id > 0 && id <= self.nr_ids
+    }
+
+    /* set or clear an identity's enable bit in the running core's file at this IMSIC's level */
+    fn update_enable(&self, id: usize, enabled: bool) -> bool
+    {
+        if self.valid_id(id) == false { return false; }
+
+        let select = match self.mode
+        {
+            PrivilegeMode::Machine => MISELECT,
+            _ => SISELECT
+        };
+
+        let reg = IMSIC_EIE_BASE + ((id / 64) * 2);
+        let bits = read_ireg(select, reg);
+        write_ireg(select, reg, match enabled
+        {
+            true => bits | (1 << (id % 64)),
+            false => bits & !(1 << (id % 64))
+        });
+        true
+    }
+}
+
+/* read a register in the running core's interrupt file through the indirect CSRs
+   => select = MISELECT for the machine-level file, or SISELECT for the supervisor-level file
+      reg = register number to read */
+fn read_ireg(select: usize, reg: usize) -> usize
+{
+    match select
+    {
+        MISELECT =>
+        {
+            write_csr!(0x350, reg); /* miselect */
+            read_csr!(0x351)        /* mireg */
+        },
+        _ =>
+        {
+            write_csr!(0x150, reg); /* siselect */
+            read_csr!(0x151)        /* sireg */
+        }
+    }
+}
+
+/* write a register in the running core's interrupt file through the indirect CSRs */
+fn write_ireg(select: usize, reg: usize, value: usize)
+{
+    match select
+    {
+        MISELECT =>
+        {
+            write_csr!(0x350, reg);
+            write_csr!(0x351, value);
+        },
+        _ =>
+        {
+            write_csr!(0x150, reg);
+            write_csr!(0x151, value);
+        }
+    }
+}
+
+/* claim the highest-priority pending and enabled interrupt in the running CPU core's
+   file at the given level. this reads and clears mtopei or stopei in one go so that a
+   higher-priority interrupt arriving in between isn't lost
+   => mode = privilege level of the file to claim from
+   <= interrupt identity, or None if nothing is pending */
+pub fn claim_from(mode: PrivilegeMode) -> Option<usize>
+{
+    let topei: usize;
+    match mode
+    {
+        PrivilegeMode::Machine => unsafe { llvm_asm!("csrrw $0, 0x35c, x0" : "=r"(topei) ::: "volatile") }, /* mtopei */
+        _ => unsafe { llvm_asm!("csrrw $0, 0x15c, x0" : "=r"(topei) ::: "volatile") }                     /* stopei */
+    }
+
+    match (topei >> TOPEI_ID_SHIFT) & TOPEI_ID_MASK
+    {
+        0 => None,
+        id => Some(id)
+    }
+}
+
+/* claim the highest-priority pending interrupt in the running CPU core's machine-level file,
+   or None if nothing's pending or there's no IMSIC */
+pub fn claim() -> Option<usize>
+{
+    match is_pinned()
+    {
+        true => claim_from(PrivilegeMode::Machine),
+        false => None
+    }
+}
+
+/* stop an interrupt identity from interrupting the running CPU core's machine-level file
+   using the pinned IMSIC */
+pub fn disable(id: usize)
+{
+    if let Some(imsic) = &*PINNED_IMSIC.lock()
+    {
+        imsic.disable(id);
+    }
+}
+
+/* raise an interrupt on a CPU core using the pinned IMSIC
+   => cpu = linear CPU core ID of the core to interrupt
+      id = interrupt identity to raise
+   <= true for success, or false for failure */
+pub fn send(cpu: cpu::CPUcount, id: usize) -> bool
+{
+    match (cpu::cpu_id_to_hart_id(cpu), &*PINNED_IMSIC.lock())
+    {
+        (Some(hart), Some(imsic)) => imsic.send(hart, id),
+        (_, _) => false
+    }
+}
+
+/* return true if an IMSIC has been pinned for platform code to use */
+pub fn is_pinned() -> bool
+{
+    PINNED_IMSIC.lock().is_some()
+}
+
+/* allocate an unused interrupt identity
+   => limit = highest identity supported by the interrupt files
+   <= interrupt identity, or None if all are in use */
+pub fn allocate_id(limit: usize) -> Option<usize>
+{
+    let mut ids = ALLOCATED_IDS.lock();
+    for id in 1..(limit + 1).min(IMSIC_MAX_IDS)
+    {
+        if ids[id / 64] & (1 << (id % 64)) == 0
+        {
+            ids[id / 64] = ids[id / 64] | (1 << (id % 64));
+            return Some(id);
+        }
+    }
+    None
+}
+
+/* release an interrupt identity allocated by allocate_id() */
+pub fn free_id(id: usize)
+{
+    if id > 0 && id < IMSIC_MAX_IDS
+    {
+        let mut ids = ALLOCATED_IDS.lock();
+        ids[id / 64] = ids[id / 64] & !(1 << (id % 64));
+    }
+}
+
+/* return the number of guest interrupt files the running CPU core implements.
+   this is found by probing which bits of hgeie are writable */
+pub fn nr_guest_files() -> usize
+{
+    if cpu::hypervisor_extension_present() == false
+    {
+        return 0;
+    }
+
+    let previous = read_csr!(0x607);    /* hgeie */
+    write_csr!(0x607, !0 as usize);
+    let implemented = read_csr!(0x607);
+    write_csr!(0x607, previous);
+
+    /* bit 0 is always zero: guest file numbers count from 1 */
+    (implemented >> 1).count_ones() as usize
+}
+
+/* allocate one of the running CPU core's guest interrupt files, for a vCPU to own
+   <= guest file number, or None if none are free */
+pub fn allocate_guest_file() -> Option<usize>
+{
+    let available = nr_guest_files();
+    let mut files = GUEST_FILES.this().lock();
+    for guest in 1..(available + 1)
+    {
+        if *files & (1 << guest) == 0
+        {
+            *files = *files | (1 << guest);
+            return Some(guest);
+        }
+    }
+    None
+}
+
+/* release one of the running CPU core's guest interrupt files */
+pub fn free_guest_file(guest: usize)
+{
+    if guest > 0 && guest < 64
+    {
+        disable_guest_file(guest);
+        let mut files = GUEST_FILES.this().lock();
+        *files = *files & !(1 << guest);
+    }
+}
+
+/* connect a guest interrupt file to the running vCPU, so that interrupts sent to the
+   file are delivered straight to the guest as virtual supervisor external interrupts.
+   call this when scheduling in the vCPU that owns the file
+   => guest = guest file number, or 0 to disconnect */
+pub fn select_guest_file(guest: usize)
+{
+    if cpu::hypervisor_extension_present() == false { return; }
+    clear_csr!(0x600, HSTATUS_VGEIN_MASK << HSTATUS_VGEIN_SHIFT);  /* hstatus */
+    set_csr!(0x600, (guest & HSTATUS_VGEIN_MASK) << HSTATUS_VGEIN_SHIFT);
+}
+
+/* allow a guest file to raise supervisor guest external interrupts for the hypervisor,
+   eg: to wake a vCPU that isn't running */
+pub fn enable_guest_file(guest: usize)
+{
+    if cpu::hypervisor_extension_present() == false { return; }
+    set_csr!(0x607, 1 << guest); /* hgeie */
+}
+
+/* stop a guest file from raising supervisor guest external interrupts */
+pub fn disable_guest_file(guest: usize)
+{
+    if cpu::hypervisor_extension_present() == false { return; }
+    clear_csr!(0x607, 1 << guest);
+}
+
+/* return a bitmask of guest files with pending interrupts. bit n set = guest file n */
+pub fn pending_guest_files() -> usize
+{
+    match cpu::hypervisor_extension_present()
+    {
+        true => read_csr!(0xe12), /* hgeip */
+        false => 0
+    }
+}
@@ -8,18 +8,21 @@
  */
 
 use super::irq::IRQContext;
-use super::cpu::PrivilegeMode;
-use super::timer;
+use super::cpu::{self, PrivilegeMode};
+use super::vclock;
+use super::stats;
+use super::timer::{self, TimerValue};
 
 extern "C"
 {
-    fn platform_read_u32_as_prev_mode(address: usize) -> u32;
+    fn platform_read_u32_as_prev_mode(address: usize, value: &mut u32) -> usize;
 }
 
 #[derive(PartialEq)]
 pub enum EmulationResult
 {
     Success, /* we were able to emulate the faulting instruction */
+    TimerIRQAt(TimerValue), /* emulated, and the supervisor's timer interrupt should be raised at or after this time */
     CantEmulate, /* don't have the means to emulate this instruction */
     CantAccess, /* can't locate or access the illegal instruction */
     IllegalInstruction, /* this instruction is truly illegal, can't be run */
@@ -31,9 +34,20 @@ const RDTIME_INST:  u32 = 0xc01 << 20 | 2 << 12 | 0x1c << 2 | 3;
 const RDTIME_MASK:  u32 = !(0x1f << 7);
 const WFI_INST:     u32 = 0x10500073;
 
+/* CSR access instructions, and the CSRs we emulate with them */
+const SYSTEM_OPCODE:    u32 = 0x73;
+const CSR_STIMECMP:     u32 = 0x14d;
+
+/* major opcodes of memory access instructions */
+const LOAD_OPCODE:      u32 = 0x03;
+const LOAD_FP_OPCODE:   u32 = 0x07;
+const STORE_OPCODE:     u32 = 0x23;
+const STORE_FP_OPCODE:  u32 = 0x27;
+const AMO_OPCODE:       u32 = 0x2f;
+
 /* attempt to emulate the currently faulting instruction. this can use and modify
-   the given context as necessary. this function may raise a fault,
-   which the hypervisor should catch and deal with appropriately
+   the given context as necessary. if the instruction can't be read,
+   CantAccess is returned for the hypervisor to deal with appropriately
    => priv_mode = privilege mode the instruction was executed in
       context = state of the CPU core trying to run the instruction,
                 which may be modified as necessary.
@@ -43,16 +57,29 @@ pub fn emulate(_priv_mode: PrivilegeMode, context: &mut IRQContext) -> Emulation
     /* get the address of the faulting instruction */
     let addr = read_csr!(mepc) as usize;
 
-    /* ensure any faults are blamed on the mode that tried to execute the instruction */
-    let instruction = unsafe { platform_read_u32_as_prev_mode(addr) };
+    /* read the instruction with the access rights of the mode that tried to execute it */
+    let instruction = match fetch_prev_mode(addr)
+    {
+        Some(i) => i,
+        None =>
+        {
+            stats::count_emulated(stats::Emulated::Failed);
+            return EmulationResult::CantAccess;
+        }
+    };
 
     /* try to enulate the rdtime instruction, which reads the 64-bit real-time clock */
     if (instruction & RDTIME_MASK) == RDTIME_INST
     {
-        let time_now = match (timer::get_pinned_timer_now(), timer::get_pinned_timer_freq())
+        /* read the time as seen by the running supervisor */
+        let time_now = match vclock::running_now()
         {
-            (Some(t), Some(f)) => t.to_exact(f),
-            (_, _) => return EmulationResult::CantEmulate
+            Some(t) => t,
+            None =>
+            {
+                stats::count_emulated(stats::Emulated::Failed);
+                return EmulationResult::CantEmulate;
+            }
         };
 
         /* update destination register with current (low) word of the timer */
@@ -60,6 +87,7 @@ pub fn emulate(_priv_mode: PrivilegeMode, context: &mut IRQContext) -> Emulation
         context.registers[rd as usize] = time_now as usize;
 
         increment_epc(); /* go to next instuction */
+        stats::count_emulated(stats::Emulated::ReadTime);
         return EmulationResult::Success;
     }
 
@@ -71,13 +99,199 @@ pub fn emulate(_priv_mode: PrivilegeMode, context: &mut IRQContext) -> Emulation
     {
         /* TODO: actually make the vCPU ait for an interrupt? */
         increment_epc(); /* go to next instruction on return */
+        stats::count_emulated(stats::Emulated::WaitForIRQ);
         return EmulationResult::Yield;
     }
 
+    /* catch accesses to stimecmp from supervisors whose clocks don't match the host timer */
+    if let Some(result) = emulate_stimecmp(instruction, context)
+    {
+        return result;
+    }
+
     /* fall through to a confirmed illegal instruction */
+    stats::count_emulated(stats::Emulated::Failed);
     EmulationResult::IllegalInstruction
 }
 
+/* fetch the instruction at the given address as the previous privilege mode.
+   a fault during the read is caught and not passed on
+   => addr = address of the instruction
+   <= instruction bits, or None if the previous mode can't read the address.
+      16-bit compressed instructions are returned in the low half-word */
+pub fn fetch_prev_mode(addr: usize) -> Option<u32>
+{
+    let mut instruction: u32 = 0;
+    if unsafe { platform_read_u32_as_prev_mode(addr, &mut instruction) } != 0
+    {
+        return None;
+    }
+
+    match instruction & 0b11
+    {
+        0b11 => Some(instruction),
+        _ => Some(instruction & 0xffff)
+    }
+}
+
+/* convert a transformed instruction from mtinst into a standard 32-bit instruction
+   => mtinst = value of mtinst
+   <= standard instruction bits, or None if mtinst doesn't hold a transformed instruction */
+pub fn from_transformed(mtinst: usize) -> Option<u32>
+{
+    /* bit 0 is clear for pseudoinstructions, and bit 1 is cleared if the
+       original instruction was compressed. otherwise it's a 32-bit encoding */
+    match mtinst & 1
+    {
+        1 => Some((mtinst as u32) | 0b10),
+        _ => None
+    }
+}
+
+/* decode the width of a load, store, or atomic memory instruction
+   => instruction = 32-bit or 16-bit compressed instruction bits to decode
+   <= size of the access in bytes, or None if this isn't a recognized memory access */
+pub fn access_width(instruction: u32) -> Option<usize>
+{
+    /* the low two bits of a compressed instruction are never 0b11 */
+    if instruction & 0b11 != 0b11
+    {
+        /* quadrants 0 and 2, identified by bits 0-1, with the operation in bits 13-15 */
+        return match (instruction & 0b11, (instruction >> 13) & 0b111)
+        {
+            (0b00, 0b001) | (0b10, 0b001) => Some(8), /* c.fld, c.fldsp */
+            (0b00, 0b010) | (0b10, 0b010) => Some(4), /* c.lw, c.lwsp */
+            (0b00, 0b011) | (0b10, 0b011) => Some(8), /* c.ld, c.ldsp */
+            (0b00, 0b101) | (0b10, 0b101) => Some(8), /* c.fsd, c.fsdsp */
+            (0b00, 0b110) | (0b10, 0b110) => Some(4), /* c.sw, c.swsp */
+            (0b00, 0b111) | (0b10, 0b111) => Some(8), /* c.sd, c.sdsp */
+            (_, _) => None
+        };
+    }
+
+    let funct3 = (instruction >> 12) & 0b111;
+    match (instruction & 0x7f, funct3)
+    {
+        (LOAD_OPCODE, 0) | (LOAD_OPCODE, 4) | (STORE_OPCODE, 0) => Some(1),
+        (LOAD_OPCODE, 1) | (LOAD_OPCODE, 5) | (STORE_OPCODE, 1) => Some(2),
+        (LOAD_OPCODE, 2) | (LOAD_OPCODE, 6) | (STORE_OPCODE, 2) => Some(4),
+        (LOAD_OPCODE, 3) | (STORE_OPCODE, 3) => Some(8),
+        (LOAD_FP_OPCODE, 2) | (STORE_FP_OPCODE, 2) | (AMO_OPCODE, 2) => Some(4),
+        (LOAD_FP_OPCODE, 3) | (STORE_FP_OPCODE, 3) | (AMO_OPCODE, 3) => Some(8),
+        (_, _) => None
+    }
+}
+
+/* decode the integer register a load or store instruction accesses
+   => instruction = 32-bit or 16-bit compressed instruction bits to decode
+   <= rd for a load, rs2 for a store, or None if this isn't an integer load or store */
+pub fn access_register(instruction: u32) -> Option<usize>
+{
+    if instruction & 0b11 != 0b11
+    {
+        return match (instruction & 0b11, (instruction >> 13) & 0b111)
+        {
+            (0b00, 0b010) | (0b00, 0b011) |
+            (0b00, 0b110) | (0b00, 0b111) => Some(8 + ((instruction >> 2) & 0b111) as usize), /* c.lw, c.ld, c.sw, c.sd */
+            (0b10, 0b010) | (0b10, 0b011) => Some(((instruction >> 7) & 0x1f) as usize),    /* c.lwsp, c.ldsp */
+            (0b10, 0b110) | (0b10, 0b111) => Some(((instruction >> 2) & 0x1f) as usize),    /* c.swsp, c.sdsp */
+            (_, _) => None
+        };
+    }
+
+    match instruction & 0x7f
+    {
+        LOAD_OPCODE => Some(((instruction >> 7) & 0x1f) as usize),
+        STORE_OPCODE => Some(((instruction >> 20) & 0x1f) as usize),
+        _ => None
+    }
+}
+
+/* return true if a load instruction sign-extends the value it reads, or false if it zero-extends it */
+pub fn load_is_signed(instruction: u32) -> bool
+{
+    match (instruction & 0x7f, (instruction >> 12) & 0b111)
+    {
+        (LOAD_OPCODE, 4) | (LOAD_OPCODE, 5) | (LOAD_OPCODE, 6) => false, /* lbu, lhu, lwu */
+        (_, _) => true
+    }
+}
+
+/* move mepc past an instruction that trapped, having emulated it
+   => instruction = bits of the instruction
+      mtinst = value of mtinst, if available. a transformed instruction in mtinst
+               is always 32 bits, so bit 1 says whether the original was compressed */
+pub fn step_over(instruction: u32, mtinst: Option<usize>)
+{
+    let length = match mtinst
+    {
+        Some(t) if t & 1 == 1 => if t & 0b10 == 0 { 2 } else { 4 },
+        _ => if instruction & 0b11 == 0b11 { 4 } else { 2 }
+    };
+
+    let epc = read_csr!(mepc);
+    write_csr!(mepc, epc + length);
+}
+
+/* emulate a csrrw, csrrs, csrrc, or their immediate forms, accessing stimecmp. the supervisor
+   sees stimecmp in its view of time, and the hypervisor is asked to raise its timer interrupt
+   at the matching host time, as with the SBI's set timer call
+   => instruction = instruction bits to emulate
+      context = registers of the supervisor running the instruction
+   <= outcome of the emulation, or None if this isn't an emulated stimecmp access */
+fn emulate_stimecmp(instruction: u32, context: &mut IRQContext) -> Option<EmulationResult>
+{
+    if instruction & 0x7f != SYSTEM_OPCODE || instruction >> 20 != CSR_STIMECMP
+    {
+        return None;
+    }
+
+    let rd = ((instruction >> 7) & 0x1f) as usize;
+    let funct3 = (instruction >> 12) & 0b111;
+    let rs1 = ((instruction >> 15) & 0x1f) as usize;
+
+    /* bit 2 of funct3 selects the 5-bit immediate in place of rs1. x0 isn't stacked */
+    let operand = match (funct3 & 0b100, rs1)
+    {
+        (0, 0) => 0,
+        (0, _) => context.registers[rs1] as u64,
+        (_, _) => rs1 as u64
+    };
+
+    let (previous, _) = cpu::emulate_stimecmp(None)?;
+    let value = match funct3 & 0b11
+    {
+        0b01 => Some(operand),                                  /* csrrw(i) */
+        0b10 if rs1 != 0 => Some(previous | operand),           /* csrrs(i) */
+        0b11 if rs1 != 0 => Some(previous & !operand),          /* csrrc(i) */
+        0b10 | 0b11 => None,                                    /* read only */
+        _ => return None
+    };
+
+    if rd != 0
+    {
+        context.registers[rd] = previous as usize;
+    }
+
+    increment_epc();
+    stats::count_emulated(stats::Emulated::TimerCompare);
+
+    /* a write withdraws any timer interrupt raised for the previous deadline */
+    let value = match value
+    {
+        Some(v) => v,
+        None => return Some(EmulationResult::Success)
+    };
+    timer::clear_supervisor_irq();
+    timer::enable_supervisor_irq();
+
+    match cpu::emulate_stimecmp(Some(value))?
+    {
+        (_, Some(host_target)) => Some(EmulationResult::TimerIRQAt(TimerValue::Exact(host_target))),
+        (_, None) => Some(EmulationResult::Success)
+    }
+}
+
 /* increment epc to the next 32-bit instruction.
    TODO: How fragile is this? Assuming 4-byte instr and
    also relying on mepc being used later on as the interrupted
@@ -0,0 +1,144 @@
+/* diosix RV64 inter-processor interrupts
+ *
+ * Physical CPU cores interrupt each other by writing to
+ * their machine software interrupt pending (MSIP) bits
+ * in the CLINT or ACLINT MSWI device. An ACLINT SSWI device
+ * also allows supervisor software interrupts to be raised
+ * on other cores directly
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::ptr::write_volatile;
+use alloc::vec::Vec;
+use spin::Mutex;
+use super::cpu;
+use super::timer;
+use super::physmem;
+
+lazy_static!
+{
+    /* acquire SSWI_HARTS lock to access the (hart ID, SETSSIP register address)
+    pairs of harts that have an ACLINT supervisor software interrupt register */
+    static ref SSWI_HARTS: Mutex<Vec<(usize, physmem::PhysMemBase)>> = Mutex::new(Vec::new());
+}
+
+/* machine software interrupt enable bit in mie */
+const MIE_MSIE: usize = 1 << 3;
+
+/* raise a machine software interrupt on the given CPU core
+   => cpu = linear CPU core ID of the core to interrupt
+   <= true for success, or false if there's no such core or no CLINT */
+pub fn send_ipi(cpu: cpu::CPUcount) -> bool
+{
+    match cpu::cpu_id_to_hart_id(cpu)
+    {
+        Some(hart) => write_msip(hart, 1),
+        None => false
+    }
+}
+
+/* return true if the given CPU core can be sent a machine software interrupt
+   => cpu = linear CPU core ID of the core to check */
+pub fn can_send_ipi(cpu: cpu::CPUcount) -> bool
+{
+    match cpu::cpu_id_to_hart_id(cpu)
+    {
+        Some(hart) => msip_address(hart).is_some(),
+        None => false
+    }
+}
+
+/* raise a machine software interrupt on a set of CPU cores
+   => cpus = bitmask of linear CPU core IDs to interrupt: bit n set to interrupt core n
+   <= number of cores interrupted */
+pub fn send_ipi_mask(cpus: u64) -> usize
+{
+    let mut sent = 0;
+    for cpu in 0..cpu::MAX_CPUS
+    {
+        if cpus & (1 << cpu) != 0 && send_ipi(cpu) == true
+        {
+            sent = sent + 1;
+        }
+    }
+    sent
+}
+
+/* clear this CPU core's pending machine software interrupt */
+pub fn clear_ipi()
+{
+    write_msip(read_csr!(mhartid), 0);
+}
+
+/* record the location of a hart's ACLINT SETSSIP register
+   => hart = hart ID of the core
+      setssip = address of the register */
+pub fn add_supervisor_swi(hart: usize, setssip: physmem::PhysMemBase)
+{
+    let mut harts = SSWI_HARTS.lock();
+    harts.retain(|(h, _)| *h != hart);
+    harts.push((hart, setssip));
+}
+
+/* raise a supervisor software interrupt on the given CPU core via its ACLINT SETSSIP
+   register. this doesn't interrupt the machine level on the target core, though any
+   supervisor running there will take the interrupt. the register clears itself
+   => cpu = linear CPU core ID of the core to interrupt
+   <= true for success, or false if there's no such core or it has no SSWI register */
+pub fn send_supervisor_ipi(cpu: cpu::CPUcount) -> bool
+{
+    let hart = match cpu::cpu_id_to_hart_id(cpu)
+    {
+        Some(h) => h,
+        None => return false
+    };
+
+    match SSWI_HARTS.lock().iter().find(|(h, _)| *h == hart)
+    {
+        Some((_, setssip)) =>
+        {
+            unsafe { write_volatile(*setssip as *mut u32, 1); }
+            true
+        },
+        None => false
+    }
+}
+
+/* allow this CPU core to be interrupted by other cores */
+pub fn enable_ipi()
+{
+    set_csr!(mie, MIE_MSIE);
+}
+
+/* return the address of a hart's MSIP register. if the device tree didn't say where it
+   is, assume it's in the pinned timer's CLINT at a slot matching its hart ID */
+fn msip_address(hart: usize) -> Option<physmem::PhysMemBase>
+{
+    match timer::get_hart_timer(hart)
+    {
+        Some(t) => t.msip,
+        None => match (timer::pinned_timer_is_clint(), timer::get_pinned_timer_base())
+        {
+            (true, Some(clint)) => Some(clint + timer::CLINT_MSIP + (hart * 4)),
+            (_, _) => None
+        }
+    }
+}
+
+/* write to a hart's MSIP register: 1 to raise its machine software interrupt, 0 to clear it
+   <= true for success, or false if the hart's MSIP register isn't known */
+fn write_msip(hart: usize, value: u32) -> bool
+{
+    match msip_address(hart)
+    {
+        Some(msip) =>
+        {
+            unsafe { write_volatile(msip as *mut u32, value); }
+            true
+        },
+        None => false
+    }
+}
@@ -5,7 +5,15 @@
  * See LICENSE for usage and copying.
  */
 
+use alloc::vec::Vec;
 use super::cpu;
+use super::ipi;
+use super::smp;
+use super::instructions;
+use super::crashdump;
+use super::stats;
+use super::timerqueue;
+use super::watchdog;
 
 /* describe the type of interruption */
 #[derive(Copy, Clone)]
@@ -41,6 +49,15 @@ pub enum IRQCause
     UserInterrupt,
     SupervisorInterrupt,
     MachineInterrupt,
+    /* interrupts for virtual supervisors, defined by the H extension */
+    VirtualSupervisorSWI,
+    VirtualSupervisorTimer,
+    VirtualSupervisorInterrupt,
+    SupervisorGuestInterrupt, /* supervisor guest external interrupt (SGEI) */
+    /* local counter overflow interrupt, defined by the Sscofpmf extension */
+    CounterOverflow,
+    /* platform-specific local interrupt, numbered 16 and above */
+    Local(usize),
 
     /* common CPU faults */
     InstructionAlignment,
@@ -55,14 +72,43 @@ pub enum IRQCause
     StorePageFault,
     Breakpoint,
 
+    /* faults defined by the H extension */
+    InstructionGuestPageFault,
+    LoadGuestPageFault,
+    StoreGuestPageFault,
+    VirtualInstruction,
+
+    /* checks defined by newer extensions, eg: Zicfilp and Smrnmi */
+    SoftwareCheck,
+    HardwareError,
+
     /* other ways to call down from user to supervisor, etc */
     UserEnvironmentCall,
     SupervisorEnvironmentCall,
+    VirtualSupervisorEnvironmentCall,
     MachineEnvironmentCall,
 
     Unknown, /* unknown, undefined, or reserved type */
 }
 
+/* describe the kind of memory access that faulted */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MemoryAccess
+{
+    Load,   /* data read */
+    Store,  /* data write, including atomic memory operations */
+    Fetch   /* instruction fetch */
+}
+
+/* describe a faulting memory access */
+#[derive(Debug, Copy, Clone)]
+pub struct MemoryFault
+{
+    pub access: MemoryAccess,   /* type of access that faulted */
+    pub address: usize,         /* faulting virtual address */
+    pub width: Option<usize>    /* size of the access in bytes, if it can be decoded */
+}
+
 /* describe IRQ in high-level, portable terms */
 pub struct IRQ
 {
@@ -72,6 +118,15 @@ pub struct IRQ
     pub cause: IRQCause, /* cause of this interruption */
     pub pc: usize,   /* where in memory this IRQ occurred */
     pub sp: usize,   /* stack pointer for interrupted supervisor */
+    pub trap_value: usize, /* mtval: bad address or instruction bits, depending on the cause, or 0 */
+    pub trap_inst: Option<usize>, /* mtinst, if the H extension is present and it's non-zero */
+    pub guest_addr: Option<usize>, /* mtval2 (the machine-level htval) if the H extension is present and
+                                      it's non-zero: a faulting guest physical address shifted right 2 bits */
+    pub instruction: Option<u32>, /* bits of the instruction that trapped, if known */
+    pub fault: Option<MemoryFault>, /* decoded details of a memory access fault */
+    pub timers: Vec<timerqueue::ExpiredTimer>, /* deadlines that passed, if this is a machine timer IRQ */
+    pub external: Vec<usize> /* external interrupt sources claimed for this IRQ that no handler dealt with.
+                                these are left claimed for the hypervisor to route and complete */
 }
 
 pub const REG_ZERO: usize = 0;
@@ -116,7 +171,118 @@ pub struct IRQContext
     /* all 32 base registers stacked. the contents of this array will be
     loaded into the registers on exit from the IRQ, so if you want
     to modify any register content, do it here */
-    pub registers: [usize; 32]
+    pub registers: [usize; 32],
+    /* trap CSRs on entry. these are restored on exit if a nested
+    trap overwrote them and the hypervisor didn't write a new mepc */
+    pub epc: usize,
+    pub status: usize,
+    pub cause: usize,
+    pub tval: usize,
+    /* address of the frame of the trap this one interrupted, or 0 if not nested */
+    pub previous: usize,
+    /* used by the low-level handler to track nested traps */
+    clobbered: usize
+}
+
+impl IRQContext
+{
+    /* return true if this trap interrupted the handling of another trap */
+    pub fn is_nested(&self) -> bool
+    {
+        self.previous != 0
+    }
+
+    /* return the frame of the trap this one interrupted, if any */
+    pub fn get_previous(&self) -> Option<&IRQContext>
+    {
+        match self.previous
+        {
+            0 => None,
+            p => Some(unsafe { &*(p as *const IRQContext) })
+        }
+    }
+
+    /* return the number of traps being handled, including this one */
+    pub fn depth(&self) -> usize
+    {
+        match self.get_previous()
+        {
+            Some(previous) => previous.depth() + 1,
+            None => 1
+        }
+    }
+}
+
+/* each CPU core's IRQ stack, which must match HV_CPU_STACK_SIZE and IRQ_STACK_GUARD_SIZE
+in asm/consts.s. the lowest page is a guard page, and can't be used by the stack */
+pub const IRQ_STACK_SIZE: usize = 128 * 1024;
+pub const IRQ_STACK_GUARD_SIZE: usize = 4096;
+
+/* return the range of this CPU core's IRQ stack, excluding its guard page
+   <= (lowest usable address, address of the top of the stack) */
+pub fn irq_stack_bounds() -> (usize, usize)
+{
+    let top = read_csr!(mscratch) as usize;
+    (top - IRQ_STACK_SIZE + IRQ_STACK_GUARD_SIZE, top)
+}
+
+/* return true if the given trap looks like this CPU core's IRQ stack overflowing: either
+   its stack pointer has run into the guard page, or it faulted accessing the guard page */
+fn irq_stack_overflowed(context: &IRQContext) -> bool
+{
+    let (base, _) = irq_stack_bounds();
+    let guard = (base - IRQ_STACK_GUARD_SIZE)..base;
+
+    /* only the hypervisor runs on this stack: check mstatus.MPP was machine mode */
+    if (context.status >> 11) & 0b11 != 3
+    {
+        return false;
+    }
+
+    if guard.contains(&context.registers[REG_SP]) == true
+    {
+        return true;
+    }
+
+    match context.cause
+    {
+        5 | 7 => guard.contains(&context.tval), /* load or store access fault */
+        _ => false
+    }
+}
+
+/* called by the low-level handler, on its emergency stack, when traps nest too deep
+   or a nested trap's stack pointer is bad. report the trap and the ones it interrupted,
+   rather than carry on with corrupted frames
+   => context = frame of the trap that can't be handled */
+#[no_mangle]
+pub extern "C" fn platform_irq_recursion(context: &IRQContext) -> !
+{
+    match irq_stack_overflowed(context)
+    {
+        true => crashdump::fatal(context, "IRQ stack overflow"),
+        false => crashdump::fatal(context, "runaway trap recursion")
+    }
+}
+
+/* called by the low-level handler, on its emergency stack, when a nested trap's handler
+   leaves mstatus set to return to a lower privilege mode. only the outermost trap holds
+   the interrupted supervisor's registers, so the hypervisor must only switch contexts
+   or forward traps to supervisors from there
+   => context = frame of the nested trap */
+#[no_mangle]
+pub extern "C" fn platform_irq_nested_escape(context: &IRQContext) -> !
+{
+    crashdump::fatal(context, "nested trap tried to leave machine mode")
+}
+
+/* called by the low-level handler, on its emergency stack, when it finds the canary
+   above the IRQ stack's guard page has been overwritten on trap entry or exit
+   => context = frame of the trap being handled when the overflow was spotted */
+#[no_mangle]
+pub extern "C" fn platform_irq_stack_overflow(context: &IRQContext) -> !
+{
+    crashdump::fatal(context, "IRQ stack overflow")
 }
 
 /* dispatch
@@ -154,23 +320,116 @@ pub fn dispatch(context: IRQContext) -> Option<IRQ>
         (IRQType::Exception, 7) => (IRQSeverity::Fatal, IRQCause::StoreAccess),
         (IRQType::Exception, 8) => (IRQSeverity::NonFatal, IRQCause::UserEnvironmentCall),
         (IRQType::Exception, 9) => (IRQSeverity::NonFatal, IRQCause::SupervisorEnvironmentCall),
+        (IRQType::Exception, 10) => (IRQSeverity::NonFatal, IRQCause::VirtualSupervisorEnvironmentCall),
         (IRQType::Exception, 11) => (IRQSeverity::NonFatal, IRQCause::MachineEnvironmentCall),
         (IRQType::Exception, 12) => (IRQSeverity::Fatal, IRQCause::InstructionPageFault),
         (IRQType::Exception, 13) => (IRQSeverity::Fatal, IRQCause::LoadPageFault),
         (IRQType::Exception, 15) => (IRQSeverity::Fatal, IRQCause::StorePageFault),
+        (IRQType::Exception, 18) => (IRQSeverity::Fatal, IRQCause::SoftwareCheck),
+        (IRQType::Exception, 19) => (IRQSeverity::Fatal, IRQCause::HardwareError),
+        (IRQType::Exception, 20) => (IRQSeverity::Fatal, IRQCause::InstructionGuestPageFault),
+        (IRQType::Exception, 21) => (IRQSeverity::Fatal, IRQCause::LoadGuestPageFault),
+        (IRQType::Exception, 22) => (IRQSeverity::Fatal, IRQCause::VirtualInstruction),
+        (IRQType::Exception, 23) => (IRQSeverity::Fatal, IRQCause::StoreGuestPageFault),
 
         /* interrupts - none are fatal */
         (IRQType::Interrupt, 0) => (IRQSeverity::NonFatal, IRQCause::UserSWI),
         (IRQType::Interrupt, 1) => (IRQSeverity::NonFatal, IRQCause::SupervisorSWI),
+        (IRQType::Interrupt, 2) => (IRQSeverity::NonFatal, IRQCause::VirtualSupervisorSWI),
         (IRQType::Interrupt, 3) => (IRQSeverity::NonFatal, IRQCause::MachineSWI),
         (IRQType::Interrupt, 4) => (IRQSeverity::NonFatal, IRQCause::UserTimer),
         (IRQType::Interrupt, 5) => (IRQSeverity::NonFatal, IRQCause::SupervisorTimer),
+        (IRQType::Interrupt, 6) => (IRQSeverity::NonFatal, IRQCause::VirtualSupervisorTimer),
         (IRQType::Interrupt, 7) => (IRQSeverity::NonFatal, IRQCause::MachineTimer),
         (IRQType::Interrupt, 8) => (IRQSeverity::NonFatal, IRQCause::UserInterrupt),
         (IRQType::Interrupt, 9) => (IRQSeverity::NonFatal, IRQCause::SupervisorInterrupt),
+        (IRQType::Interrupt, 10) => (IRQSeverity::NonFatal, IRQCause::VirtualSupervisorInterrupt),
         (IRQType::Interrupt, 11) => (IRQSeverity::NonFatal, IRQCause::MachineInterrupt),
+        (IRQType::Interrupt, 12) => (IRQSeverity::NonFatal, IRQCause::SupervisorGuestInterrupt),
+        (IRQType::Interrupt, 13) => (IRQSeverity::NonFatal, IRQCause::CounterOverflow),
+        (IRQType::Interrupt, n) if n >= 16 => (IRQSeverity::NonFatal, IRQCause::Local(n)),
         (_, _) => (IRQSeverity::NonFatal, IRQCause::Unknown)
     };
+    stats::count_irq(cause);
+
+    /* the hypervisor hit the guard page below its IRQ stack. nothing on this core can be trusted now */
+    if irq_stack_overflowed(&context) == true
+    {
+        crashdump::fatal(&context, "IRQ stack overflow");
+    }
+
+    /* a fatal exception raised by the hypervisor itself can't be handed back to anyone.
+    fatal exceptions raised by supervisors are passed on: the hypervisor can end the
+    offending capsule, and should call crashdump::fatal() if it can't recover */
+    if let (IRQSeverity::Fatal, cpu::PrivilegeMode::Machine) = (severity, cpu::previous_privilege())
+    {
+        crashdump::fatal(&context, "fatal exception in the hypervisor");
+    }
+
+    /* other CPU cores raise machine software interrupts to ask this core
+    to carry out work on their behalf. clear the interrupt first so that any
+    requests queued while we're busy will raise it again */
+    if cause == IRQCause::MachineSWI
+    {
+        ipi::clear_ipi();
+        smp::process();
+        watchdog::alive(); /* answers any liveness check */
+    }
+
+    /* collect the deadlines that fell due, and program the timer for the next */
+    let timers = match cause
+    {
+        IRQCause::MachineTimer => timerqueue::expired(),
+        _ => Vec::new()
+    };
+
+    /* keep the watchdog fed on cores whose scheduler tick is stopped */
+    watchdog::handle_expired(&timers);
+
+    /* gather the trap's extra information. mtinst and mtval2 only exist with the H extension */
+    let trap_value = read_csr!(mtval);
+    let (trap_inst, guest_addr) = match cpu::hypervisor_extension_present()
+    {
+        true => (non_zero(read_csr!(0x34a)), non_zero(read_csr!(0x34b))), /* mtinst, mtval2 */
+        false => (None, None)
+    };
+
+    let access = match cause
+    {
+        IRQCause::InstructionAlignment | IRQCause::InstructionAccess |
+        IRQCause::InstructionPageFault | IRQCause::InstructionGuestPageFault => Some(MemoryAccess::Fetch),
+        IRQCause::LoadAlignment | IRQCause::LoadAccess |
+        IRQCause::LoadPageFault | IRQCause::LoadGuestPageFault => Some(MemoryAccess::Load),
+        IRQCause::StoreAlignment | IRQCause::StoreAccess |
+        IRQCause::StorePageFault | IRQCause::StoreGuestPageFault => Some(MemoryAccess::Store),
+        _ => None
+    };
+
+    /* find the bits of the trapping instruction. illegal instructions are usually reported in mtval.
+    for loads and stores, use the transformed instruction in mtinst, or fetch the instruction */
+    let instruction = match (cause, access, trap_inst)
+    {
+        (IRQCause::IllegalInstruction, _, _) => non_zero(trap_value).map(|i| i as u32),
+        (_, Some(MemoryAccess::Load), Some(inst)) | (_, Some(MemoryAccess::Store), Some(inst)) => instructions::from_transformed(inst),
+        (_, Some(MemoryAccess::Load), None) | (_, Some(MemoryAccess::Store), None) => instructions::fetch_prev_mode(read_csr!(mepc)),
+        (_, _, _) => None
+    };
+
+    let fault = match access
+    {
+        Some(access) => Some(MemoryFault
+        {
+            access,
+            address: trap_value,
+            width: match (access, instruction)
+            {
+                (MemoryAccess::Fetch, _) => None,
+                (_, Some(inst)) => instructions::access_width(inst),
+                (_, None) => None
+            }
+        }),
+        None => None
+    };
 
     /* return structure describing this exception to
     the high-level hypervisor for it to deal with */
@@ -184,22 +443,256 @@ pub fn dispatch(context: IRQContext) -> Option<IRQ>
             privilege_mode: crate::cpu::previous_privilege(),
             pc: read_csr!(mepc),
             sp: context.registers[2], /* x2 = sp */
+            trap_value,
+            trap_inst,
+            guest_addr,
+            instruction,
+            fault,
+            timers,
+            external: Vec::new()
         }
     )
 }
 
+/* convert a CSR value into None if it's zero, meaning not implemented or not applicable */
+fn non_zero(value: usize) -> Option<usize>
+{
+    match value
+    {
+        0 => None,
+        v => Some(v)
+    }
+}
+
+/* bits in sstatus updated when a trap is taken by the supervisor */
+const SSTATUS_SIE:  usize = 1 << 1; /* supervisor interrupts enabled */
+const SSTATUS_SPIE: usize = 1 << 5; /* supervisor interrupts enabled prior to trap */
+const SSTATUS_SPP:  usize = 1 << 8; /* privilege mode prior to trap: 1 = supervisor, 0 = user */
+
+/* stvec mode field, in its low two bits */
+const STVEC_MODE_MASK:      usize = 0b11;
+const STVEC_MODE_VECTORED:  usize = 1;
+
+/* convert a high-level cause into the RISC-V exception or interrupt code
+   that would be found in mcause or scause, minus the interrupt bit
+   => cause = cause to convert
+   <= type of IRQ and its code, or None if there's no such code */
+fn cause_to_code(cause: IRQCause) -> Option<(IRQType, usize)>
+{
+    let code = match cause
+    {
+        IRQCause::InstructionAlignment              => (IRQType::Exception, 0),
+        IRQCause::InstructionAccess                 => (IRQType::Exception, 1),
+        IRQCause::IllegalInstruction                => (IRQType::Exception, 2),
+        IRQCause::Breakpoint                        => (IRQType::Exception, 3),
+        IRQCause::LoadAlignment                     => (IRQType::Exception, 4),
+        IRQCause::LoadAccess                        => (IRQType::Exception, 5),
+        IRQCause::StoreAlignment                    => (IRQType::Exception, 6),
+        IRQCause::StoreAccess                       => (IRQType::Exception, 7),
+        IRQCause::UserEnvironmentCall               => (IRQType::Exception, 8),
+        IRQCause::SupervisorEnvironmentCall         => (IRQType::Exception, 9),
+        IRQCause::VirtualSupervisorEnvironmentCall  => (IRQType::Exception, 10),
+        IRQCause::MachineEnvironmentCall            => (IRQType::Exception, 11),
+        IRQCause::InstructionPageFault              => (IRQType::Exception, 12),
+        IRQCause::LoadPageFault                     => (IRQType::Exception, 13),
+        IRQCause::StorePageFault                    => (IRQType::Exception, 15),
+        IRQCause::SoftwareCheck                     => (IRQType::Exception, 18),
+        IRQCause::HardwareError                     => (IRQType::Exception, 19),
+        IRQCause::InstructionGuestPageFault         => (IRQType::Exception, 20),
+        IRQCause::LoadGuestPageFault                => (IRQType::Exception, 21),
+        IRQCause::VirtualInstruction                => (IRQType::Exception, 22),
+        IRQCause::StoreGuestPageFault               => (IRQType::Exception, 23),
+
+        IRQCause::UserSWI                           => (IRQType::Interrupt, 0),
+        IRQCause::SupervisorSWI                     => (IRQType::Interrupt, 1),
+        IRQCause::VirtualSupervisorSWI              => (IRQType::Interrupt, 2),
+        IRQCause::MachineSWI                        => (IRQType::Interrupt, 3),
+        IRQCause::UserTimer                         => (IRQType::Interrupt, 4),
+        IRQCause::SupervisorTimer                   => (IRQType::Interrupt, 5),
+        IRQCause::VirtualSupervisorTimer            => (IRQType::Interrupt, 6),
+        IRQCause::MachineTimer                      => (IRQType::Interrupt, 7),
+        IRQCause::UserInterrupt                     => (IRQType::Interrupt, 8),
+        IRQCause::SupervisorInterrupt               => (IRQType::Interrupt, 9),
+        IRQCause::VirtualSupervisorInterrupt        => (IRQType::Interrupt, 10),
+        IRQCause::MachineInterrupt                  => (IRQType::Interrupt, 11),
+        IRQCause::SupervisorGuestInterrupt          => (IRQType::Interrupt, 12),
+        IRQCause::CounterOverflow                   => (IRQType::Interrupt, 13),
+        IRQCause::Local(n)                          => (IRQType::Interrupt, n),
+
+        IRQCause::Unknown => return None
+    };
+
+    Some(code)
+}
+
+/* forward an exception or interrupt to the interrupted supervisor's own trap handler,
+   as if the hardware had delegated it. on return from the IRQ, the supervisor will
+   enter its trap handler with scause, sepc, stval and sstatus set up accordingly.
+   only call from an IRQ context, and before any other context is loaded.
+   => cause = cause of the trap to forward
+      tval = value for stval, eg: the faulting address or instruction bits
+   <= true for success, or false if the trap can't be forwarded, eg: it interrupted
+      machine-mode code. a stvec of zero is taken as a handler at address zero */
+pub fn inject_into_supervisor(cause: IRQCause, tval: usize) -> bool
+{
+    /* only code running in supervisor or user mode can take a supervisor-level trap */
+    let previous = match cpu::previous_privilege()
+    {
+        cpu::PrivilegeMode::Supervisor => SSTATUS_SPP,
+        cpu::PrivilegeMode::User => 0,
+        cpu::PrivilegeMode::Machine => return false
+    };
+
+    let (irq_type, code) = match cause_to_code(cause)
+    {
+        Some(c) => c,
+        None => return false
+    };
+
+    let stvec = read_csr!(stvec);
+    let handler = stvec & !STVEC_MODE_MASK;
+
+    /* describe the trap to the supervisor. the interrupt bit is the top-most bit of scause */
+    write_csr!(scause, match irq_type
+    {
+        IRQType::Interrupt => (1 << (cpu::get_isa_width() - 1)) | code,
+        IRQType::Exception => code
+    });
+    /* the trap entry path steps mepc over supervisor ecalls, so point sepc back at the ecall */
+    write_csr!(sepc, match cause
+    {
+        IRQCause::SupervisorEnvironmentCall | IRQCause::VirtualSupervisorEnvironmentCall => read_csr!(mepc) - 4,
+        _ => read_csr!(mepc)
+    });
+    write_csr!(stval, tval);
+
+    /* save the supervisor's interrupt enable bit, disable its interrupts,
+       and record the privilege mode it was running in */
+    let sstatus = read_csr!(sstatus);
+    let spie = match sstatus & SSTATUS_SIE
+    {
+        0 => 0,
+        _ => SSTATUS_SPIE
+    };
+    write_csr!(sstatus, (sstatus & !(SSTATUS_SPP | SSTATUS_SPIE | SSTATUS_SIE)) | spie | previous);
+
+    /* return to the supervisor's trap handler, in supervisor mode. in vectored
+    mode, interrupts go to the handler's base address + 4 * the interrupt code */
+    let target = match (stvec & STVEC_MODE_MASK, irq_type)
+    {
+        (STVEC_MODE_VECTORED, IRQType::Interrupt) => handler + (4 * code),
+        (_, _) => handler
+    };
+    write_csr!(mepc, target);
+    cpu::prep_supervisor_return();
+
+    true
+}
+
+/* forward an IRQ, described by dispatch(), to the interrupted supervisor's own trap handler.
+   see inject_into_supervisor() for details
+   => irq = IRQ to forward
+   <= true for success, or false for failure */
+pub fn forward_to_supervisor(irq: &IRQ) -> bool
+{
+    inject_into_supervisor(irq.cause, irq.trap_value)
+}
+
+/* convert a supervisor-level interrupt cause into its pending bit in sip, or None if it has none */
+fn cause_to_sip_bit(cause: IRQCause) -> Option<cpu::Reg>
+{
+    match cause
+    {
+        IRQCause::SupervisorSWI         => Some(cpu::SIP_SSIP),
+        IRQCause::SupervisorTimer       => Some(cpu::SIP_STIP),
+        IRQCause::SupervisorInterrupt   => Some(cpu::SIP_SEIP),
+        _ => None
+    }
+}
+
+/* queue a virtual interrupt for a virtual CPU core. the interrupt is delivered when
+   the vCPU's state is next loaded, and remains pending until retracted. this means
+   interrupts aren't lost if the vCPU is descheduled before it can take them
+   => state = saved supervisor state of the vCPU
+      cause = SupervisorSWI, SupervisorTimer or SupervisorInterrupt
+   <= true for success, or false if the cause can't be injected */
+pub fn inject(state: &mut cpu::SupervisorState, cause: IRQCause) -> bool
+{
+    match cause_to_sip_bit(cause)
+    {
+        Some(bit) =>
+        {
+            state.set_virtual_irq_pending(bit, true);
+            true
+        },
+        None => false
+    }
+}
+
+/* withdraw a queued virtual interrupt from a virtual CPU core
+   => state = saved supervisor state of the vCPU
+      cause = SupervisorSWI, SupervisorTimer or SupervisorInterrupt
+   <= true for success, or false if the cause can't be retracted */
+pub fn retract(state: &mut cpu::SupervisorState, cause: IRQCause) -> bool
+{
+    match cause_to_sip_bit(cause)
+    {
+        Some(bit) =>
+        {
+            state.set_virtual_irq_pending(bit, false);
+            true
+        },
+        None => false
+    }
+}
+
+/* as with inject() and retract() but for the vCPU running on this physical CPU core */
+pub fn inject_running(cause: IRQCause) -> bool
+{
+    match cause_to_sip_bit(cause)
+    {
+        Some(bit) =>
+        {
+            cpu::set_running_virtual_irq_pending(bit, true);
+            true
+        },
+        None => false
+    }
+}
+
+pub fn retract_running(cause: IRQCause) -> bool
+{
+    match cause_to_sip_bit(cause)
+    {
+        Some(bit) =>
+        {
+            cpu::set_running_virtual_irq_pending(bit, false);
+            true
+        },
+        None => false
+    }
+}
+
 /* clear an interrupt condition so we can return without the IRQ firing immediately. */
 pub fn acknowledge(irq: IRQ)
 {
     /* clear the appropriate pending bit in mip */
     let bit = match irq.cause
     {
+        IRQCause::MachineSWI =>
+        {
+            /* machine software interrupts are raised and cleared via the CLINT */
+            ipi::clear_ipi();
+            return;
+        },
         IRQCause::UserSWI               => 0,
         IRQCause::SupervisorSWI         => 1,
+        IRQCause::VirtualSupervisorSWI  => 2,
         IRQCause::UserTimer             => 4,
         IRQCause::SupervisorTimer       => 5,
         IRQCause::UserInterrupt         => 8,
         IRQCause::SupervisorInterrupt   => 9,
+        IRQCause::CounterOverflow       => 13,
         _ => return
     };
 
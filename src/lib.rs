@@ -36,8 +36,22 @@ pub mod virtmem;
 pub mod irq;
 pub mod cpu;
 pub mod timer;
+pub mod timerqueue;
+pub mod idle;
+pub mod watchdog;
+pub mod vclock;
+pub mod rtc;
 pub mod test;
 pub mod devices;
 pub mod errata;
 pub mod instructions;
 pub mod syscalls;
+pub mod plic;
+pub mod vplic;
+pub mod ipi;
+pub mod smp;
+pub mod handlers;
+pub mod crashdump;
+pub mod stats;
+pub mod aplic;
+pub mod imsic;
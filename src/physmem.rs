@@ -6,6 +6,9 @@
  */
 
 use core::intrinsics::transmute;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::vec::Vec;
+use spin::Mutex;
 use super::cpu;
 
 extern "C"
@@ -37,7 +40,7 @@ pub fn tlb_flush()
 }
 
 /* allowed physical memory access permissions for supervisor kernels */
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum AccessPermissions
 {
     Read,
@@ -47,13 +50,106 @@ pub enum AccessPermissions
     NoAccess
 }
 
-/* there are a maximum number of physical memory regions */
+/* there are a maximum number of physical memory regions, though a core may implement fewer */
 const PHYS_PMP_MAX_ENTRY: usize = 15;
+/* number of PMP entries on a core that hasn't been probed yet */
+const PHYS_PMP_UNPROBED: usize = usize::MAX;
 /* PMP access flags */
 const PHYS_PMP_READ: usize  = 1 << 0;
 const PHYS_PMP_WRITE: usize = 1 << 1;
 const PHYS_PMP_EXEC: usize  = 1 << 2;
 const PHYS_PMP_TOR: usize   = 1 << 3;
+const PHYS_PMP_NA4: usize   = 2 << 3;
+const PHYS_PMP_NAPOT: usize = 3 << 3;
+const PHYS_PMP_LOCKED: usize = 1 << 7;
+
+/* priority given to regions defined by protect() and protect_regions() */
+pub const PMP_PRIORITY_DEFAULT: usize = 0;
+
+lazy_static!
+{
+    /* physical memory regions defined on each CPU core, indexed by linear CPU core ID */
+    static ref PMP_REGIONS: cpu::PerCpu<Mutex<Vec<PMPRegion>>> = cpu::PerCpu::new(|| Mutex::new(Vec::new()));
+
+    /* number of PMP entries implemented by each CPU core, found on first use */
+    static ref PMP_ENTRIES: cpu::PerCpu<AtomicUsize> = cpu::PerCpu::new(|| AtomicUsize::new(PHYS_PMP_UNPROBED));
+}
+
+/* each region gets a unique ID number */
+static NEXT_PMP_REGION_ID: AtomicUsize = AtomicUsize::new(0);
+
+/* describe a physical memory region the running supervisor kernel can access */
+#[derive(Debug, Copy, Clone)]
+struct PMPRegion
+{
+    id: usize,
+    base: PhysMemBase,
+    end: PhysMemEnd,
+    access: AccessPermissions,
+    priority: usize             /* higher priority regions override lower ones they overlap */
+}
+
+impl PMPRegion
+{
+    /* create a description of a region, or None if its addresses aren't 4-byte aligned or it's empty */
+    fn new(base: PhysMemBase, end: PhysMemEnd, access: AccessPermissions, priority: usize) -> Option<PMPRegion>
+    {
+        if end <= base || base & 0b11 != 0 || end & 0b11 != 0
+        {
+            return None;
+        }
+
+        Some(PMPRegion
+        {
+            id: NEXT_PMP_REGION_ID.fetch_add(1, Ordering::SeqCst),
+            base,
+            end,
+            access,
+            priority
+        })
+    }
+
+    /* return the cheapest way to encode this region into PMP entries. NA4 and NAPOT
+    need one entry, whereas TOR needs two unless it can share its base with the entry before */
+    fn encoding(&self) -> PMPEncoding
+    {
+        let size = self.end - self.base;
+        if size == 4
+        {
+            return PMPEncoding::NA4(self.base >> 2);
+        }
+
+        /* naturally aligned power-of-two regions encode their size in the trailing one bits of the address */
+        if size.is_power_of_two() == true && self.base & (size - 1) == 0
+        {
+            return PMPEncoding::NAPOT((self.base >> 2) | ((size >> 3) - 1));
+        }
+
+        PMPEncoding::TOR(self.base >> 2, self.end >> 2)
+    }
+}
+
+/* ways of encoding a region into PMP entries, with the pmpaddr values to use */
+enum PMPEncoding
+{
+    NA4(usize),                 /* naturally aligned four-byte region: one entry */
+    NAPOT(usize),               /* naturally aligned power-of-two region of 8 bytes or more: one entry */
+    TOR(usize, usize)           /* any other region, from base to top: one or two entries */
+}
+
+/* identify a region defined on a CPU core so that it can be removed */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PMPHandle
+{
+    cpu: cpu::CPUcount,
+    id: usize
+}
+
+impl PMPHandle
+{
+    /* return the linear ID of the CPU core the region is defined on */
+    pub fn get_cpu(&self) -> cpu::CPUcount { self.cpu }
+}
 
 /* each CPU has a fix memory overhead, allocated during boot, for its fixed heap,
 exception stack, private variables, etc */
@@ -219,54 +315,273 @@ fn hypervisor_footprint(cpu_count: usize) -> (PhysMemBase, PhysMemEnd)
 }
 
 /* Control currently running supervisor kernel's access to a region of physical memory. Either use PMP or CPU hypervisor extension,
-   depending on whatever is available, to enforce this. So far, just PMP is supported.
+   depending on whatever is available, to enforce this. So far, just PMP is supported. This replaces any regions
+   previously defined on this CPU core. Use protect_regions() to define more than one region
    => base, end = start and end addresses of physical RAM region
       access = access permissions for the region for the currently running supervisor kernel
    <= true for success, or false for failure */
 pub fn protect(base: usize, end: usize, access: AccessPermissions) -> bool
 {
-    return pmp_protect(0, base, end, access);
+    protect_regions(&[(base, end, access)]).is_some()
+}
+
+/* Control currently running supervisor kernel's access to a set of physical memory regions, such as its RAM areas,
+   MMIO passthrough windows, and shared pages. This replaces any regions previously defined on this CPU core.
+   If the regions overlap, those earlier in the list take priority
+   => regions = list of (base address, end address, access permissions) of each region
+   <= handle for each region, in the same order, or None for failure, in which case the previous regions remain */
+pub fn protect_regions(regions: &[(PhysMemBase, PhysMemEnd, AccessPermissions)]) -> Option<Vec<PMPHandle>>
+{
+    let cpu = cpu::get_cpu_id();
+    let mut current = PMP_REGIONS[cpu].lock();
+
+    let mut replacement = Vec::new();
+    for (base, end, access) in regions.iter()
+    {
+        replacement.push(PMPRegion::new(*base, *end, *access, PMP_PRIORITY_DEFAULT)?);
+    }
+
+    if pmp_apply(&replacement) == false
+    {
+        return None;
+    }
+
+    let handles = replacement.iter().map(|r| PMPHandle { cpu, id: r.id }).collect();
+    *current = replacement;
+    Some(handles)
 }
 
-/* define a per-CPU physical memory region and apply access permissions to it. if the region already exists, overwrite it.
-each region is a pair of RISC-V physical memory protection (PMP) area. we pair up PMP addresses in TOR (top of range) mode.
-eg, region 0 uses pmp0cfg and pmp1cfg in pmpcfg0 for start and end, region 1 uses pmp1cfg and pmp2cfg in pmpcfg0.
-   => regionid = ID number of the region to create or update, from 0 to PHYS_PMP_MAX_REGIONS (typically 8).
-                 Remember: one region is a pair of PMP entries
-      base, end = start and end addresses of region
+/* add a physical memory region to those the currently running supervisor kernel can access on this CPU core.
+   a region with a higher priority overrides lower-priority regions it overlaps. regions of the same
+   priority are ordered by when they were added, with the earliest taking priority
+   => base, end = start and end addresses of the region. these must be 4-byte aligned
       access = access permissions for the region
-   <= true for success, or false for failure */
-fn pmp_protect(region_id: usize, base: usize, end: usize, access: AccessPermissions) -> bool
+      priority = region's priority, or PMP_PRIORITY_DEFAULT
+   <= handle to remove the region, or None if there aren't enough free PMP entries */
+pub fn add_region(base: PhysMemBase, end: PhysMemEnd, access: AccessPermissions, priority: usize) -> Option<PMPHandle>
 {
-    /* here are two PMP entries to one diosix region: one for base address, one for the end address */
-    let pmp_entry_base_id = region_id * 2;
-    let pmp_entry_end_id = pmp_entry_base_id + 1;
-    if pmp_entry_end_id > PHYS_PMP_MAX_ENTRY { return false; }
+    let cpu = cpu::get_cpu_id();
+    let mut regions = PMP_REGIONS[cpu].lock();
+    let region = PMPRegion::new(base, end, access, priority)?;
 
-    let accessbits = match access
+    regions.push(region);
+    if pmp_apply(&regions) == false
     {
-        AccessPermissions::Read => PHYS_PMP_READ,
-        AccessPermissions::ReadWrite => PHYS_PMP_READ | PHYS_PMP_WRITE,
-        AccessPermissions::ReadExecute => PHYS_PMP_READ | PHYS_PMP_EXEC,
-        AccessPermissions::ReadWriteExecute => PHYS_PMP_READ | PHYS_PMP_WRITE | PHYS_PMP_EXEC,
-        AccessPermissions::NoAccess => 0
+        regions.pop();
+        return None;
+    }
+
+    Some(PMPHandle { cpu, id: region.id })
+}
+
+/* remove a physical memory region from this CPU core, freeing its PMP entries
+   => handle = region to remove
+   <= true if removed, or false if no such region is defined on this CPU core */
+pub fn remove_region(handle: PMPHandle) -> bool
+{
+    if handle.cpu != cpu::get_cpu_id()
+    {
+        return false;
+    }
+
+    let mut regions = PMP_REGIONS[handle.cpu].lock();
+    match regions.iter().position(|r| r.id == handle.id)
+    {
+        Some(index) =>
+        {
+            regions.remove(index);
+            pmp_apply(&regions) /* this can't run out of entries as it needs fewer than before */
+        },
+        None => false
+    }
+}
+
+/* remove all physical memory regions from this CPU core, denying the supervisor kernel access to memory */
+pub fn clear_regions()
+{
+    let mut regions = PMP_REGIONS.this().lock();
+    regions.clear();
+    pmp_apply(&regions);
+}
+
+/* return the number of PMP entries available to define regions on this CPU core, excluding locked entries */
+pub fn free_pmp_entries() -> usize
+{
+    let regions = PMP_REGIONS.this().lock();
+    match pmp_plan(&regions)
+    {
+        Some((_, used)) => (0..pmp_entries()).filter(|e| pmp_entry_locked(*e) == false).count() - used,
+        None => 0
+    }
+}
+
+/* return the number of PMP entries this CPU core implements. the lowest-numbered entries must be
+   implemented first, and unimplemented entries' pmpaddr registers are read-only zero, so write to each
+   unlocked entry's address register and read it back until one doesn't stick. the result is cached */
+fn pmp_entries() -> usize
+{
+    let probed = PMP_ENTRIES.this();
+    let entries = probed.load(Ordering::SeqCst);
+    if entries != PHYS_PMP_UNPROBED
+    {
+        return entries;
+    }
+
+    let mut entries = 0;
+    while entries <= PHYS_PMP_MAX_ENTRY
+    {
+        /* locked entries can't be written, but they must be implemented to have been locked */
+        if pmp_entry_locked(entries) == false
+        {
+            /* M-mode ignores unlocked entries, so briefly changing the address is harmless */
+            let previous = read_pmp_addr(entries);
+            write_pmp_addr(entries, !0);
+            let implemented = read_pmp_addr(entries) != 0;
+            write_pmp_addr(entries, previous);
+
+            if implemented == false
+            {
+                break;
+            }
+        }
+        entries = entries + 1;
+    }
+
+    probed.store(entries, Ordering::SeqCst);
+    entries
+}
+
+/* work out how to lay out a CPU core's regions in its PMP entries, highest priority
+   in the lowest-numbered entries, skipping entries that are locked or not implemented
+   => regions = regions to lay out
+   <= (pmpaddr, pmpcfg) pair to write to each entry, or None to turn it off, and the
+      number of entries used, or None if the regions don't fit */
+fn pmp_plan(regions: &Vec<PMPRegion>) -> Option<([Option<(usize, usize)>; PHYS_PMP_MAX_ENTRY + 1], usize)>
+{
+    let entries = pmp_entries();
+
+    /* the sort is stable, so regions of the same priority stay in the order they were added */
+    let mut sorted: Vec<&PMPRegion> = regions.iter().collect();
+    sorted.sort_by(|a, b| b.priority.cmp(&a.priority));
+
+    let mut plan = [None; PHYS_PMP_MAX_ENTRY + 1];
+    let mut used = 0;
+    let mut next = 0;
+
+    /* address in the previous entry, if it can be used as the base of a TOR region */
+    let mut previous_top: Option<usize> = None;
+
+    for region in sorted.iter()
+    {
+        let accessbits = pmp_access_bits(region.access);
+
+        /* skip over locked entries, which can't be reused */
+        while next < entries && pmp_entry_locked(next) == true
+        {
+            next = next + 1;
+            previous_top = None;
+        }
+        if next >= entries
+        {
+            return None;
+        }
+
+        match region.encoding()
+        {
+            PMPEncoding::NA4(addr) =>
+            {
+                plan[next] = Some((addr, accessbits | PHYS_PMP_NA4));
+                next = next + 1;
+                used = used + 1;
+                previous_top = None;
+            },
+            PMPEncoding::NAPOT(addr) =>
+            {
+                plan[next] = Some((addr, accessbits | PHYS_PMP_NAPOT));
+                next = next + 1;
+                used = used + 1;
+                previous_top = None;
+            },
+            PMPEncoding::TOR(base, top) =>
+            {
+                /* a TOR entry's base is the previous entry's address, or zero for entry 0. share it if
+                possible, such as when this region follows on from the one before, else use an entry for it */
+                if previous_top != Some(base) && (next != 0 || base != 0)
+                {
+                    while next + 1 < entries && (pmp_entry_locked(next) == true || pmp_entry_locked(next + 1) == true)
+                    {
+                        next = next + 1;
+                    }
+                    if next + 1 >= entries
+                    {
+                        return None;
+                    }
+
+                    plan[next] = Some((base, 0)); /* the base address's entry is off */
+                    next = next + 1;
+                    used = used + 1;
+                }
+
+                plan[next] = Some((top, accessbits | PHYS_PMP_TOR));
+                next = next + 1;
+                used = used + 1;
+                previous_top = Some(top);
+            }
+        }
+    }
+
+    Some((plan, used))
+}
+
+/* program this CPU core's PMP entries with the given regions, turning off unused entries
+   and leaving locked entries alone
+   => regions = regions to program
+   <= true for success, or false if they don't fit, in which case the PMP entries are untouched */
+fn pmp_apply(regions: &Vec<PMPRegion>) -> bool
+{
+    let (plan, _) = match pmp_plan(regions)
+    {
+        Some(p) => p,
+        None => return false
     };
 
-    /* update the appropriate pmpcfg register and bits from the PMP entry ID */
-    /* clear the base address's settings: only the end address is used */
-    write_pmp_entry(pmp_entry_base_id, 0);
-    /* do the end address's settings and make it TOR (top of range) */
-    write_pmp_entry(pmp_entry_end_id, accessbits | PHYS_PMP_TOR);
+    for (entry, setting) in plan.iter().enumerate().take(pmp_entries())
+    {
+        if pmp_entry_locked(entry) == true
+        {
+            continue;
+        }
 
-    /* program in the actual base and end addresses. there are a pair of PMP addresses
-    per region: the base and the end address. they are also shifted down two bits
-    because that's exactly what the spec says. word alignment, right? */
-    write_pmp_addr(pmp_entry_base_id, base >> 2);
-    write_pmp_addr(pmp_entry_end_id, end >> 2);
+        /* turn the entry off while its address is changed. addresses are shifted
+        down two bits because that's exactly what the spec says. word alignment, right? */
+        write_pmp_entry(entry, 0);
+        match setting
+        {
+            Some((addr, cfg)) =>
+            {
+                write_pmp_addr(entry, *addr);
+                write_pmp_entry(entry, *cfg);
+            },
+            None => write_pmp_addr(entry, 0)
+        }
+    }
 
     /* force a reload of MMU data structures */
     tlb_flush();
-    return true;
+    true
+}
+
+/* convert access permissions into PMP configuration bits */
+fn pmp_access_bits(access: AccessPermissions) -> usize
+{
+    match access
+    {
+        AccessPermissions::Read => PHYS_PMP_READ,
+        AccessPermissions::ReadWrite => PHYS_PMP_READ | PHYS_PMP_WRITE,
+        AccessPermissions::ReadExecute => PHYS_PMP_READ | PHYS_PMP_EXEC,
+        AccessPermissions::ReadWriteExecute => PHYS_PMP_READ | PHYS_PMP_WRITE | PHYS_PMP_EXEC,
+        AccessPermissions::NoAccess => 0
+    }
 }
 
 /* write_pmp_entry
@@ -288,9 +603,10 @@ fn write_pmp_entry(entry_id: usize, value: usize)
 
         64 =>
         {
-            /* eight PMP entries to a 64-bit pmpcfg register */
-            let pmp_cfg_id = entry_id >> 3;
-            let offset = entry_id - (pmp_cfg_id << 3);
+            /* eight PMP entries to a 64-bit pmpcfg register. only the even-numbered
+            pmpcfg registers exist on RV64, so entries 8-15 are in pmpcfg2 */
+            let offset = entry_id & 0b111;
+            let pmp_cfg_id = (entry_id >> 3) << 1;
             (pmp_cfg_id, offset)
         },
 
@@ -305,6 +621,13 @@ fn write_pmp_entry(entry_id: usize, value: usize)
     write_pmpcfg(pmp_cfg_id, cfgbits | ((value & 0xff) << (offset << 3)));
 }
 
+/* return true if the given PMP entry (0 to 15) is locked. RV64 only */
+fn pmp_entry_locked(entry_id: usize) -> bool
+{
+    let cfgbits = read_pmpcfg((entry_id >> 3) << 1) >> ((entry_id & 0b111) << 3);
+    cfgbits & PHYS_PMP_LOCKED != 0
+}
+
 /* read_pmpcfg
    Read the 64-bit value of the given PMP configuration register (pmpcfg0 or 2)
    => register = selects N out of pmpcfgN, where N = 0 or 2
@@ -335,6 +658,31 @@ fn write_pmpcfg(register: usize, value: usize)
     };
 }
 
+/* read the given PMP address register 0-15 (pmpaddr0-15), or 0 for can't read */
+fn read_pmp_addr(register: usize) -> usize
+{
+    match register
+    {
+        0 => read_csr!(pmpaddr0),
+        1 => read_csr!(pmpaddr1),
+        2 => read_csr!(pmpaddr2),
+        3 => read_csr!(pmpaddr3),
+        4 => read_csr!(pmpaddr4),
+        5 => read_csr!(pmpaddr5),
+        6 => read_csr!(pmpaddr6),
+        7 => read_csr!(pmpaddr7),
+        8 => read_csr!(pmpaddr8),
+        9 => read_csr!(pmpaddr9),
+        10 => read_csr!(pmpaddr10),
+        11 => read_csr!(pmpaddr11),
+        12 => read_csr!(pmpaddr12),
+        13 => read_csr!(pmpaddr13),
+        14 => read_csr!(pmpaddr14),
+        15 => read_csr!(pmpaddr15),
+        _ => 0
+    }
+}
+
 /* write value to the given PMP address register 0-15 (pmpaddr0-15). warning: silently fails */
 fn write_pmp_addr(register: usize, value: usize)
 {
@@ -0,0 +1,310 @@
+/* diosix RV64 platform-level interrupt controller (PLIC) driver
+ *
+ * Derived from the RISC-V PLIC specification:
+ * https://github.com/riscv/riscv-plic-spec/blob/master/riscv-plic.adoc
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::ptr::{read_volatile, write_volatile};
+use spin::Mutex;
+use alloc::vec::Vec;
+use super::physmem;
+use super::cpu::PrivilegeMode;
+
+lazy_static!
+{
+    /* acquire PINNED_PLIC lock before accessing the system's interrupt controller */
+    static ref PINNED_PLIC: Mutex<Option<Plic>> = Mutex::new(None);
+}
+
+/* PLIC memory-mapped registers, relative to the controller's base address */
+const PLIC_PRIORITY_BASE:       usize = 0x0;        /* one 32-bit priority word per source */
+const PLIC_PENDING_BASE:        usize = 0x1000;     /* one pending bit per source */
+const PLIC_ENABLE_BASE:         usize = 0x2000;     /* one enable bit per source, per context... */
+const PLIC_ENABLE_STRIDE:       usize = 0x80;       /* ...with each context's bits this far apart */
+const PLIC_CONTEXT_BASE:        usize = 0x200000;   /* per-context threshold and claim registers... */
+const PLIC_CONTEXT_STRIDE:      usize = 0x1000;     /* ...with each context's registers this far apart */
+const PLIC_CONTEXT_THRESHOLD:   usize = 0x0;        /* priority threshold, relative to a context */
+const PLIC_CONTEXT_CLAIM:       usize = 0x4;        /* claim/complete, relative to a context */
+
+/* the PLIC supports up to 1024 sources, though source 0 is reserved to mean 'no interrupt' */
+pub const PLIC_MAX_SOURCES: usize = 1024;
+
+/* machine-level external interrupt enable bit in mie */
+const MIE_MEIE: usize = 1 << 11;
+
+/* interrupt numbers used by a CPU core's local interrupt controller to describe PLIC contexts */
+pub const INTC_SUPERVISOR_EXTERNAL: u32 = 9;
+pub const INTC_MACHINE_EXTERNAL:    u32 = 11;
+
+/* the PLIC's interrupt target contexts are sets of interrupt enable bits, a priority
+threshold, and a claim/complete register, each wired to a particular privilege mode
+of a particular CPU core. these are enumerated by the device tree */
+#[derive(Debug, Clone, Copy)]
+pub struct PlicContext
+{
+    pub hart: usize,            /* mhartid of the CPU core this context interrupts */
+    pub mode: PrivilegeMode,    /* privilege mode of the CPU core this context interrupts */
+    pub index: usize            /* this context's number in the PLIC register space */
+}
+
+/* describe a platform-level interrupt controller */
+#[derive(Debug, Clone)]
+pub struct Plic
+{
+    base: physmem::PhysMemBase,     /* base MMIO address of the controller */
+    size: physmem::PhysMemSize,     /* size of the controller's MMIO area in bytes */
+    nr_sources: usize,              /* number of interrupt sources, not including source 0 */
+    contexts: Vec<PlicContext>      /* list of interrupt target contexts */
+}
+
+impl Plic
+{
+    /* create a new PLIC object
+       => base = base MMIO address of the controller
+          size = size of the controller's MMIO area in bytes
+          nr_sources = number of interrupt sources wired to the controller
+          contexts = list of interrupt target contexts
+       <= PLIC object */
+    pub fn new(base: physmem::PhysMemBase, size: physmem::PhysMemSize, nr_sources: usize, contexts: Vec<PlicContext>) -> Plic
+    {
+        Plic
+        {
+            base,
+            size,
+            nr_sources: if nr_sources >= PLIC_MAX_SOURCES { PLIC_MAX_SOURCES - 1 } else { nr_sources },
+            contexts
+        }
+    }
+
+    /* register this PLIC as the pinned controller, allowing other platform code to find it */
+    pub fn pin(&self)
+    {
+        let mut pinned = PINNED_PLIC.lock();
+        *pinned = Some(self.clone());
+    }
+
+    /* return information about this controller */
+    pub fn get_mmio_base(&self) -> physmem::PhysMemBase { self.base }
+    pub fn get_mmio_size(&self) -> physmem::PhysMemSize { self.size }
+    pub fn get_nr_sources(&self) -> usize { self.nr_sources }
+    pub fn get_contexts(&self) -> &Vec<PlicContext> { &self.contexts }
+
+    /* find the context that interrupts the given CPU core in the given privilege mode
+       => hart = mhartid of the CPU core
+          mode = privilege mode of the core that the context interrupts
+       <= context number, or None if no such context */
+    pub fn find_context(&self, hart: usize, mode: PrivilegeMode) -> Option<usize>
+    {
+        for context in &self.contexts
+        {
+            if context.hart == hart
+            {
+                match (context.mode, mode)
+                {
+                    (PrivilegeMode::Machine, PrivilegeMode::Machine) |
+                    (PrivilegeMode::Supervisor, PrivilegeMode::Supervisor) => return Some(context.index),
+                    (_, _) => ()
+                }
+            }
+        }
+
+        None
+    }
+
+    /* return the context that interrupts the running CPU core in the given privilege mode, if any */
+    pub fn this_context(&self, mode: PrivilegeMode) -> Option<usize>
+    {
+        self.find_context(read_csr!(mhartid), mode)
+    }
+
+    /* set the priority of an interrupt source. a priority of 0 means never interrupt
+       => source = interrupt source number, from 1 to the number of sources
+          priority = priority level for the source
+       <= true for success, or false for a bad source number */
+    pub fn set_priority(&self, source: usize, priority: u32) -> bool
+    {
+        if self.valid_source(source) == false { return false; }
+        self.write_reg(PLIC_PRIORITY_BASE + (source * 4), priority);
+        true
+    }
+
+    /* return the priority of the given interrupt source, or None for bad source number */
+    pub fn get_priority(&self, source: usize) -> Option<u32>
+    {
+        if self.valid_source(source) == false { return None; }
+        Some(self.read_reg(PLIC_PRIORITY_BASE + (source * 4)))
+    }
+
+    /* set the priority threshold of a context. interrupts at or below this level are masked
+       => context = context number to update
+          threshold = priority level threshold */
+    pub fn set_threshold(&self, context: usize, threshold: u32)
+    {
+        self.write_reg(context_reg(context, PLIC_CONTEXT_THRESHOLD), threshold);
+    }
+
+    /* return a context's priority threshold */
+    pub fn get_threshold(&self, context: usize) -> u32
+    {
+        self.read_reg(context_reg(context, PLIC_CONTEXT_THRESHOLD))
+    }
+
+    /* allow the given interrupt source to interrupt a context
+       => context = context to interrupt
+          source = interrupt source number
+       <= true for success, or false for bad source number */
+    pub fn enable(&self, context: usize, source: usize) -> bool
+    {
+        self.update_enable(context, source, true)
+    }
+
+    /* prevent the given interrupt source from interrupting a context
+       => context = context to no longer interrupt
+          source = interrupt source number
+       <= true for success, or false for bad source number */
+    pub fn disable(&self, context: usize, source: usize) -> bool
+    {
+        self.update_enable(context, source, false)
+    }
+
+    /* return true if the given source is enabled for the given context */
+    pub fn is_enabled(&self, context: usize, source: usize) -> bool
+    {
+        if self.valid_source(source) == false { return false; }
+        let (reg, bit) = enable_reg(context, source);
+        self.read_reg(reg) & (1 << bit) != 0
+    }
+
+    /* return true if the given interrupt source is pending */
+    pub fn is_pending(&self, source: usize) -> bool
+    {
+        if self.valid_source(source) == false { return false; }
+        let reg = PLIC_PENDING_BASE + ((source / 32) * 4);
+        self.read_reg(reg) & (1 << (source % 32)) != 0
+    }
+
+    /* claim the highest-priority pending interrupt for the given context.
+       the source will not be raised again until complete() is called
+       => context = context claiming the interrupt
+       <= source number of the claimed interrupt, or None if nothing is pending */
+    pub fn claim(&self, context: usize) -> Option<usize>
+    {
+        match self.read_reg(context_reg(context, PLIC_CONTEXT_CLAIM)) as usize
+        {
+            0 => None, /* source 0 means nothing to claim */
+            source => Some(source)
+        }
+    }
+
+    /* signal that a claimed interrupt has been handled
+       => context = context that claimed the interrupt
+          source = interrupt source number returned by claim() */
+    pub fn complete(&self, context: usize, source: usize)
+    {
+        self.write_reg(context_reg(context, PLIC_CONTEXT_CLAIM), source as u32);
+    }
+
+    /* prepare the running CPU core to receive machine-level external interrupts.
+       all sources are masked for this core's context until they are enabled
+       <= true for success, or false if this core has no machine-level context */
+    pub fn init_hart(&self) -> bool
+    {
+        let context = match self.this_context(PrivilegeMode::Machine)
+        {
+            Some(c) => c,
+            None => return false
+        };
+
+        for source in 1..(self.nr_sources + 1)
+        {
+            self.disable(context, source);
+        }
+
+        /* let through interrupts of any non-zero priority */
+        self.set_threshold(context, 0);
+        set_csr!(mie, MIE_MEIE);
+        true
+    }
+
+    /* check an interrupt source is within range */
+    fn valid_source(&self, source: usize) -> bool
+    {
+        source > 0 && source <= self.nr_sources
+    }
+
+    /* set or clear a source's enable bit in a context */
+    fn update_enable(&self, context: usize, source: usize, enabled: bool) -> bool
+    {
+        if self.valid_source(source) == false { return false; }
+        let (reg, bit) = enable_reg(context, source);
+        let bits = self.read_reg(reg);
+        self.write_reg(reg, match enabled
+        {
+            true => bits | (1 << bit),
+            false => bits & !(1 << bit)
+        });
+        true
+    }
+
+    /* access a 32-bit PLIC register at the given offset from the base address */
+    fn read_reg(&self, offset: usize) -> u32
+    {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write_reg(&self, offset: usize, value: u32)
+    {
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+    }
+}
+
+/* return the offset of a per-context register */
+fn context_reg(context: usize, reg: usize) -> usize
+{
+    PLIC_CONTEXT_BASE + (context * PLIC_CONTEXT_STRIDE) + reg
+}
+
+/* return the (offset of the 32-bit enable register, bit within it) for a context's source */
+fn enable_reg(context: usize, source: usize) -> (usize, usize)
+{
+    (PLIC_ENABLE_BASE + (context * PLIC_ENABLE_STRIDE) + ((source / 32) * 4), source % 32)
+}
+
+/* claim the highest-priority pending interrupt for the running CPU core's machine-level
+   context using the pinned PLIC, or None if nothing's pending or there's no PLIC */
+pub fn claim() -> Option<usize>
+{
+    let pinned = PINNED_PLIC.lock();
+    match &*pinned
+    {
+        Some(plic) => match plic.this_context(PrivilegeMode::Machine)
+        {
+            Some(context) => plic.claim(context),
+            None => None
+        },
+        None => None
+    }
+}
+
+/* complete a claimed interrupt for the running CPU core's machine-level context using the pinned PLIC */
+pub fn complete(source: usize)
+{
+    let pinned = PINNED_PLIC.lock();
+    if let Some(plic) = &*pinned
+    {
+        if let Some(context) = plic.this_context(PrivilegeMode::Machine)
+        {
+            plic.complete(context, source);
+        }
+    }
+}
+
+/* return true if a PLIC has been pinned for platform code to use */
+pub fn is_pinned() -> bool
+{
+    PINNED_PLIC.lock().is_some()
+}
@@ -0,0 +1,349 @@
+/* diosix RV64 wall-clock real-time clock (RTC) support
+ *
+ * Read the time of day from a Goldfish RTC, as found on Qemu's
+ * virt machine, and convert it into a calendar date and time.
+ * Guests can be given a virtual Goldfish RTC of their own. Each
+ * virtual CPU core's RTC state is loaded with the rest of its state,
+ * and the guest's accesses to the RTC's MMIO window, which fault as
+ * nothing is mapped there, are emulated by the built-in fault handler
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::fmt;
+use core::ptr::read_volatile;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use super::physmem;
+use super::cpu::{self, PrivilegeMode};
+use super::irq::{IRQ, IRQContext, MemoryAccess};
+use super::instructions;
+
+lazy_static!
+{
+    /* acquire PINNED_RTC lock before accessing the system's RTC */
+    static ref PINNED_RTC: Mutex<Option<Rtc>> = Mutex::new(None);
+
+    /* virtual RTCs of the supervisors running on each physical CPU core, indexed by linear CPU core ID */
+    static ref RUNNING: cpu::PerCpu<Mutex<VirtualRtc>> = cpu::PerCpu::new(|| Mutex::new(VirtualRtc::new()));
+}
+
+/* Goldfish RTC registers, relative to its base address. reading TIME_LOW
+latches the upper 32 bits of the time into TIME_HIGH for the next read */
+const GOLDFISH_TIME_LOW:        usize = 0x00;
+const GOLDFISH_TIME_HIGH:       usize = 0x04;
+const GOLDFISH_ALARM_LOW:       usize = 0x08;
+const GOLDFISH_ALARM_HIGH:      usize = 0x0c;
+const GOLDFISH_IRQ_ENABLED:     usize = 0x10;
+const GOLDFISH_CLEAR_ALARM:     usize = 0x14;
+const GOLDFISH_ALARM_STATUS:    usize = 0x18;
+const GOLDFISH_CLEAR_INTERRUPT: usize = 0x1c;
+
+/* where a virtual RTC appears in a guest's physical memory map, and its interrupt line
+on the guest's virtual PLIC. these match Qemu's virt machine */
+pub const VIRTUAL_RTC_BASE: usize = 0x101000;
+pub const VIRTUAL_RTC_SIZE: usize = 0x1000;
+pub const VIRTUAL_RTC_IRQ:  usize = 11;
+
+/* set once guests run under two-stage address translation, so that their accesses to a
+virtual RTC raise guest page faults that can be emulated. guests running directly in
+supervisor mode would instead take access faults, which are delegated to them */
+static GUEST_TRANSLATION: AtomicBool = AtomicBool::new(false);
+
+const NANOSECONDS_PER_SECOND: u64 = 1000 * 1000 * 1000;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/* describe a Goldfish RTC */
+#[derive(Debug, Clone, Copy)]
+pub struct Rtc
+{
+    base: physmem::PhysMemBase,
+    size: physmem::PhysMemSize
+}
+
+impl Rtc
+{
+    /* create a new RTC object
+       => base, size = base address and size of the RTC's MMIO area
+       <= RTC object */
+    pub fn new(base: physmem::PhysMemBase, size: physmem::PhysMemSize) -> Rtc
+    {
+        Rtc { base, size }
+    }
+
+    /* register this RTC as the pinned RTC, allowing other platform code to find it */
+    pub fn pin(&self)
+    {
+        *(PINNED_RTC.lock()) = Some(self.clone());
+    }
+
+    /* return base MMIO address and size of the RTC */
+    pub fn get_mmio_base(&self) -> physmem::PhysMemBase { self.base }
+    pub fn get_mmio_size(&self) -> physmem::PhysMemSize { self.size }
+
+    /* return the UTC time in nanoseconds since 00:00:00 on 1 January 1970 */
+    pub fn now(&self) -> u64
+    {
+        /* the low word must be read first to latch the high word */
+        let low = unsafe { read_volatile((self.base + GOLDFISH_TIME_LOW) as *const u32) } as u64;
+        let high = unsafe { read_volatile((self.base + GOLDFISH_TIME_HIGH) as *const u32) } as u64;
+        (high << 32) | low
+    }
+
+    /* return the UTC date and time */
+    pub fn get_date_time(&self) -> DateTime
+    {
+        DateTime::from_unix_nanoseconds(self.now())
+    }
+}
+
+/* return the pinned RTC's UTC time in nanoseconds since the epoch, or None for no pinned RTC */
+pub fn get_pinned_rtc_now() -> Option<u64>
+{
+    match *(PINNED_RTC.lock())
+    {
+        Some(rtc) => Some(rtc.now()),
+        None => None
+    }
+}
+
+/* return the pinned RTC's UTC date and time, or None for no pinned RTC */
+pub fn get_pinned_date_time() -> Option<DateTime>
+{
+    get_pinned_rtc_now().map(|ns| DateTime::from_unix_nanoseconds(ns))
+}
+
+/* a UTC calendar date and time, in the proleptic Gregorian calendar */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateTime
+{
+    pub year: u64,
+    pub month: u8,          /* 1 = January to 12 = December */
+    pub day: u8,            /* day of the month, from 1 */
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+    pub weekday: u8         /* 0 = Sunday to 6 = Saturday */
+}
+
+impl DateTime
+{
+    /* convert a number of nanoseconds since 00:00:00 UTC on 1 January 1970 into a
+       calendar date and time. leap seconds aren't counted, as with Unix time */
+    pub fn from_unix_nanoseconds(nanoseconds: u64) -> DateTime
+    {
+        let seconds = nanoseconds / NANOSECONDS_PER_SECOND;
+        let days = seconds / SECONDS_PER_DAY;
+        let time = seconds % SECONDS_PER_DAY;
+
+        /* count days from 1 March 0000 so that leap days fall at the end of each year.
+        there are 719468 days from then to 1 January 1970, and 146097 days in every
+        400-year era. see Howard Hinnant's civil_from_days() algorithm */
+        let days = days + 719468;
+        let era = days / 146097;
+        let day_of_era = days % 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_from_march = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+        let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime
+        {
+            year,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: ((time / 60) % 60) as u8,
+            second: (time % 60) as u8,
+            nanosecond: (nanoseconds % NANOSECONDS_PER_SECOND) as u32,
+            weekday: ((seconds / SECONDS_PER_DAY + 4) % 7) as u8 /* 1 January 1970 was a Thursday */
+        }
+    }
+}
+
+/* produce an ISO 8601 timestamp, eg: 2021-03-14T15:09:26.535897932Z */
+impl fmt::Display for DateTime
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.nanosecond)
+    }
+}
+
+/* describe a guest's virtual Goldfish RTC. it reads the host's RTC plus an offset, which is
+changed when the guest sets the time. the alarm isn't emulated */
+#[derive(Debug, Clone, Copy)]
+pub struct VirtualRtc
+{
+    offset: i64,        /* nanoseconds to add to the host's time */
+    latched_high: u32,  /* upper word of the time latched by the last read of TIME_LOW */
+    pending_high: u32   /* upper word written to TIME_HIGH, applied when TIME_LOW is written */
+}
+
+impl VirtualRtc
+{
+    /* create a virtual RTC that reads the same as the host's */
+    pub fn new() -> VirtualRtc
+    {
+        VirtualRtc
+        {
+            offset: 0,
+            latched_high: 0,
+            pending_high: 0
+        }
+    }
+
+    /* return the guest's UTC time in nanoseconds since the epoch, or None for no host RTC */
+    pub fn now(&self) -> Option<u64>
+    {
+        let host = get_pinned_rtc_now()? as i64;
+        match host.saturating_add(self.offset)
+        {
+            t if t < 0 => Some(0), /* don't go back before the epoch */
+            t => Some(t as u64)
+        }
+    }
+
+    /* emulate a guest's 32-bit read from the virtual RTC's registers
+       => offset = byte offset of the register from the virtual RTC's base address
+       <= value read, or None for a bad offset or no host RTC */
+    pub fn read(&mut self, offset: usize) -> Option<u32>
+    {
+        match offset
+        {
+            GOLDFISH_TIME_LOW =>
+            {
+                let now = self.now()?;
+                self.latched_high = (now >> 32) as u32;
+                Some(now as u32)
+            },
+            GOLDFISH_TIME_HIGH => Some(self.latched_high),
+            GOLDFISH_ALARM_LOW | GOLDFISH_ALARM_HIGH | GOLDFISH_IRQ_ENABLED | GOLDFISH_ALARM_STATUS => Some(0),
+            _ => None
+        }
+    }
+
+    /* emulate a guest's 32-bit write to the virtual RTC's registers. the guest sets
+       the time by writing TIME_HIGH and then TIME_LOW
+       => offset = byte offset of the register from the virtual RTC's base address
+          value = value to write
+       <= Ok(()) for success, or Err(()) for a bad offset or no host RTC */
+    pub fn write(&mut self, offset: usize, value: u32) -> Result<(), ()>
+    {
+        match offset
+        {
+            GOLDFISH_TIME_HIGH =>
+            {
+                self.pending_high = value;
+                Ok(())
+            },
+            GOLDFISH_TIME_LOW =>
+            {
+                let host = get_pinned_rtc_now().ok_or(())? as i64;
+                let wanted = (((self.pending_high as u64) << 32) | value as u64) as i64;
+                self.offset = wanted.wrapping_sub(host);
+                Ok(())
+            },
+            GOLDFISH_ALARM_LOW | GOLDFISH_ALARM_HIGH | GOLDFISH_IRQ_ENABLED |
+            GOLDFISH_CLEAR_ALARM | GOLDFISH_CLEAR_INTERRUPT => Ok(()), /* alarms aren't supported */
+            _ => Err(())
+        }
+    }
+}
+
+/* tell the platform that guests run in virtualized supervisor mode under two-stage address
+   translation, with the guest physical page holding the virtual RTC left unmapped. only then
+   are guests given virtual RTCs in their device trees. this needs the H extension
+   <= true for success, or false if there's no H extension */
+pub fn enable_guest_rtcs() -> bool
+{
+    if cpu::hypervisor_extension_present() == false
+    {
+        return false;
+    }
+
+    GUEST_TRANSLATION.store(true, Ordering::SeqCst);
+    true
+}
+
+/* return true if guests can be given virtual RTCs */
+pub fn guest_rtcs_enabled() -> bool
+{
+    GUEST_TRANSLATION.load(Ordering::SeqCst)
+}
+
+/* make the given virtual RTC the one used by the supervisor running on this CPU core.
+   this is called when the supervisor's state is loaded */
+pub fn load(rtc: &VirtualRtc)
+{
+    *(RUNNING.this().lock()) = *rtc;
+}
+
+/* return a copy of the running supervisor's virtual RTC on this CPU core.
+   this is called when the supervisor's state is saved, to keep any change to its time */
+pub fn running() -> VirtualRtc
+{
+    *(RUNNING.this().lock())
+}
+
+/* built-in handler for guest page faults. emulate a running supervisor's 32-bit load from or
+   store to its virtual RTC, and step over the faulting instruction. the guest physical address
+   is only known for guests under two-stage translation, which is why the virtual RTC is only
+   offered to them. see enable_guest_rtcs()
+   <= true if the fault was an access to the virtual RTC that was emulated */
+pub fn emulate(irq: &IRQ, context: &mut IRQContext) -> bool
+{
+    if let PrivilegeMode::Machine = irq.privilege_mode
+    {
+        return false;
+    }
+
+    let (fault, guest_addr, instruction) = match (irq.fault, irq.guest_addr, irq.instruction)
+    {
+        (Some(f), Some(a), Some(i)) => (f, (a << 2) | (f.address & 0b11), i),
+        (_, _, _) => return false
+    };
+
+    if fault.width != Some(4) || guest_addr < VIRTUAL_RTC_BASE || guest_addr >= VIRTUAL_RTC_BASE + VIRTUAL_RTC_SIZE
+    {
+        return false;
+    }
+
+    let reg = match instructions::access_register(instruction)
+    {
+        Some(r) => r,
+        None => return false
+    };
+
+    let offset = guest_addr - VIRTUAL_RTC_BASE;
+    let mut rtc = RUNNING.this().lock();
+    match fault.access
+    {
+        MemoryAccess::Load => match rtc.read(offset)
+        {
+            Some(value) => if reg != 0
+            {
+                context.registers[reg] = match instructions::load_is_signed(instruction)
+                {
+                    true => value as i32 as isize as usize,
+                    false => value as usize
+                };
+            },
+            None => return false
+        },
+        MemoryAccess::Store => if rtc.write(offset, context.registers[reg] as u32).is_err()
+        {
+            return false;
+        },
+        MemoryAccess::Fetch => return false
+    }
+
+    instructions::step_over(instruction, irq.trap_inst);
+    true
+}
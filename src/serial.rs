@@ -96,24 +96,26 @@ impl SerialPort
     {
         for byte in msg.bytes()
         {
-            match &self.chip
+            if self.send_byte(byte) == false
             {
-                Controllers::NS16550a(c) => match c.send_byte(byte)
-                {
-                    Ok(_) => (),
-                    Err(_) => return false
-                },
-                Controllers::SiFive(c) => match c.send_byte(byte)
-                {
-                    Ok(_) => (),
-                    Err(_) => return false
-                }
+                return false;
             }
         }
 
         true
     }
 
+    /* write a byte to the serial port, waiting until the controller can accept it
+       <= true if successful, false if not */
+    pub fn send_byte(&self, byte: u8) -> bool
+    {
+        match &self.chip
+        {
+            Controllers::NS16550a(c) => c.send_byte(byte).is_ok(),
+            Controllers::SiFive(c) => c.send_byte(byte).is_ok()
+        }
+    }
+
     /* read in a byte from the serial port */
     pub fn read(&self) -> Option<u8>
     {
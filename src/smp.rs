@@ -0,0 +1,364 @@
+/* diosix RV64 cross-CPU core function calls and rendezvous
+ *
+ * Ask other physical CPU cores to run an action, such as a fence
+ * or a closure, and wait for them to finish. Each core has a queue
+ * of requests that it drains when it receives an inter-processor
+ * interrupt (IPI) from irq::dispatch()
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::sync::atomic::{AtomicUsize, AtomicU64, Ordering};
+use alloc::sync::Arc;
+use alloc::collections::vec_deque::VecDeque;
+use spin::Mutex;
+use super::cpu;
+use super::ipi;
+use super::timer;
+use super::physmem;
+
+lazy_static!
+{
+    /* per-CPU core queues of requests, indexed by linear CPU core ID */
+    static ref QUEUES: cpu::PerCpu<Mutex<VecDeque<Request>>> = cpu::PerCpu::new(|| Mutex::new(VecDeque::new()));
+
+    /* acquire RENDEZVOUS lock to start a stop-the-world rendezvous */
+    static ref RENDEZVOUS: Mutex<()> = Mutex::new(());
+}
+
+/* each rendezvous is numbered. the upper 32 bits hold the current rendezvous's number,
+and the lower 32 bits count the cores that have arrived at it. keeping both in one word
+stops a core arriving late for an abandoned rendezvous from being counted in the next */
+static RENDEZVOUS_STATE: AtomicU64 = AtomicU64::new(0);
+/* number of the latest rendezvous to release its cores */
+static RENDEZVOUS_RELEASED: AtomicU64 = AtomicU64::new(0);
+
+/* actions a CPU core can be asked to carry out on behalf of another */
+#[derive(Clone)]
+pub enum SMPAction
+{
+    FenceI,                                 /* synchronize the core's instruction and data streams */
+    SFenceVMA,                              /* flush the core's TLB, needed after PMP and SATP changes */
+    Stop,                                   /* park the core permanently, eg: for shutdown */
+    Call(Arc<dyn Fn() + Send + Sync>)       /* call this function on the core */
+}
+
+/* work queued for a CPU core */
+enum Work
+{
+    Action(SMPAction),  /* carry out an action */
+    Rendezvous(u64)     /* wait in the given numbered rendezvous */
+}
+
+/* a request made of a CPU core, and the counter to decrement once it's done */
+struct Request
+{
+    work: Work,
+    outstanding: Arc<AtomicUsize>
+}
+
+/* track the progress of a call made to one or more CPU cores */
+pub struct Completion
+{
+    outstanding: Arc<AtomicUsize>
+}
+
+impl Completion
+{
+    /* return the number of CPU cores yet to complete the call */
+    pub fn outstanding(&self) -> usize
+    {
+        self.outstanding.load(Ordering::SeqCst)
+    }
+
+    /* return true if all CPU cores have completed the call */
+    pub fn is_done(&self) -> bool
+    {
+        self.outstanding() == 0
+    }
+
+    /* wait for all CPU cores to complete the call. this core will
+       continue to service its own queue of requests while it waits
+       => timeout = give up waiting after this duration, or None to wait forever
+       <= true if all cores completed the call, or false if timed out */
+    pub fn wait(&self, timeout: Option<timer::TimerValue>) -> bool
+    {
+        let deadline = make_deadline(timeout);
+        loop
+        {
+            if self.is_done() == true
+            {
+                return true;
+            }
+
+            process();
+            if deadline_passed(deadline) == true
+            {
+                return self.is_done();
+            }
+        }
+    }
+}
+
+/* ask a CPU core to carry out an action. if it's this core, the action is carried out immediately
+   => cpu = linear CPU core ID of the core to ask
+      action = action to carry out
+   <= Completion to track the request, or None if no such core */
+pub fn call_on(cpu: cpu::CPUcount, action: SMPAction) -> Option<Completion>
+{
+    if cpu::cpu_id_to_hart_id(cpu).is_none()
+    {
+        return None;
+    }
+
+    Some(call_on_mask(1 << cpu, action))
+}
+
+/* ask a set of CPU cores to carry out an action. if this core is in
+   the set, it carries out the action before returning
+   => cpus = bitmask of linear CPU core IDs: bit n set to ask core n
+      action = action to carry out
+   <= Completion to track the request */
+pub fn call_on_mask(cpus: u64, action: SMPAction) -> Completion
+{
+    let outstanding = Arc::new(AtomicUsize::new(0));
+    let myself = cpu::get_cpu_id();
+
+    for cpu in 0..cpu::nr_booted_cpus()
+    {
+        if cpus & (1 << cpu) != 0 && cpu != myself
+        {
+            queue(cpu, Work::Action(action.clone()), &outstanding);
+        }
+    }
+
+    /* do our part while the other cores get going */
+    if cpus & (1 << myself) != 0
+    {
+        run(&action);
+    }
+
+    Completion { outstanding }
+}
+
+/* ask all booted CPU cores, optionally including this one, to carry out an action
+   => action = action to carry out
+      include_self = true to also carry out the action on this core
+   <= Completion to track the request */
+pub fn call_on_all(action: SMPAction, include_self: bool) -> Completion
+{
+    let mut cpus = all_cpus_mask();
+    if include_self == false
+    {
+        cpus = cpus & !(1 << cpu::get_cpu_id());
+    }
+
+    call_on_mask(cpus, action)
+}
+
+/* stop all other CPU cores, run a function on this core while they wait
+   in a rendezvous, and then release them
+   => timeout = give up if the other cores haven't all arrived within this duration,
+                or None to wait forever
+      f = function to run while the other cores are stopped
+   <= true if f was run with all other cores stopped, or false if timed out */
+pub fn stop_the_world<F>(timeout: Option<timer::TimerValue>, f: F) -> bool where F: FnOnce()
+{
+    /* keep servicing requests while waiting for another rendezvous to finish */
+    let _lock = loop
+    {
+        if let Some(lock) = RENDEZVOUS.try_lock()
+        {
+            break lock;
+        }
+        process();
+    };
+    let myself = cpu::get_cpu_id();
+    let outstanding = Arc::new(AtomicUsize::new(0));
+
+    /* start a new rendezvous with no arrivals */
+    let number = (RENDEZVOUS_STATE.load(Ordering::SeqCst) >> 32) + 1;
+    RENDEZVOUS_STATE.store(number << 32, Ordering::SeqCst);
+
+    /* only wait for the cores that could be asked to join */
+    let mut expected: u64 = 0;
+    for cpu in 0..cpu::nr_booted_cpus()
+    {
+        if cpu != myself && queue(cpu, Work::Rendezvous(number), &outstanding) == true
+        {
+            expected = expected + 1;
+        }
+    }
+
+    /* wait for everyone to check in */
+    let deadline = make_deadline(timeout);
+    let mut arrived = true;
+    while RENDEZVOUS_STATE.load(Ordering::SeqCst) & 0xffffffff < expected
+    {
+        if deadline_passed(deadline) == true
+        {
+            arrived = false;
+            break;
+        }
+    }
+
+    if arrived == true
+    {
+        physmem::barrier();
+        f();
+        physmem::barrier();
+    }
+    else
+    {
+        /* withdraw the requests of cores that never arrived so they don't join a later rendezvous */
+        for cpu in 0..cpu::nr_booted_cpus()
+        {
+            QUEUES[cpu].lock().retain(|request| match request.work
+            {
+                Work::Rendezvous(n) if n == number =>
+                {
+                    request.outstanding.fetch_sub(1, Ordering::SeqCst);
+                    false
+                },
+                _ => true
+            });
+        }
+    }
+
+    /* let the other cores go, and wait for them to leave */
+    RENDEZVOUS_RELEASED.store(number, Ordering::SeqCst);
+    if arrived == true
+    {
+        while outstanding.load(Ordering::SeqCst) > 0 {}
+    }
+
+    arrived
+}
+
+/* carry out all requests queued for this CPU core. this is called
+   when a machine software interrupt arrives, and while waiting for calls to complete */
+pub fn process()
+{
+    let myself = cpu::get_cpu_id();
+    loop
+    {
+        /* don't hold the lock while running the request */
+        let request = match QUEUES[myself].lock().pop_front()
+        {
+            Some(r) => r,
+            None => return
+        };
+
+        match request.work
+        {
+            Work::Action(SMPAction::Stop) =>
+            {
+                request.outstanding.fetch_sub(1, Ordering::SeqCst);
+                park();
+            },
+            Work::Action(action) => run(&action),
+            Work::Rendezvous(number) =>
+            {
+                /* only wait if the rendezvous hasn't been abandoned or replaced by another */
+                if arrive(number) == true
+                {
+                    while RENDEZVOUS_RELEASED.load(Ordering::SeqCst) < number {}
+                }
+            }
+        }
+
+        request.outstanding.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/* check in to the given rendezvous
+   => number = rendezvous to join
+   <= true if checked in, or false if it's no longer the current rendezvous */
+fn arrive(number: u64) -> bool
+{
+    let mut state = RENDEZVOUS_STATE.load(Ordering::SeqCst);
+    loop
+    {
+        if state >> 32 != number || RENDEZVOUS_RELEASED.load(Ordering::SeqCst) >= number
+        {
+            return false;
+        }
+
+        match RENDEZVOUS_STATE.compare_exchange(state, state + 1, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Ok(_) => return true,
+            Err(current) => state = current
+        }
+    }
+}
+
+/* add work to a CPU core's queue and interrupt it
+   <= true if queued, or false if the core can't be interrupted */
+fn queue(cpu: cpu::CPUcount, work: Work, outstanding: &Arc<AtomicUsize>) -> bool
+{
+    /* don't queue work for a core we can't interrupt */
+    if ipi::can_send_ipi(cpu) == false
+    {
+        return false;
+    }
+
+    outstanding.fetch_add(1, Ordering::SeqCst);
+    QUEUES[cpu].lock().push_back(Request { work, outstanding: outstanding.clone() });
+    ipi::send_ipi(cpu);
+    true
+}
+
+/* carry out an action on this CPU core */
+fn run(action: &SMPAction)
+{
+    match action
+    {
+        SMPAction::FenceI => unsafe { llvm_asm!("fence.i" :::: "volatile") },
+        SMPAction::SFenceVMA => physmem::tlb_flush(),
+        SMPAction::Stop => park(),
+        SMPAction::Call(f) => f()
+    }
+}
+
+/* stop this CPU core permanently */
+fn park() -> !
+{
+    /* mask all interrupts so nothing wakes us */
+    write_csr!(mie, 0);
+    loop
+    {
+        unsafe { llvm_asm!("wfi" :::: "volatile") };
+    }
+}
+
+/* return a bitmask of all booted CPU cores */
+fn all_cpus_mask() -> u64
+{
+    match cpu::nr_booted_cpus()
+    {
+        64 => !0,
+        n => (1 << n) - 1
+    }
+}
+
+/* convert a timeout into an exact pinned timer deadline, or None to wait forever */
+fn make_deadline(timeout: Option<timer::TimerValue>) -> Option<u64>
+{
+    match (timeout, timer::get_pinned_timer_now(), timer::get_pinned_timer_freq())
+    {
+        (Some(t), Some(now), Some(freq)) => Some(now.to_exact(freq).saturating_add(t.to_exact(freq))),
+        (_, _, _) => None
+    }
+}
+
+/* return true if the given deadline has passed */
+fn deadline_passed(deadline: Option<u64>) -> bool
+{
+    match (deadline, timer::get_pinned_timer_now(), timer::get_pinned_timer_freq())
+    {
+        (Some(d), Some(now), Some(freq)) => now.to_exact(freq) >= d,
+        (_, _, _) => false
+    }
+}
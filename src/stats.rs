@@ -0,0 +1,232 @@
+/* diosix RV64 per-CPU core trap statistics
+ *
+ * Count the interrupts, exceptions, SBI calls, and emulated
+ * instructions each CPU core handles. The counters are kept
+ * in the platform's own page of per-CPU variables, above each
+ * core's private variables, which is zeroed at boot, and can be
+ * read by any core
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::vec::Vec;
+use super::cpu;
+use super::irq::IRQCause;
+
+extern "C"
+{
+    fn platform_cpu_stats() -> usize;
+    fn platform_cpu_stats_of(cpu: cpu::CPUcount) -> usize;
+    fn platform_cpu_stats_size() -> usize;
+}
+
+/* causes counted individually. all platform-local interrupts are counted
+together, and reported as Local(16), the first local interrupt number */
+const COUNTED_CAUSES: &[IRQCause] = &[
+    IRQCause::UserSWI, IRQCause::SupervisorSWI, IRQCause::MachineSWI,
+    IRQCause::UserTimer, IRQCause::SupervisorTimer, IRQCause::MachineTimer,
+    IRQCause::UserInterrupt, IRQCause::SupervisorInterrupt, IRQCause::MachineInterrupt,
+    IRQCause::VirtualSupervisorSWI, IRQCause::VirtualSupervisorTimer, IRQCause::VirtualSupervisorInterrupt,
+    IRQCause::SupervisorGuestInterrupt, IRQCause::CounterOverflow, IRQCause::Local(16),
+    IRQCause::InstructionAlignment, IRQCause::InstructionAccess, IRQCause::IllegalInstruction,
+    IRQCause::InstructionPageFault, IRQCause::LoadAlignment, IRQCause::LoadAccess,
+    IRQCause::LoadPageFault, IRQCause::StoreAlignment, IRQCause::StoreAccess,
+    IRQCause::StorePageFault, IRQCause::Breakpoint,
+    IRQCause::InstructionGuestPageFault, IRQCause::LoadGuestPageFault,
+    IRQCause::StoreGuestPageFault, IRQCause::VirtualInstruction,
+    IRQCause::SoftwareCheck, IRQCause::HardwareError,
+    IRQCause::UserEnvironmentCall, IRQCause::SupervisorEnvironmentCall,
+    IRQCause::VirtualSupervisorEnvironmentCall, IRQCause::MachineEnvironmentCall
+];
+
+/* SBI extensions counted individually */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SBIExtension
+{
+    Base,
+    Timer,
+    RFence,
+    SystemReset,
+    IPI,
+    Diosix,             /* our own implementation-specific calls */
+    LegacyConsole,      /* legacy putchar and getchar */
+    LegacyTimer,
+    LegacyRFence,       /* legacy remote fence.i and sfence.vma */
+    LegacyShutdown,
+    Unknown             /* an extension we don't support */
+}
+
+const COUNTED_SBI_EXTENSIONS: &[SBIExtension] = &[
+    SBIExtension::Base, SBIExtension::Timer, SBIExtension::RFence, SBIExtension::SystemReset,
+    SBIExtension::IPI, SBIExtension::Diosix, SBIExtension::LegacyConsole, SBIExtension::LegacyTimer,
+    SBIExtension::LegacyRFence, SBIExtension::LegacyShutdown, SBIExtension::Unknown
+];
+
+/* outcomes of instruction emulation counted individually */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Emulated
+{
+    ReadTime,       /* rdtime emulated */
+    WaitForIRQ,     /* wfi caught as a yield */
+    TimerCompare,   /* stimecmp access emulated */
+    Failed          /* couldn't emulate the instruction */
+}
+
+const COUNTED_EMULATIONS: &[Emulated] = &[ Emulated::ReadTime, Emulated::WaitForIRQ, Emulated::TimerCompare, Emulated::Failed ];
+
+/* counters stored in each CPU core's platform variables */
+#[repr(C)]
+struct Counters
+{
+    causes: [AtomicU64; COUNTED_CAUSES.len()],
+    sbi: [AtomicU64; COUNTED_SBI_EXTENSIONS.len()],
+    emulated: [AtomicU64; COUNTED_EMULATIONS.len()]
+}
+
+/* copy of a CPU core's counters */
+#[derive(Debug, Clone)]
+pub struct Snapshot
+{
+    pub causes: Vec<(IRQCause, u64)>,
+    pub sbi: Vec<(SBIExtension, u64)>,
+    pub emulated: Vec<(Emulated, u64)>
+}
+
+impl Snapshot
+{
+    /* return the number of times the given cause has been seen */
+    pub fn get_cause(&self, cause: IRQCause) -> u64
+    {
+        match cause_index(cause)
+        {
+            Some(i) => self.causes[i].1,
+            None => 0
+        }
+    }
+
+    /* return the number of calls made to the given SBI extension */
+    pub fn get_sbi(&self, extension: SBIExtension) -> u64
+    {
+        match COUNTED_SBI_EXTENSIONS.iter().position(|e| *e == extension)
+        {
+            Some(i) => self.sbi[i].1,
+            None => 0
+        }
+    }
+
+    /* return the total number of traps of all causes */
+    pub fn total_traps(&self) -> u64
+    {
+        self.causes.iter().map(|(_, count)| count).sum()
+    }
+}
+
+/* count a trap taken by this CPU core */
+pub fn count_irq(cause: IRQCause)
+{
+    if let Some(i) = cause_index(cause)
+    {
+        if let Some(counters) = this_cpu()
+        {
+            counters.causes[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/* count an SBI call handled by this CPU core */
+pub fn count_sbi(extension: SBIExtension)
+{
+    if let Some(i) = COUNTED_SBI_EXTENSIONS.iter().position(|e| *e == extension)
+    {
+        if let Some(counters) = this_cpu()
+        {
+            counters.sbi[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/* count an instruction emulation attempt by this CPU core */
+pub fn count_emulated(outcome: Emulated)
+{
+    if let Some(i) = COUNTED_EMULATIONS.iter().position(|e| *e == outcome)
+    {
+        if let Some(counters) = this_cpu()
+        {
+            counters.emulated[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/* take a copy of a CPU core's counters. the counters are not all read at the same
+   instant, so a snapshot of a busy core may be slightly inconsistent
+   => cpu = linear CPU core ID of the core to read
+   <= copy of the counters, or None if no such core has booted */
+pub fn snapshot(cpu: cpu::CPUcount) -> Option<Snapshot>
+{
+    let counters = match cpu < cpu::nr_booted_cpus()
+    {
+        true => cpu_counters(cpu)?,
+        false => return None
+    };
+
+    Some(Snapshot
+    {
+        causes: COUNTED_CAUSES.iter().zip(counters.causes.iter())
+                    .map(|(c, n)| (*c, n.load(Ordering::Relaxed))).collect(),
+        sbi: COUNTED_SBI_EXTENSIONS.iter().zip(counters.sbi.iter())
+                    .map(|(e, n)| (*e, n.load(Ordering::Relaxed))).collect(),
+        emulated: COUNTED_EMULATIONS.iter().zip(counters.emulated.iter())
+                    .map(|(e, n)| (*e, n.load(Ordering::Relaxed))).collect()
+    })
+}
+
+/* zero a CPU core's counters
+   => cpu = linear CPU core ID of the core to reset */
+pub fn reset(cpu: cpu::CPUcount)
+{
+    if let (true, Some(counters)) = (cpu < cpu::nr_booted_cpus(), cpu_counters(cpu))
+    {
+        counters.causes.iter().chain(counters.sbi.iter()).chain(counters.emulated.iter())
+            .for_each(|n| n.store(0, Ordering::Relaxed));
+    }
+}
+
+/* return the counters slot for the given cause */
+fn cause_index(cause: IRQCause) -> Option<usize>
+{
+    let cause = match cause
+    {
+        IRQCause::Local(_) => IRQCause::Local(16),
+        c => c
+    };
+    COUNTED_CAUSES.iter().position(|c| *c == cause)
+}
+
+/* return this CPU core's counters, or None if they don't fit in its platform variables */
+fn this_cpu() -> Option<&'static Counters>
+{
+    match counters_fit()
+    {
+        true => Some(unsafe { &*(platform_cpu_stats() as *const Counters) }),
+        false => None
+    }
+}
+
+/* return the given CPU core's counters, or None if they don't fit in its platform variables */
+fn cpu_counters(cpu: cpu::CPUcount) -> Option<&'static Counters>
+{
+    match counters_fit()
+    {
+        true => Some(unsafe { &*(platform_cpu_stats_of(cpu) as *const Counters) }),
+        false => None
+    }
+}
+
+/* return true if the counters fit in the space set aside for them in asm/consts.s */
+fn counters_fit() -> bool
+{
+    core::mem::size_of::<Counters>() <= unsafe { platform_cpu_stats_size() }
+}
@@ -27,7 +27,13 @@
 #![allow(dead_code)]
 
 use super::irq;
+use super::cpu;
+use super::vclock;
 use super::timer;
+use super::stats;
+use super::ipi;
+use super::smp::{self, SMPAction};
+use alloc::sync::Arc;
 
 /* this implementation follows version 0.2 of the RISC-V SBI */
 const SBI_SPEC_VERSION: usize = 2;
@@ -76,6 +82,12 @@ const SBI_EXT_TIMER_SET:                usize = 0;
 /* the timer extension is mirrored in legacy SBI extension 0 */
 const SBI_LEGACY_TIMER_SET:             usize = 0;
 
+/* IPI extension */
+const SBI_EXT_IPI:                      usize = 0x735049;
+const SBI_EXT_IPI_SEND_IPI:             usize = 0;
+/* a hart mask base of -1 selects all harts */
+const SBI_HART_MASK_BASE_ALL:           usize = usize::MAX;
+
 /* rfence extension */
 const SBI_EXT_RFENCE:                   usize = 0x52464e43;
 const SBI_EXT_RFENCE_I:                 usize = 0;
@@ -95,6 +107,7 @@ static SBI_EXTS: &'static [usize] = &[
     /* modern extensions */
     SBI_EXT_BASE,
     SBI_EXT_TIMER,
+    SBI_EXT_IPI,
     SBI_EXT_RFENCE,
     SBI_EXT_SYS_RESET,
     SBI_EXT_DIOSIX,
@@ -114,6 +127,7 @@ pub enum Action
     Terminate,  /* terminate the running supervisor environment */
     Restart, /* restart the running supervisor environment */
     TimerIRQAt(timer::TimerValue), /* raise a timer interrupt at or after the given time */
+    SendIPI(u64), /* raise a software interrupt on the virtual cores in this mask: bit n set for virtual hart n */
     OutputChar(char), /* the guest wants to write a character to the console */
     InputChar, /* the guest wants to read a character from the console */
     ConsoleBufferWriteChar(char, usize), /* console capsule wants to write to a guest's console buffer */
@@ -142,6 +156,8 @@ pub fn handler(context: &mut irq::IRQContext) -> Option<Action>
     let extension = context.registers[irq::REG_A7];
     let function = context.registers[irq::REG_A6];
 
+    stats::count_sbi(classify(extension));
+
     match (extension, function)
     {
         /* legacy extensions that have no modern mapping */
@@ -246,10 +262,40 @@ pub fn handler(context: &mut irq::IRQContext) -> Option<Action>
 
             let trigger_at: u64 =  context.registers[irq::REG_A0] as u64;
 
-            /* let the supervisor know this worked, and let the hypervisor know
-            it needs to trigger a timer interrupt at some point */
+            /* let the supervisor know this worked. with Sstc, program the supervisor's own
+            timer compare, and the hardware will raise the interrupt. otherwise, let the
+            hypervisor know it needs to trigger a timer interrupt at some point, translating
+            the supervisor's target into host time. a paused clock never reaches its target */
             success(context, 0);
-            Some(Action::TimerIRQAt(timer::TimerValue::Exact(trigger_at)))
+            match cpu::set_supervisor_timer_target(trigger_at)
+            {
+                true => None,
+                false => match vclock::running().to_host(trigger_at)
+                {
+                    Some(host_target) => Some(Action::TimerIRQAt(timer::TimerValue::Exact(host_target))),
+                    None => None
+                }
+            }
+        },
+
+        /* send a software interrupt to other virtual cores. the hypervisor knows which physical
+        cores, if any, are running them, and should deliver the interrupt with raise_ipi() or
+        SupervisorState::set_virtual_irq_pending() for descheduled cores */
+        (SBI_EXT_IPI, SBI_EXT_IPI_SEND_IPI) =>
+        {
+            match hart_mask(context.registers[irq::REG_A0], context.registers[irq::REG_A1])
+            {
+                Some(mask) =>
+                {
+                    success(context, 0);
+                    Some(Action::SendIPI(mask))
+                },
+                None =>
+                {
+                    set_error_code(context, SBI_ERR_INVALID_PARAM);
+                    None
+                }
+            }
         },
 
         /* newer system shutdown ABI call */
@@ -319,6 +365,60 @@ pub fn handler(context: &mut irq::IRQContext) -> Option<Action>
     }
 }
 
+/* convert an SBI hart mask and base into a mask of virtual harts
+   => mask = bit n set to select hart base + n
+      base = hart ID of bit 0, or SBI_HART_MASK_BASE_ALL to select every hart
+   <= mask with bit n set for hart n, or None if the selected harts can't be represented */
+fn hart_mask(mask: usize, base: usize) -> Option<u64>
+{
+    if base == SBI_HART_MASK_BASE_ALL
+    {
+        return Some(u64::MAX);
+    }
+
+    if base >= 64 || (base > 0 && (mask as u64) >> (64 - base) != 0)
+    {
+        return None;
+    }
+
+    Some((mask as u64) << base)
+}
+
+/* raise a supervisor software interrupt for the virtual core running on a physical CPU core,
+   as requested by another virtual core via Action::SendIPI. if the target core has an ACLINT
+   SSWI register, the interrupt is raised directly without interrupting its machine level.
+   otherwise, the target core is asked to raise it for its running supervisor
+   => cpu = linear CPU core ID of the physical core running the target virtual core
+   <= true for success, or false if there's no such core */
+pub fn raise_ipi(cpu: cpu::CPUcount) -> bool
+{
+    if ipi::send_supervisor_ipi(cpu) == true
+    {
+        return true;
+    }
+
+    smp::call_on(cpu, SMPAction::Call(Arc::new(|| cpu::set_running_virtual_irq_pending(cpu::SIP_SSIP, true)))).is_some()
+}
+
+/* describe an SBI extension ID for the trap statistics */
+fn classify(extension: usize) -> stats::SBIExtension
+{
+    match extension
+    {
+        SBI_EXT_BASE => stats::SBIExtension::Base,
+        SBI_EXT_TIMER => stats::SBIExtension::Timer,
+        SBI_EXT_IPI => stats::SBIExtension::IPI,
+        SBI_EXT_RFENCE => stats::SBIExtension::RFence,
+        SBI_EXT_SYS_RESET => stats::SBIExtension::SystemReset,
+        SBI_EXT_DIOSIX => stats::SBIExtension::Diosix,
+        SBI_EXT_CONSOLE_PUTCHAR | SBI_EXT_CONSOLE_GETCHAR => stats::SBIExtension::LegacyConsole,
+        SBI_LEGACY_TIMER_SET => stats::SBIExtension::LegacyTimer,
+        SBI_LEGACY_REMOTE_FENCE_I | SBI_LEGACY_SFENCE_VMA => stats::SBIExtension::LegacyRFence,
+        SBI_EXT_SHUTDOWN => stats::SBIExtension::LegacyShutdown,
+        _ => stats::SBIExtension::Unknown
+    }
+}
+
 /* indicate a syscall failed */
 pub fn failed(context: &mut irq::IRQContext, reason: ActionResult)
 {
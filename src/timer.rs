@@ -5,15 +5,20 @@
  * See LICENSE for usage and copying.
  */
 
+use core::cmp::Ordering;
+use core::ops::{Add, Sub};
+use core::time::Duration;
+use alloc::vec::Vec;
 use spin::Mutex;
 use super::physmem;
+use super::cpu;
 
 extern "C"
 {
     fn platform_timer_machine_enable();
-    fn platform_timer_target(target: u64, clint_base: physmem::PhysMemBase);
-    fn platform_timer_get_target(clint_base: physmem::PhysMemBase) -> u64;
-    fn platform_timer_now(clint_base: physmem::PhysMemBase) -> u64;
+    fn platform_timer_target(target: u64, mtimecmp: physmem::PhysMemBase);
+    fn platform_timer_get_target(mtimecmp: physmem::PhysMemBase) -> u64;
+    fn platform_timer_now(mtime: physmem::PhysMemBase) -> u64;
     fn platform_timer_supervisor_enable();
     fn platform_timer_supervisor_trigger();
     fn platform_timer_supervisor_clear();
@@ -23,6 +28,120 @@ lazy_static!
 {
     /* acquire PINNED_TIMER lock before accessing the timer */
     static ref PINNED_TIMER: Mutex<Option<Timer>> = Mutex::new(None);
+
+    /* acquire HART_TIMERS lock to access the locations of each hart's timer registers */
+    static ref HART_TIMERS: Mutex<Vec<HartTimer>> = Mutex::new(Vec::new());
+
+    /* each CPU core's timer registers once found, with the base address of the timer they
+    were found for, so that timer accesses don't have to search HART_TIMERS every time */
+    static ref THIS_HART_TIMER: cpu::PerCpu<Mutex<Option<(physmem::PhysMemBase, HartTimer)>>> = cpu::PerCpu::new(|| Mutex::new(None));
+}
+
+/* offsets of the timer and software interrupt registers from a classic CLINT's base address.
+each hart has its own mtimecmp and MSIP registers, indexed by the hart's slot in the CLINT */
+pub const CLINT_MSIP:       usize = 0x0;
+pub const CLINT_MTIMECMP:   usize = 0x4000;
+pub const CLINT_MTIME:      usize = 0xbff8;
+
+/* interrupt numbers of a CPU core's local interrupt controller that a CLINT or ACLINT's
+interrupts-extended property wires its software interrupt and timer registers to */
+pub const INTC_SUPERVISOR_SOFTWARE: u32 = 1;
+pub const INTC_MACHINE_SOFTWARE:    u32 = 3;
+pub const INTC_MACHINE_TIMER:       u32 = 7;
+
+/* describe where a hart's timer and machine software interrupt registers are */
+#[derive(Debug, Clone, Copy)]
+pub struct HartTimer
+{
+    pub hart: usize,                            /* hart ID of the core */
+    pub mtime: physmem::PhysMemBase,            /* address of the timer counter it reads */
+    pub mtimecmp: physmem::PhysMemBase,         /* address of its timer compare register */
+    pub msip: Option<physmem::PhysMemBase>      /* address of its MSIP register, if known */
+}
+
+impl HartTimer
+{
+    /* describe a hart's registers in a classic CLINT
+       => hart = hart ID of the core
+          clint_base = base MMIO address of the CLINT
+          slot = the hart's position in the CLINT's list of harts */
+    pub fn from_clint(hart: usize, clint_base: physmem::PhysMemBase, slot: usize) -> HartTimer
+    {
+        HartTimer
+        {
+            hart,
+            mtime: clint_base + CLINT_MTIME,
+            mtimecmp: clint_base + CLINT_MTIMECMP + (slot * 8),
+            msip: Some(clint_base + CLINT_MSIP + (slot * 4))
+        }
+    }
+
+    /* describe a hart's registers in an ACLINT MTIMER. its MSIP register is in a
+       separate MSWI device, so it must be added with set_hart_msip()
+       => hart = hart ID of the core
+          mtimecmp_base = address of the MTIMER's first mtimecmp register
+          mtime = address of the MTIMER's mtime register
+          slot = the hart's position in the MTIMER's list of harts */
+    pub fn from_aclint(hart: usize, mtimecmp_base: physmem::PhysMemBase, mtime: physmem::PhysMemBase, slot: usize) -> HartTimer
+    {
+        HartTimer
+        {
+            hart,
+            mtime,
+            mtimecmp: mtimecmp_base + (slot * 8),
+            msip: None
+        }
+    }
+}
+
+/* record the locations of a hart's timer registers, replacing any previous record
+   => timer = description of the hart's registers */
+pub fn add_hart_timer(timer: HartTimer)
+{
+    {
+        let mut timers = HART_TIMERS.lock();
+        timers.retain(|t| t.hart != timer.hart);
+        timers.push(timer);
+    }
+    forget_hart_timers();
+}
+
+/* record the location of a hart's MSIP register, such as one in an ACLINT MSWI device
+   => hart = hart ID of the core
+      msip = address of its MSIP register
+   <= true for success, or false if the hart's timer registers aren't known yet */
+pub fn set_hart_msip(hart: usize, msip: physmem::PhysMemBase) -> bool
+{
+    let found = match HART_TIMERS.lock().iter_mut().find(|t| t.hart == hart)
+    {
+        Some(timer) =>
+        {
+            timer.msip = Some(msip);
+            true
+        },
+        None => false
+    };
+
+    if found == true
+    {
+        forget_hart_timers();
+    }
+    found
+}
+
+/* make every CPU core look up its timer registers again, after the records change */
+fn forget_hart_timers()
+{
+    for cpu in 0..cpu::MAX_CPUS
+    {
+        *(THIS_HART_TIMER[cpu].lock()) = None;
+    }
+}
+
+/* return the locations of the given hart's timer registers, or None if not known */
+pub fn get_hart_timer(hart: usize) -> Option<HartTimer>
+{
+    HART_TIMERS.lock().iter().find(|t| t.hart == hart).cloned()
 }
 
 /* divide timer frequency down into ticks per millisecond (1 thousandth of a second) */
@@ -30,9 +149,9 @@ const THOUSAND: u64 = 1 * 1000;
 /* divide timer frequency down into ticks per microsecond (1 millionth of a second) */
 const MILLION: u64 = 1 * THOUSAND * THOUSAND;
 /* divide timer frequency down into ticks per nanosecond (1 billionth of a second) */
-const BILLION: u64 = 1 * THOUSAND * MILLION;
+pub const BILLION: u64 = 1 * THOUSAND * MILLION;
 
-/* a timer value is either in microseconds or an exact timer value */
+/* a timer value is either in sub-seconds or seconds, or an exact timer value */
 #[derive(Debug, Clone, Copy)]
 pub enum TimerValue
 {
@@ -43,76 +162,421 @@ pub enum TimerValue
     Exact(u64)
 }
 
+/* select how to round a conversion that doesn't divide exactly */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rounding
+{
+    Down,   /* truncate towards zero */
+    Up      /* round away from zero, so a non-zero value never becomes zero */
+}
+
+/* calculate value * mul / div without losing precision, saturating on overflow.
+   a zero divisor, such as an unknown timer frequency, saturates any non-zero value */
+pub fn scale(value: u64, mul: u64, div: u64, rounding: Rounding) -> u64
+{
+    if div == 0
+    {
+        return if value == 0 { 0 } else { u64::MAX };
+    }
+
+    let product = value as u128 * mul as u128;
+    let result = match rounding
+    {
+        Rounding::Down => product / div as u128,
+        Rounding::Up => (product + (div as u128 - 1)) / div as u128
+    };
+
+    if result > u64::MAX as u128 { u64::MAX } else { result as u64 }
+}
+
 impl TimerValue
 {
-    /* convert whatever the per-second value is to an
-    exact timer value given the timer's freq in Hz */
+    /* return the number of this value's units in a second, given the timer's freq in Hz */
+    fn per_second(&self, freq: u64) -> u64
+    {
+        match self
+        {
+            TimerValue::Nanoseconds(_)  => BILLION,
+            TimerValue::Microseconds(_) => MILLION,
+            TimerValue::Milliseconds(_) => THOUSAND,
+            TimerValue::Seconds(_)      => 1,
+            TimerValue::Exact(_)        => freq
+        }
+    }
+
+    /* return the value without its unit */
+    fn value(&self) -> u64
+    {
+        match *self
+        {
+            TimerValue::Nanoseconds(t) | TimerValue::Microseconds(t) | TimerValue::Milliseconds(t) |
+            TimerValue::Seconds(t) | TimerValue::Exact(t) => t
+        }
+    }
+
+    /* return this value in units of which there are per_second in a second */
+    fn convert(self, per_second: u64, freq: u64, rounding: Rounding) -> u64
+    {
+        match self.per_second(freq)
+        {
+            same if same == per_second => self.value(), /* already in the wanted units */
+            from => scale(self.value(), per_second, from, rounding)
+        }
+    }
+
+    /* convert whatever the per-second value is to an exact timer value given the timer's
+    freq in Hz. this rounds up so that a timer never fires early, and a non-zero duration
+    never becomes zero ticks */
     pub fn to_exact(self, freq: u64) -> u64
+    {
+        self.to_exact_rounded(freq, Rounding::Up)
+    }
+
+    pub fn to_exact_rounded(self, freq: u64, rounding: Rounding) -> u64
     {
         match self
         {
-            TimerValue::Nanoseconds(t)  => (freq / BILLION) * t,
-            TimerValue::Microseconds(t) => (freq / MILLION) * t,
-            TimerValue::Milliseconds(t) => (freq / THOUSAND) * t,
-            TimerValue::Seconds(t)      => freq * t,
-            TimerValue::Exact(t)        => t
+            TimerValue::Exact(t) => t,
+            _ => self.convert(freq, freq, rounding)
         }
     }
 
+    /* convert to a per-second value given the timer's freq in Hz. these round down */
     pub fn to_nanoseconds(self, freq: u64) -> TimerValue
     {
-        TimerValue::Nanoseconds(match self
-        {
-            TimerValue::Nanoseconds(t)  => t,
-            TimerValue::Microseconds(t) => t * THOUSAND,
-            TimerValue::Milliseconds(t) => t * MILLION,
-            TimerValue::Seconds(t)      => t * BILLION,
-            TimerValue::Exact(t)        => (t / freq) * BILLION
-        })
+        self.to_nanoseconds_rounded(freq, Rounding::Down)
     }
 
     pub fn to_microseconds(self, freq: u64) -> TimerValue
     {
-        TimerValue::Microseconds(match self
-        {
-            TimerValue::Nanoseconds(t)  => t / THOUSAND,
-            TimerValue::Microseconds(t) => t,
-            TimerValue::Milliseconds(t) => t * THOUSAND,
-            TimerValue::Seconds(t)      => t * MILLION,
-            TimerValue::Exact(t)        => (t / freq) * MILLION
-        })
+        self.to_microseconds_rounded(freq, Rounding::Down)
     }
 
     pub fn to_milliseconds(self, freq: u64) -> TimerValue
     {
-        TimerValue::Milliseconds(match self
-        {
-            TimerValue::Nanoseconds(t)  => t / MILLION,
-            TimerValue::Microseconds(t) => t / THOUSAND,
-            TimerValue::Milliseconds(t) => t,
-            TimerValue::Seconds(t)      => t * THOUSAND,
-            TimerValue::Exact(t)        => (t / freq) * THOUSAND
-        })
+        self.to_milliseconds_rounded(freq, Rounding::Down)
     }
 
     pub fn to_seconds(self, freq: u64) -> TimerValue
     {
-        TimerValue::Seconds(match self
+        self.to_seconds_rounded(freq, Rounding::Down)
+    }
+
+    pub fn to_nanoseconds_rounded(self, freq: u64, rounding: Rounding) -> TimerValue
+    {
+        TimerValue::Nanoseconds(self.convert(BILLION, freq, rounding))
+    }
+
+    pub fn to_microseconds_rounded(self, freq: u64, rounding: Rounding) -> TimerValue
+    {
+        TimerValue::Microseconds(self.convert(MILLION, freq, rounding))
+    }
+
+    pub fn to_milliseconds_rounded(self, freq: u64, rounding: Rounding) -> TimerValue
+    {
+        TimerValue::Milliseconds(self.convert(THOUSAND, freq, rounding))
+    }
+
+    pub fn to_seconds_rounded(self, freq: u64, rounding: Rounding) -> TimerValue
+    {
+        TimerValue::Seconds(self.convert(1, freq, rounding))
+    }
+
+    /* convert to a Duration given the timer's freq in Hz, rounding down to the nearest
+    nanosecond. a zero freq makes any non-zero exact value the longest possible Duration */
+    pub fn to_duration(self, freq: u64) -> Duration
+    {
+        let per_second = self.per_second(freq);
+        if per_second == 0
         {
-            TimerValue::Nanoseconds(t)  => t / BILLION,
-            TimerValue::Microseconds(t) => t / MILLION,
-            TimerValue::Milliseconds(t) => t / THOUSAND,
-            TimerValue::Seconds(t)      => t,
-            TimerValue::Exact(t)        => t / freq
-        })
+            return match self.value()
+            {
+                0 => Duration::from_secs(0),
+                _ => Duration::new(u64::MAX, (BILLION - 1) as u32)
+            };
+        }
+
+        let seconds = self.value() / per_second;
+        let remainder = self.value() % per_second;
+        Duration::new(seconds, scale(remainder, BILLION, per_second, Rounding::Down) as u32)
+    }
+
+    /* return true if converting between this value's units and the other's needs the timer's
+    frequency, which is the case when mixing an exact value with a sub-second or seconds value */
+    fn needs_freq(&self, other: &TimerValue) -> bool
+    {
+        match (self, other)
+        {
+            (TimerValue::Exact(_), TimerValue::Exact(_)) => false,
+            (TimerValue::Exact(_), _) | (_, TimerValue::Exact(_)) => true,
+            (_, _) => false
+        }
+    }
+
+    /* convert this value and another to the finer of their two units
+       => freq = timer's frequency in Hz, used if one value is exact and the other isn't
+       <= (this value, the other value, a function to wrap a raw value in that unit) */
+    fn to_common_unit(self, other: TimerValue, freq: u64) -> (u64, u64, fn(u64) -> TimerValue)
+    {
+        let (unit, per_second) = match self.per_second(freq) >= other.per_second(freq)
+        {
+            true => (self, self.per_second(freq)),
+            false => (other, other.per_second(freq))
+        };
+
+        let wrap: fn(u64) -> TimerValue = match unit
+        {
+            TimerValue::Nanoseconds(_)  => TimerValue::Nanoseconds,
+            TimerValue::Microseconds(_) => TimerValue::Microseconds,
+            TimerValue::Milliseconds(_) => TimerValue::Milliseconds,
+            TimerValue::Seconds(_)      => TimerValue::Seconds,
+            TimerValue::Exact(_)        => TimerValue::Exact
+        };
+
+        (self.convert(per_second, freq, Rounding::Down), other.convert(per_second, freq, Rounding::Down), wrap)
+    }
+
+    /* add or subtract timer values in any units, in the finer of their two units. additions
+       saturate on overflow, and subtractions stop at zero
+       => other = value to add or subtract
+          freq = timer's frequency in Hz, used if one value is exact and the other isn't */
+    pub fn add_at(self, other: TimerValue, freq: u64) -> TimerValue
+    {
+        let (a, b, wrap) = self.to_common_unit(other, freq);
+        wrap(a.saturating_add(b))
+    }
+
+    pub fn sub_at(self, other: TimerValue, freq: u64) -> TimerValue
+    {
+        let (a, b, wrap) = self.to_common_unit(other, freq);
+        wrap(a.saturating_sub(b))
+    }
+
+    /* compare timer values in any units. each value is scaled by the other's units per
+       second, rather than converted and rounded, so that nothing is lost to rounding
+       => other = value to compare with
+          freq = timer's frequency in Hz, used if one value is exact and the other isn't */
+    pub fn cmp_at(&self, other: &TimerValue, freq: u64) -> Ordering
+    {
+        if self.per_second(freq) == other.per_second(freq)
+        {
+            return self.value().cmp(&other.value());
+        }
+
+        let a = self.value() as u128 * other.per_second(freq) as u128;
+        let b = other.value() as u128 * self.per_second(freq) as u128;
+        a.cmp(&b)
+    }
+
+    /* pair this value with the timer's frequency in Hz for use with the operators
+       => freq = timer's frequency in Hz
+       <= value that can be mixed with values in any units */
+    pub fn at(self, freq: u64) -> TimerValueAt
+    {
+        TimerValueAt { value: self, freq }
     }
 }
 
+/* a timer value paired with the timer's frequency in Hz, so that it can be added to,
+subtracted from, and ordered against values in any units. create with TimerValue::at() */
+#[derive(Debug, Clone, Copy)]
+pub struct TimerValueAt
+{
+    value: TimerValue,
+    freq: u64
+}
+
+impl TimerValueAt
+{
+    /* return the value, in the units the operators left it in */
+    pub fn value(&self) -> TimerValue { self.value }
+    pub fn freq(&self) -> u64 { self.freq }
+}
+
+/* additions saturate on overflow, and subtractions stop at zero. see add_at() and sub_at() */
+impl Add<TimerValue> for TimerValueAt
+{
+    type Output = TimerValueAt;
+
+    fn add(self, other: TimerValue) -> TimerValueAt
+    {
+        self.value.add_at(other, self.freq).at(self.freq)
+    }
+}
+
+impl Sub<TimerValue> for TimerValueAt
+{
+    type Output = TimerValueAt;
+
+    fn sub(self, other: TimerValue) -> TimerValueAt
+    {
+        self.value.sub_at(other, self.freq).at(self.freq)
+    }
+}
+
+/* values paired with different frequencies are compared using the left-hand value's frequency */
+impl Ord for TimerValueAt
+{
+    fn cmp(&self, other: &TimerValueAt) -> Ordering
+    {
+        self.value.cmp_at(&other.value, self.freq)
+    }
+}
+
+impl PartialOrd for TimerValueAt
+{
+    fn partial_cmp(&self, other: &TimerValueAt) -> Option<Ordering>
+    {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for TimerValueAt
+{
+    fn eq(&self, other: &TimerValueAt) -> bool
+    {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for TimerValueAt {}
+
+/* plain timer values can only be ordered when their units can be converted without knowing
+the timer's frequency: exact values with exact values, and seconds and sub-second values with
+each other. otherwise they're unordered and never equal: use at() or cmp_at() to mix them */
+impl PartialOrd for TimerValue
+{
+    fn partial_cmp(&self, other: &TimerValue) -> Option<Ordering>
+    {
+        match self.needs_freq(other)
+        {
+            true => None,
+            false => Some(self.cmp_at(other, 0))
+        }
+    }
+}
+
+impl PartialEq for TimerValue
+{
+    fn eq(&self, other: &TimerValue) -> bool
+    {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+/* Durations are stored to the nanosecond, saturating after about 584 years */
+impl From<Duration> for TimerValue
+{
+    fn from(duration: Duration) -> TimerValue
+    {
+        let nanoseconds = duration.as_nanos();
+        TimerValue::Nanoseconds(if nanoseconds > u64::MAX as u128 { u64::MAX } else { nanoseconds as u64 })
+    }
+}
+
+/* how sure we are of a timer's frequency */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimebaseConfidence
+{
+    DeviceTree, /* given by the device tree, so assumed exact */
+    High,       /* measured against a reference clock, and repeat measurements agreed */
+    Low,        /* measured against a reference clock, but repeat measurements disagreed */
+    Guess       /* no way to measure it, so a common frequency was assumed */
+}
+
+/* describe a timer's frequency and where it came from */
+#[derive(Debug, Clone, Copy)]
+pub struct Timebase
+{
+    pub frequency: u64,                 /* ticks per second */
+    pub confidence: TimebaseConfidence
+}
+
+/* frequency to assume if a timer can't be measured. this is Qemu's timebase */
+pub const TIMEBASE_GUESS: u64 = 10 * MILLION;
+
+/* measure a timer against a reference clock for this many nanoseconds, this many times */
+const CALIBRATION_PERIOD: u64 = 10 * MILLION;
+const CALIBRATION_RUNS: usize = 3;
+
+/* measurements within this many parts per million of each other are considered in agreement */
+const CALIBRATION_TOLERANCE_PPM: u64 = 1000;
+
+/* give up if the timer or reference clock doesn't move in this many reads */
+const CALIBRATION_MAX_SPINS: usize = 100 * 1000 * 1000;
+
+/* measure a timer's frequency against a reference clock that counts nanoseconds
+   => timer = timer to measure. its own frequency setting is ignored
+      reference = function that returns the reference clock's current value, or None if unavailable
+   <= measured frequency and confidence, or None if the timer or reference doesn't tick */
+pub fn calibrate<F>(timer: &Timer, reference: F) -> Option<Timebase> where F: Fn() -> Option<u64>
+{
+    let mut results = [0u64; CALIBRATION_RUNS];
+    for result in results.iter_mut()
+    {
+        /* start on the edge of a reference clock tick */
+        let mut start_ref = reference()?;
+        let mut spins = 0;
+        loop
+        {
+            let r = reference()?;
+            if r != start_ref
+            {
+                start_ref = r;
+                break;
+            }
+            spins = spins + 1;
+            if spins > CALIBRATION_MAX_SPINS { return None; }
+        }
+        let start_ticks = timer.get_now().to_exact(1);
+
+        /* wait for the measurement period to pass on the reference clock */
+        let mut end_ref;
+        spins = 0;
+        loop
+        {
+            end_ref = reference()?;
+            if end_ref.saturating_sub(start_ref) >= CALIBRATION_PERIOD
+            {
+                break;
+            }
+            spins = spins + 1;
+            if spins > CALIBRATION_MAX_SPINS { return None; }
+        }
+        let end_ticks = timer.get_now().to_exact(1);
+
+        let ticks = end_ticks.wrapping_sub(start_ticks);
+        if ticks == 0
+        {
+            return None;
+        }
+        *result = scale(ticks, BILLION, end_ref - start_ref, Rounding::Down);
+    }
+
+    /* use the median measurement, and check the others agree with it */
+    results.sort();
+    let frequency = results[CALIBRATION_RUNS / 2];
+    let tolerance = scale(frequency, CALIBRATION_TOLERANCE_PPM, MILLION, Rounding::Up);
+    let agreed = results.iter().all(|f| (*f as i128 - frequency as i128).abs() <= tolerance as i128);
+
+    Some(Timebase
+    {
+        frequency,
+        confidence: match agreed
+        {
+            true => TimebaseConfidence::High,
+            false => TimebaseConfidence::Low
+        }
+    })
+}
+
 /* describe a per-CPU core timer */
 #[derive(Clone, Copy, Debug)]
 pub struct Timer
 {
-    clint_base: physmem::PhysMemBase, /* base MMIO address of system's CLINT IO controller */
+    clint_base: physmem::PhysMemBase, /* base MMIO address of system's CLINT IO controller,
+                                         or of an ACLINT MTIMER's mtimecmp registers */
+    aclint_mtime: Option<physmem::PhysMemBase>, /* address of an ACLINT MTIMER's mtime register */
     frequency: u64 /* rate at which timer is incremented */
 }
 
@@ -128,6 +592,23 @@ impl Timer
         Timer
         {
             clint_base,
+            aclint_mtime: None,
+            frequency
+        }
+    }
+
+    /* create a new per-CPU core timer from an ACLINT MTIMER device, which has
+       its mtime and mtimecmp registers at independent addresses
+       => frequency = rate at which this timer counter increments
+          mtimecmp_base = address of the first hart's mtimecmp register
+          mtime = address of the mtime register
+       <= per-CPU core timer object */
+    pub fn new_aclint(frequency: u64, mtimecmp_base: physmem::PhysMemBase, mtime: physmem::PhysMemBase) -> Timer
+    {
+        Timer
+        {
+            clint_base: mtimecmp_base,
+            aclint_mtime: Some(mtime),
             frequency
         }
     }
@@ -142,9 +623,20 @@ impl Timer
     /* return base MMIO address of timer */
     pub fn get_mmio_base(&self) -> physmem::PhysMemBase { self.clint_base }
 
+    /* return true if this timer is an ACLINT MTIMER rather than part of a classic CLINT */
+    pub fn is_aclint(&self) -> bool { self.aclint_mtime.is_some() }
+
     /* return frequency of timer */
     pub fn get_frequency(&self) -> u64 { self.frequency }
 
+    /* return a copy of this timer with the given frequency, such as after calibrating it */
+    pub fn with_frequency(&self, frequency: u64) -> Timer
+    {
+        let mut timer = self.clone();
+        timer.frequency = frequency;
+        timer
+    }
+
     /* enable this CPU core's incremental timer interrupt */
     pub fn start(&self)
     {
@@ -154,33 +646,68 @@ impl Timer
         unsafe { platform_timer_machine_enable(); }
     }
 
+    /* enable this CPU core's timer interrupt without arming it. in tickless mode,
+    the timer only fires for deadlines queued in the core's timer queue, rather than
+    for a periodic tick, so that an idle core can sleep until it has work to do */
+    pub fn start_tickless(&self)
+    {
+        unsafe { platform_timer_target(u64::MAX, self.this_hart().mtimecmp); }
+        unsafe { platform_timer_machine_enable(); }
+    }
+
     /* return the current timer value. this is a clock-on-the-wall
     value in that it doesn't reset, always incremements at a fixed rate,
     though will rollover to 0 */
     pub fn get_now(&self) -> TimerValue
     {
-        TimerValue::Exact(unsafe { platform_timer_now(self.clint_base) })
+        TimerValue::Exact(unsafe { platform_timer_now(self.this_hart().mtime) })
     }
 
     /* trigger an IRQ after this number of ticks or sub-seconds
        => duration = number of ticks or sub-seconds from now to interrupt */
     pub fn next_in(&self, duration: TimerValue)
     {
-        let target = duration.to_exact(self.frequency) + unsafe { platform_timer_now(self.clint_base) };
-        unsafe { platform_timer_target(target, self.clint_base); }
+        let registers = self.this_hart();
+        let target = duration.to_exact(self.frequency).saturating_add(unsafe { platform_timer_now(registers.mtime) });
+        unsafe { platform_timer_target(target, registers.mtimecmp); }
     }
 
     /* define the timer value after which an IRQ is triggered for this CPU core.
     => target = fire the IRQ when the timer value passes this target value */
     pub fn next_at(&self, target: TimerValue)
     {
-        unsafe { platform_timer_target(target.to_exact(self.frequency), self.clint_base); }
+        unsafe { platform_timer_target(target.to_exact(self.frequency), self.this_hart().mtimecmp); }
     }
 
     /* get the target value that will cause the timer IRQ to fire next */
     pub fn get_next_at(&self) -> TimerValue
     {
-        TimerValue::Exact(unsafe { platform_timer_get_target(self.clint_base) })
+        TimerValue::Exact(unsafe { platform_timer_get_target(self.this_hart().mtimecmp) })
+    }
+
+    /* return the locations of this CPU core's timer registers. if the device tree didn't
+    say where they are, assume the core's slot in this timer's CLINT or MTIMER is its hart ID.
+    the result is kept per core so that it's only looked up once */
+    fn this_hart(&self) -> HartTimer
+    {
+        if let Some((base, registers)) = *(THIS_HART_TIMER.this().lock())
+        {
+            if base == self.clint_base
+            {
+                return registers;
+            }
+        }
+
+        let hart = read_csr!(mhartid);
+        let registers = match (get_hart_timer(hart), self.aclint_mtime)
+        {
+            (Some(registers), _) => registers,
+            (None, Some(mtime)) => HartTimer::from_aclint(hart, self.clint_base, mtime, hart),
+            (None, None) => HartTimer::from_clint(hart, self.clint_base, hart)
+        };
+
+        *(THIS_HART_TIMER.this().lock()) = Some((self.clint_base, registers));
+        registers
     }
 }
 
@@ -195,6 +722,27 @@ pub fn get_pinned_timer_now() -> Option<TimerValue>
     }
 }
 
+/* return the base MMIO address of the pinned timer's CLINT, or None for no pinned timer */
+pub fn get_pinned_timer_base() -> Option<physmem::PhysMemBase>
+{
+    let pinned = PINNED_TIMER.lock();
+    match *pinned
+    {
+        Some(timer) => Some(timer.get_mmio_base()),
+        None => None
+    }
+}
+
+/* return true if the pinned timer is part of a classic CLINT, or false if it's an ACLINT MTIMER or there's no timer */
+pub fn pinned_timer_is_clint() -> bool
+{
+    match *(PINNED_TIMER.lock())
+    {
+        Some(timer) => timer.is_aclint() == false,
+        None => false
+    }
+}
+
 /* return the frequency of the pinned timer, or None for no pinned timer */
 pub fn get_pinned_timer_freq() -> Option<u64>
 {
@@ -206,7 +754,25 @@ pub fn get_pinned_timer_freq() -> Option<u64>
     }
 }
 
-/* enable the supervisor's timer interrupt, trigger it, and clear a pending interrupt */
+/* program this CPU core's pinned timer to fire when its value passes the given exact target
+   <= true for success, or false for no pinned timer */
+pub fn set_pinned_timer_target(target: u64) -> bool
+{
+    let pinned = PINNED_TIMER.lock();
+    match *pinned
+    {
+        Some(timer) =>
+        {
+            timer.next_at(TimerValue::Exact(target));
+            true
+        },
+        None => false
+    }
+}
+
+/* enable the supervisor's timer interrupt, trigger it, and clear a pending interrupt.
+   these act on the physical CPU core. to queue a timer interrupt for a virtual CPU core
+   that may be descheduled before it takes the interrupt, use irq::inject() */
 pub fn enable_supervisor_irq()  { unsafe { platform_timer_supervisor_enable();  } }
 pub fn trigger_supervisor_irq() { unsafe { platform_timer_supervisor_trigger(); } }
 pub fn clear_supervisor_irq()   { unsafe { platform_timer_supervisor_clear();   } }
\ No newline at end of file
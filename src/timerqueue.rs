@@ -0,0 +1,258 @@
+/* diosix RV64 per-CPU core timer deadline queues
+ *
+ * Each CPU core has a single timer comparator. Multiplex many
+ * deadlines, such as the scheduler's tick and virtual CPU cores'
+ * timers, onto it by queuing them in deadline order and always
+ * programming the earliest into the timer. irq::dispatch() collects
+ * the deadlines that have passed when the machine timer fires
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::vec::Vec;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::btree_set::BTreeSet;
+use spin::Mutex;
+use super::cpu;
+use super::timer::{self, TimerValue};
+
+lazy_static!
+{
+    /* per-CPU core queues of deadlines, indexed by linear CPU core ID */
+    static ref QUEUES: cpu::PerCpu<Mutex<Queue>> = cpu::PerCpu::new(|| Mutex::new(Queue::new()));
+}
+
+/* each deadline gets a unique sequence number */
+static NEXT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/* program this into the timer to stop it firing */
+const NO_DEADLINE: u64 = u64::MAX;
+
+/* who a deadline belongs to */
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TimerOwner
+{
+    Scheduler,          /* the hypervisor's scheduling tick */
+    VirtualCPU(usize),  /* a virtual CPU core's timer, identified by the hypervisor */
+    Watchdog,           /* the platform's watchdog */
+    Other(usize)        /* anything else, identified by the caller */
+}
+
+/* identify a queued deadline so that it can be cancelled */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TimerHandle
+{
+    cpu: cpu::CPUcount,
+    deadline: u64,
+    sequence: u64
+}
+
+impl TimerHandle
+{
+    /* return the linear ID of the CPU core the deadline is queued on */
+    pub fn get_cpu(&self) -> cpu::CPUcount { self.cpu }
+
+    /* return the deadline as an exact timer value */
+    pub fn get_deadline(&self) -> TimerValue { TimerValue::Exact(self.deadline) }
+}
+
+/* describe a deadline that has passed */
+#[derive(Debug, Copy, Clone)]
+pub struct ExpiredTimer
+{
+    pub handle: TimerHandle,
+    pub owner: TimerOwner
+}
+
+/* a CPU core's queue of deadlines. deadlines are keyed by exact timer value then sequence
+number, so they're sorted with the earliest first, and deadlines that fall due at the same
+time are kept in the order they were added. each owner's keys are indexed too, so that
+cancelling an owner's deadlines doesn't mean searching the whole queue */
+struct Queue
+{
+    deadlines: BTreeMap<(u64, u64), TimerOwner>,
+    owners: BTreeMap<TimerOwner, BTreeSet<(u64, u64)>>
+}
+
+impl Queue
+{
+    fn new() -> Queue
+    {
+        Queue { deadlines: BTreeMap::new(), owners: BTreeMap::new() }
+    }
+
+    fn insert(&mut self, key: (u64, u64), owner: TimerOwner)
+    {
+        self.deadlines.insert(key, owner);
+        self.owners.entry(owner).or_insert_with(BTreeSet::new).insert(key);
+    }
+
+    /* <= owner of the removed deadline, or None if it wasn't queued */
+    fn remove(&mut self, key: &(u64, u64)) -> Option<TimerOwner>
+    {
+        let owner = self.deadlines.remove(key)?;
+        if let Some(keys) = self.owners.get_mut(&owner)
+        {
+            keys.remove(key);
+            if keys.is_empty() == true
+            {
+                self.owners.remove(&owner);
+            }
+        }
+        Some(owner)
+    }
+
+    /* <= number of the owner's deadlines removed */
+    fn remove_owner(&mut self, owner: TimerOwner) -> usize
+    {
+        match self.owners.remove(&owner)
+        {
+            Some(keys) =>
+            {
+                for key in keys.iter()
+                {
+                    self.deadlines.remove(key);
+                }
+                keys.len()
+            },
+            None => 0
+        }
+    }
+
+    /* <= key of the earliest deadline, and its owner, or None if nothing is queued */
+    fn first(&self) -> Option<((u64, u64), TimerOwner)>
+    {
+        self.deadlines.iter().next().map(|(key, owner)| (*key, *owner))
+    }
+}
+
+/* queue a deadline on this CPU core, and reprogram the timer if it's now the earliest
+   => owner = who the deadline belongs to
+      at = timer value at or after which the deadline expires
+   <= handle to cancel the deadline, or None if there's no timer */
+pub fn add(owner: TimerOwner, at: TimerValue) -> Option<TimerHandle>
+{
+    let deadline = at.to_exact(timer::get_pinned_timer_freq()?);
+    let cpu = cpu::get_cpu_id();
+    let handle = TimerHandle
+    {
+        cpu,
+        deadline,
+        sequence: NEXT_SEQUENCE.fetch_add(1, Ordering::SeqCst)
+    };
+
+    let mut queue = QUEUES[cpu].lock();
+    queue.insert((handle.deadline, handle.sequence), owner);
+    program(&queue);
+    Some(handle)
+}
+
+/* queue a deadline on this CPU core a duration from now
+   => owner = who the deadline belongs to
+      duration = number of ticks or sub-seconds from now
+   <= handle to cancel the deadline, or None if there's no timer */
+pub fn add_in(owner: TimerOwner, duration: TimerValue) -> Option<TimerHandle>
+{
+    let freq = timer::get_pinned_timer_freq()?;
+    let now = timer::get_pinned_timer_now()?.to_exact(freq);
+    add(owner, TimerValue::Exact(now.saturating_add(duration.to_exact(freq))))
+}
+
+/* cancel all of an owner's deadlines on this CPU core and queue a new one.
+   use this for owners that only ever have one deadline outstanding
+   => owner = who the deadline belongs to
+      at = timer value at or after which the deadline expires
+   <= handle to cancel the deadline, or None if there's no timer */
+pub fn replace(owner: TimerOwner, at: TimerValue) -> Option<TimerHandle>
+{
+    cancel_owner(owner);
+    add(owner, at)
+}
+
+/* remove a queued deadline. if it was queued on another CPU core, that core's
+   timer may still fire for it, in which case nothing will be reported as expired
+   => handle = deadline to cancel
+   <= true if cancelled, or false if it has already expired or been cancelled */
+pub fn cancel(handle: TimerHandle) -> bool
+{
+    if handle.cpu >= cpu::MAX_CPUS
+    {
+        return false;
+    }
+
+    let mut queue = QUEUES[handle.cpu].lock();
+    let removed = queue.remove(&(handle.deadline, handle.sequence)).is_some();
+    if removed == true && handle.cpu == cpu::get_cpu_id()
+    {
+        program(&queue);
+    }
+    removed
+}
+
+/* remove all of an owner's deadlines on this CPU core
+   => owner = whose deadlines to cancel
+   <= number of deadlines cancelled */
+pub fn cancel_owner(owner: TimerOwner) -> usize
+{
+    let mut queue = QUEUES.this().lock();
+    let cancelled = queue.remove_owner(owner);
+    if cancelled > 0
+    {
+        program(&queue);
+    }
+    cancelled
+}
+
+/* return the earliest deadline queued on this CPU core, or None if nothing is queued */
+pub fn next_deadline() -> Option<TimerValue>
+{
+    QUEUES.this().lock().first().map(|((deadline, _), _)| TimerValue::Exact(deadline))
+}
+
+/* remove the deadlines that have passed on this CPU core, and program the timer for the next.
+   call this when the machine timer interrupt fires
+   <= expired deadlines, earliest first */
+pub fn expired() -> Vec<ExpiredTimer>
+{
+    let cpu = cpu::get_cpu_id();
+    let mut queue = QUEUES[cpu].lock();
+    let mut expired = Vec::new();
+
+    let now = match timer::get_pinned_timer_now()
+    {
+        Some(TimerValue::Exact(now)) => now,
+        _ => return expired
+    };
+
+    while let Some(((deadline, sequence), owner)) = queue.first()
+    {
+        if deadline > now
+        {
+            break;
+        }
+
+        queue.remove(&(deadline, sequence));
+        expired.push(ExpiredTimer
+        {
+            handle: TimerHandle { cpu, deadline, sequence },
+            owner
+        });
+    }
+
+    program(&queue);
+    expired
+}
+
+/* program the timer with the earliest deadline in this CPU core's queue, or stop it if there is none */
+fn program(queue: &Queue)
+{
+    let target = match queue.first()
+    {
+        Some(((deadline, _), _)) => deadline,
+        None => NO_DEADLINE
+    };
+    timer::set_pinned_timer_target(target);
+}
@@ -0,0 +1,168 @@
+/* diosix RV64 per-virtual CPU core clocks
+ *
+ * Each virtual CPU core reads time from its own clock, which is
+ * derived from the host timer by an offset and an optional scale.
+ * A clock can be frozen while its virtual core is paused and resumed
+ * later without the guest seeing time jump. The clock of the
+ * supervisor running on each physical CPU core is loaded along with
+ * the rest of its state, and used to emulate rdtime and to translate
+ * the guest's timer targets into host timer values
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use spin::Mutex;
+use super::cpu;
+use super::timer::{self, Rounding};
+
+lazy_static!
+{
+    /* clocks of the supervisors running on each physical CPU core, indexed by linear CPU core ID */
+    static ref RUNNING: cpu::PerCpu<Mutex<VirtualClock>> = cpu::PerCpu::new(|| Mutex::new(VirtualClock::new()));
+}
+
+/* describe a virtual CPU core's clock. at host time base_host, the guest's time was
+base_guest, and since then guest time has advanced by scale_mul / scale_div ticks per
+host tick. while paused, guest time stands still at the paused value */
+#[derive(Debug, Copy, Clone)]
+pub struct VirtualClock
+{
+    base_host: u64,
+    base_guest: u64,
+    scale_mul: u64,
+    scale_div: u64,
+    paused: bool,
+    paused_at: u64
+}
+
+impl VirtualClock
+{
+    /* create a clock that matches the host timer */
+    pub fn new() -> VirtualClock
+    {
+        VirtualClock
+        {
+            base_host: 0,
+            base_guest: 0,
+            scale_mul: 1,
+            scale_div: 1,
+            paused: false,
+            paused_at: 0
+        }
+    }
+
+    /* create a clock that starts from zero and ticks at a fraction of the host timer's rate
+       => host_now = host timer's current exact value
+          mul, div = guest ticks per host tick, as a fraction. neither can be zero
+       <= new clock, or None for a bad scale */
+    pub fn new_scaled(host_now: u64, mul: u64, div: u64) -> Option<VirtualClock>
+    {
+        if mul == 0 || div == 0
+        {
+            return None;
+        }
+
+        Some(VirtualClock
+        {
+            base_host: host_now,
+            base_guest: 0,
+            scale_mul: mul,
+            scale_div: div,
+            paused: false,
+            paused_at: 0
+        })
+    }
+
+    /* return true if this clock reads the same as the host timer. the guest can then be
+       allowed to read the host timer and program its timer compare directly */
+    pub fn is_identity(&self) -> bool
+    {
+        self.paused == false && self.scale_mul == self.scale_div && self.base_host == self.base_guest
+    }
+
+    /* return true if the clock is frozen */
+    pub fn is_paused(&self) -> bool { self.paused }
+
+    /* return the rate the guest's clock ticks at, in Hz, given the host timer's frequency.
+       advertise this to the guest as its timebase frequency */
+    pub fn get_frequency(&self, host_freq: u64) -> u64
+    {
+        timer::scale(host_freq, self.scale_mul, self.scale_div, Rounding::Down)
+    }
+
+    /* return the guest's time
+       => host_now = host timer's current exact value
+       <= guest's current exact timer value */
+    pub fn now(&self, host_now: u64) -> u64
+    {
+        if self.paused == true
+        {
+            return self.paused_at;
+        }
+
+        let elapsed = timer::scale(host_now.saturating_sub(self.base_host), self.scale_mul, self.scale_div, Rounding::Down);
+        self.base_guest.saturating_add(elapsed)
+    }
+
+    /* freeze the guest's time
+       => host_now = host timer's current exact value */
+    pub fn pause(&mut self, host_now: u64)
+    {
+        if self.paused == false
+        {
+            self.paused_at = self.now(host_now);
+            self.paused = true;
+        }
+    }
+
+    /* continue the guest's time from where it was paused
+       => host_now = host timer's current exact value */
+    pub fn resume(&mut self, host_now: u64)
+    {
+        if self.paused == true
+        {
+            self.base_guest = self.paused_at;
+            self.base_host = host_now;
+            self.paused = false;
+        }
+    }
+
+    /* convert a guest timer target into the host timer value at which it falls due.
+       this rounds up so that the guest's timer never fires early
+       => target = guest's exact timer value
+       <= host's exact timer value, or None if the clock is paused and will never reach it */
+    pub fn to_host(&self, target: u64) -> Option<u64>
+    {
+        if self.paused == true
+        {
+            return None;
+        }
+
+        /* targets in the guest's past fall due straight away */
+        let remaining = timer::scale(target.saturating_sub(self.base_guest), self.scale_div, self.scale_mul, Rounding::Up);
+        Some(self.base_host.saturating_add(remaining))
+    }
+}
+
+/* make the given clock the one used by the supervisor running on this CPU core.
+   this is called when the supervisor's state is loaded */
+pub fn load(clock: &VirtualClock)
+{
+    *(RUNNING.this().lock()) = *clock;
+}
+
+/* return a copy of the running supervisor's clock on this CPU core */
+pub fn running() -> VirtualClock
+{
+    *(RUNNING.this().lock())
+}
+
+/* return the running supervisor's time on this CPU core, or None if there's no host timer */
+pub fn running_now() -> Option<u64>
+{
+    let freq = timer::get_pinned_timer_freq()?;
+    let host_now = timer::get_pinned_timer_now()?.to_exact(freq);
+    Some(running().now(host_now))
+}
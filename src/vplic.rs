@@ -0,0 +1,439 @@
+/* diosix RV64 virtual platform-level interrupt controller (PLIC) for guests
+ *
+ * Each capsule can be given a virtual PLIC that emulates the register
+ * layout of a real one. The hypervisor forwards the guest's accesses to the
+ * controller's MMIO window to read() and write(). Physical PLIC sources and
+ * virtual device events are mapped to a capsule's virtual interrupt lines
+ * via the routing table below, and raised with deliver() on the virtual
+ * PLICs the hypervisor has attached to the table.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use spin::Mutex;
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+use super::cpu;
+use super::irq;
+use super::plic;
+
+lazy_static!
+{
+    /* acquire ROUTES lock before accessing the interrupt routing table */
+    static ref ROUTES: Mutex<Vec<Route>> = Mutex::new(Vec::new());
+
+    /* acquire ATTACHED lock to access the (capsule ID, virtual PLIC) pairs that routed interrupts are raised on */
+    static ref ATTACHED: Mutex<Vec<(usize, Arc<Mutex<VirtualPlic>>)>> = Mutex::new(Vec::new());
+}
+
+/* where a virtual PLIC appears in a guest's physical memory map */
+pub const VIRTUAL_PLIC_BASE: usize = 0x0c000000;
+pub const VIRTUAL_PLIC_SIZE: usize = 0x4000000;
+
+/* number of interrupt lines provided by each virtual PLIC, not including line 0 */
+pub const VIRTUAL_PLIC_NR_SOURCES: usize = 127;
+
+/* priorities are three bits wide, as they are on Qemu and SiFive hardware */
+pub const VIRTUAL_PLIC_MAX_PRIORITY: u32 = 7;
+
+/* emulated registers, relative to the controller's base address.
+   these match the layout of the physical PLIC (see plic.rs) */
+const PRIORITY_BASE:        usize = 0x0;
+const PENDING_BASE:         usize = 0x1000;
+const ENABLE_BASE:          usize = 0x2000;
+const ENABLE_STRIDE:        usize = 0x80;
+const CONTEXT_BASE:         usize = 0x200000;
+const CONTEXT_STRIDE:       usize = 0x1000;
+const CONTEXT_THRESHOLD:    usize = 0x0;
+const CONTEXT_CLAIM:        usize = 0x4;
+
+/* number of 32-bit words needed to hold one bit per line, including line 0 */
+const BITMAP_WORDS: usize = (VIRTUAL_PLIC_NR_SOURCES + 1 + 31) / 32;
+
+/* per-context state. each virtual CPU core has one supervisor-level context,
+   numbered from 0 upwards to match the vCPU's hart ID */
+#[derive(Debug, Clone)]
+struct Context
+{
+    enables: [u32; BITMAP_WORDS],   /* one enable bit per line */
+    threshold: u32                  /* lines at or below this priority are masked */
+}
+
+/* describe a capsule's virtual PLIC */
+#[derive(Debug, Clone)]
+pub struct VirtualPlic
+{
+    priorities: [u32; VIRTUAL_PLIC_NR_SOURCES + 1], /* priority per line. line 0 is reserved */
+    pending: [u32; BITMAP_WORDS],                   /* one pending bit per line */
+    claimed: [u32; BITMAP_WORDS],                   /* lines claimed but not yet completed */
+    contexts: Vec<Context>                          /* one context per vCPU */
+}
+
+impl VirtualPlic
+{
+    /* create a virtual PLIC with all lines masked and nothing pending
+       => vcpus = number of virtual CPU cores in the capsule
+       <= virtual PLIC object */
+    pub fn new(vcpus: usize) -> VirtualPlic
+    {
+        let mut contexts = Vec::new();
+        for _ in 0..vcpus
+        {
+            contexts.push(Context
+            {
+                enables: [0; BITMAP_WORDS],
+                threshold: 0
+            });
+        }
+
+        VirtualPlic
+        {
+            priorities: [0; VIRTUAL_PLIC_NR_SOURCES + 1],
+            pending: [0; BITMAP_WORDS],
+            claimed: [0; BITMAP_WORDS],
+            contexts
+        }
+    }
+
+    /* return the number of contexts, one per vCPU */
+    pub fn get_nr_contexts(&self) -> usize { self.contexts.len() }
+
+    /* emulate a guest's 32-bit read from the virtual PLIC's registers.
+       reading a context's claim register claims its highest-priority interrupt
+       => offset = byte offset of the register from the virtual PLIC's base address
+       <= value read, or None for a bad or unaligned offset */
+    pub fn read(&mut self, offset: usize) -> Option<u32>
+    {
+        if offset & 3 != 0 { return None; }
+
+        match decode(offset)?
+        {
+            Register::Priority(line) => Some(self.priorities[line]),
+            Register::Pending(word) => Some(self.pending[word]),
+            Register::Enable(context, word) => Some(self.contexts.get(context)?.enables[word]),
+            Register::Threshold(context) => Some(self.contexts.get(context)?.threshold),
+            Register::Claim(context) =>
+            {
+                if context >= self.contexts.len() { return None; }
+                match self.best_pending(context)
+                {
+                    Some(line) =>
+                    {
+                        clear_bit(&mut self.pending, line);
+                        set_bit(&mut self.claimed, line);
+                        Some(line as u32)
+                    },
+                    None => Some(0) /* nothing to claim */
+                }
+            }
+        }
+    }
+
+    /* emulate a guest's 32-bit write to the virtual PLIC's registers
+       => offset = byte offset of the register from the virtual PLIC's base address
+          value = value to write
+       <= Ok(Some(line)) if this write completed a claimed line,
+          Ok(None) for any other successful write,
+          or Err(()) for a bad or unaligned offset */
+    pub fn write(&mut self, offset: usize, value: u32) -> Result<Option<usize>, ()>
+    {
+        if offset & 3 != 0 { return Err(()); }
+
+        match decode(offset).ok_or(())?
+        {
+            Register::Priority(line) =>
+            {
+                if line > 0
+                {
+                    self.priorities[line] = value & VIRTUAL_PLIC_MAX_PRIORITY;
+                }
+            },
+            Register::Pending(_) => (), /* pending bits are read-only */
+            Register::Enable(context, word) =>
+            {
+                let ctx = self.contexts.get_mut(context).ok_or(())?;
+                /* line 0 does not exist so its enable bit is hardwired to zero */
+                ctx.enables[word] = if word == 0 { value & !1 } else { value };
+            },
+            Register::Threshold(context) =>
+            {
+                self.contexts.get_mut(context).ok_or(())?.threshold = value & VIRTUAL_PLIC_MAX_PRIORITY;
+            },
+            Register::Claim(context) =>
+            {
+                let line = value as usize;
+                if context >= self.contexts.len() { return Err(()); }
+
+                /* completing a line that isn't claimed, or isn't enabled for
+                   this context, is silently ignored, as per the spec */
+                if line > 0 && line <= VIRTUAL_PLIC_NR_SOURCES
+                    && test_bit(&self.claimed, line) && test_bit(&self.contexts[context].enables, line)
+                {
+                    clear_bit(&mut self.claimed, line);
+                    return Ok(Some(line));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /* raise a virtual interrupt line. the line is held pending until claimed.
+       => line = virtual interrupt line to raise
+       <= true for success, or false for bad line number */
+    pub fn raise(&mut self, line: usize) -> bool
+    {
+        if line == 0 || line > VIRTUAL_PLIC_NR_SOURCES { return false; }
+        set_bit(&mut self.pending, line);
+        true
+    }
+
+    /* withdraw a pending virtual interrupt line before it's claimed
+       => line = virtual interrupt line to lower
+       <= true for success, or false for bad line number */
+    pub fn lower(&mut self, line: usize) -> bool
+    {
+        if line == 0 || line > VIRTUAL_PLIC_NR_SOURCES { return false; }
+        clear_bit(&mut self.pending, line);
+        true
+    }
+
+    /* return true if the given context's external interrupt should be asserted */
+    pub fn is_asserted(&self, context: usize) -> bool
+    {
+        self.best_pending(context).is_some()
+    }
+
+    /* update the virtual external interrupt pending bit of a descheduled vCPU
+       so that the interrupt is delivered, or withdrawn, when it next runs
+       => context = context number of the vCPU
+          state = the vCPU's saved supervisor state */
+    pub fn update_vcpu(&self, context: usize, state: &mut cpu::SupervisorState)
+    {
+        match self.is_asserted(context)
+        {
+            true => irq::inject(state, irq::IRQCause::SupervisorInterrupt),
+            false => irq::retract(state, irq::IRQCause::SupervisorInterrupt)
+        };
+    }
+
+    /* update the virtual external interrupt pending bit of the vCPU running
+       on this physical CPU core. only call from an IRQ context
+       => context = context number of the running vCPU */
+    pub fn update_running_vcpu(&self, context: usize)
+    {
+        match self.is_asserted(context)
+        {
+            true => irq::inject_running(irq::IRQCause::SupervisorInterrupt),
+            false => irq::retract_running(irq::IRQCause::SupervisorInterrupt)
+        };
+    }
+
+    /* find the highest-priority line pending, enabled and above the threshold for the given
+       context that isn't already claimed. where priorities are equal, the lowest line wins
+       <= line number, or None if nothing's eligible */
+    fn best_pending(&self, context: usize) -> Option<usize>
+    {
+        let ctx = self.contexts.get(context)?;
+        let mut best: Option<(usize, u32)> = None;
+
+        for line in 1..(VIRTUAL_PLIC_NR_SOURCES + 1)
+        {
+            if test_bit(&self.pending, line) && test_bit(&ctx.enables, line)
+                && test_bit(&self.claimed, line) == false
+            {
+                let priority = self.priorities[line];
+                if priority > ctx.threshold
+                {
+                    match best
+                    {
+                        Some((_, p)) if p >= priority => (),
+                        _ => best = Some((line, priority))
+                    }
+                }
+            }
+        }
+
+        match best
+        {
+            Some((line, _)) => Some(line),
+            None => None
+        }
+    }
+}
+
+/* registers that can be accessed within the virtual PLIC */
+enum Register
+{
+    Priority(usize),        /* priority of a line */
+    Pending(usize),         /* word of pending bits */
+    Enable(usize, usize),   /* (context, word) of enable bits */
+    Threshold(usize),       /* a context's priority threshold */
+    Claim(usize)            /* a context's claim/complete register */
+}
+
+/* convert a byte offset into the virtual PLIC's MMIO area into a register, or None for no such register */
+fn decode(offset: usize) -> Option<Register>
+{
+    if offset < PENDING_BASE
+    {
+        let line = (offset - PRIORITY_BASE) / 4;
+        return if line <= VIRTUAL_PLIC_NR_SOURCES { Some(Register::Priority(line)) } else { None };
+    }
+
+    if offset < ENABLE_BASE
+    {
+        let word = (offset - PENDING_BASE) / 4;
+        return if word < BITMAP_WORDS { Some(Register::Pending(word)) } else { None };
+    }
+
+    if offset < CONTEXT_BASE
+    {
+        let context = (offset - ENABLE_BASE) / ENABLE_STRIDE;
+        let word = ((offset - ENABLE_BASE) % ENABLE_STRIDE) / 4;
+        return if word < BITMAP_WORDS { Some(Register::Enable(context, word)) } else { None };
+    }
+
+    if offset < VIRTUAL_PLIC_SIZE
+    {
+        let context = (offset - CONTEXT_BASE) / CONTEXT_STRIDE;
+        return match (offset - CONTEXT_BASE) % CONTEXT_STRIDE
+        {
+            CONTEXT_THRESHOLD => Some(Register::Threshold(context)),
+            CONTEXT_CLAIM => Some(Register::Claim(context)),
+            _ => None
+        };
+    }
+
+    None
+}
+
+/* manipulate bits in a bitmap of interrupt lines */
+fn test_bit(bitmap: &[u32], line: usize) -> bool { bitmap[line / 32] & (1 << (line % 32)) != 0 }
+fn set_bit(bitmap: &mut [u32], line: usize)      { bitmap[line / 32] |= 1 << (line % 32); }
+fn clear_bit(bitmap: &mut [u32], line: usize)    { bitmap[line / 32] &= !(1 << (line % 32)); }
+
+/* source of an interrupt that can be routed to a capsule */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RouteSource
+{
+    Physical(usize),    /* physical PLIC source number */
+    Virtual(usize)      /* hypervisor-defined virtual device event number, eg: a virtio device */
+}
+
+/* map an interrupt source to a capsule's virtual interrupt line */
+#[derive(Debug, Clone, Copy)]
+pub struct Route
+{
+    pub source: RouteSource,    /* where the interrupt comes from */
+    pub capsule: usize,         /* ID of the capsule to deliver the interrupt to */
+    pub line: usize             /* virtual PLIC line to raise in the capsule */
+}
+
+/* add an interrupt route. a source can be routed to more than one capsule
+   => source = physical or virtual interrupt source
+      capsule = ID of capsule to receive the interrupt
+      line = virtual PLIC line to raise in the capsule
+   <= true for success, or false if the line is invalid or already routed in this capsule */
+pub fn add_route(source: RouteSource, capsule: usize, line: usize) -> bool
+{
+    if line == 0 || line > VIRTUAL_PLIC_NR_SOURCES { return false; }
+
+    let mut routes = ROUTES.lock();
+    if routes.iter().any(|r| r.capsule == capsule && r.line == line) == true
+    {
+        return false;
+    }
+
+    routes.push(Route { source, capsule, line });
+    true
+}
+
+/* remove all routes from the given source */
+pub fn remove_routes_from(source: RouteSource)
+{
+    ROUTES.lock().retain(|r| r.source != source);
+}
+
+/* remove all routes to the given capsule, eg: when it's destroyed */
+pub fn remove_routes_to(capsule: usize)
+{
+    ROUTES.lock().retain(|r| r.capsule != capsule);
+}
+
+/* return the list of (capsule ID, virtual line) pairs the given source is routed to */
+pub fn lookup_routes(source: RouteSource) -> Vec<(usize, usize)>
+{
+    ROUTES.lock().iter().filter(|r| r.source == source).map(|r| (r.capsule, r.line)).collect()
+}
+
+/* return the source routed to a capsule's virtual line, if any. use this to find
+   the physical PLIC source to complete after a guest completes a routed line */
+pub fn lookup_source(capsule: usize, line: usize) -> Option<RouteSource>
+{
+    ROUTES.lock().iter().find(|r| r.capsule == capsule && r.line == line).map(|r| r.source)
+}
+
+/* connect a capsule's virtual PLIC to the routing table, so that interrupts routed to
+   the capsule are raised on it. this replaces any virtual PLIC already attached for the capsule
+   => capsule = ID of the capsule
+      vplic = the capsule's virtual PLIC, shared with the hypervisor */
+pub fn attach(capsule: usize, vplic: Arc<Mutex<VirtualPlic>>)
+{
+    let mut attached = ATTACHED.lock();
+    attached.retain(|(c, _)| *c != capsule);
+    attached.push((capsule, vplic));
+}
+
+/* disconnect a capsule's virtual PLIC and remove all routes to it, eg: when it's destroyed */
+pub fn detach(capsule: usize)
+{
+    ATTACHED.lock().retain(|(c, _)| *c != capsule);
+    remove_routes_to(capsule);
+}
+
+/* raise an interrupt source's routed lines on the attached virtual PLICs. a physical source
+   should be left claimed on the physical PLIC until the guest completes the line: see complete()
+   => source = interrupt source that fired
+   <= list of (capsule ID, virtual line) pairs raised. the hypervisor should update those
+      capsules' vCPUs with update_vcpu() or update_running_vcpu() so that they're interrupted */
+pub fn deliver(source: RouteSource) -> Vec<(usize, usize)>
+{
+    let routes = lookup_routes(source);
+    let attached = ATTACHED.lock();
+    let mut raised = Vec::new();
+
+    for (capsule, line) in routes
+    {
+        if let Some((_, vplic)) = attached.iter().find(|(c, _)| *c == capsule)
+        {
+            if vplic.lock().raise(line) == true
+            {
+                raised.push((capsule, line));
+            }
+        }
+    }
+
+    raised
+}
+
+/* tell the physical PLIC a routed interrupt has been dealt with, once the guest completes
+   its virtual line, ie: when VirtualPlic::write() returns the completed line. call this on
+   the physical CPU core that claimed the source, as completion goes to this core's context
+   => capsule = ID of the capsule that completed the line
+      line = virtual line completed
+   <= true if a physical source was completed, or false if none is routed to the line */
+pub fn complete(capsule: usize, line: usize) -> bool
+{
+    match lookup_source(capsule, line)
+    {
+        Some(RouteSource::Physical(source)) =>
+        {
+            plic::complete(source);
+            true
+        },
+        _ => false
+    }
+}
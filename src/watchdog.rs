@@ -0,0 +1,395 @@
+/* diosix RV64 hypervisor watchdog
+ *
+ * Each CPU core pets the watchdog from its scheduler tick. Cores
+ * check each other, one core at a time and at a limited rate: if a
+ * core hasn't petted the watchdog for a while, it's sent an IPI, and
+ * if it doesn't respond in time, the configured action is taken.
+ * Callbacks are deferred until the hypervisor runs them outside of
+ * an IRQ context. If the system has
+ * a SiFive watchdog, it's fed while all the cores are responsive, so
+ * that the system is reset if every core wedges, or if a stuck core
+ * is to be dealt with by resetting the system
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::ptr::write_volatile;
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::vec::Vec;
+use spin::Mutex;
+use super::cpu;
+use super::ipi;
+use super::timer::{self, TimerValue, Rounding};
+use super::timerqueue::{self, TimerOwner, ExpiredTimer};
+use super::crashdump;
+use super::physmem;
+
+lazy_static!
+{
+    /* acquire WATCHDOG lock to access the watchdog's configuration. None until started */
+    static ref WATCHDOG: Mutex<Option<Watchdog>> = Mutex::new(None);
+
+    /* acquire PINNED_WDOG lock to access the system's hardware watchdog, if any */
+    static ref PINNED_WDOG: Mutex<Option<SiFiveWatchdog>> = Mutex::new(None);
+
+    /* exact timer value at which each CPU core last petted the watchdog, indexed by linear
+    CPU core ID. zero means the core hasn't started petting it, so isn't checked */
+    static ref LAST_PET: cpu::PerCpu<AtomicU64> = cpu::PerCpu::new(|| AtomicU64::new(0));
+
+    /* outstanding liveness checks sent to each CPU core, indexed by linear CPU core ID */
+    static ref PINGS: cpu::PerCpu<Mutex<Option<Ping>>> = cpu::PerCpu::new(|| Mutex::new(None));
+}
+
+/* exact timer value at or after which the next check of the cores is due. only
+the core that moves this on carries out the check */
+static NEXT_CHECK: AtomicU64 = AtomicU64::new(0);
+
+/* the cores are checked this many times per timeout */
+const CHECKS_PER_TIMEOUT: u64 = 4;
+
+/* bitmask of linear CPU core IDs found stuck, whose callbacks are still to be run */
+static PENDING_CALLBACKS: AtomicU64 = AtomicU64::new(0);
+
+/* SiFive watchdog registers, relative to its base address. every write to them
+must be immediately preceded by writing SIFIVE_WDOG_KEY_VALUE to the key register */
+const SIFIVE_WDOG_CFG:   usize = 0x00;
+const SIFIVE_WDOG_COUNT: usize = 0x08;
+const SIFIVE_WDOG_FEED:  usize = 0x18;
+const SIFIVE_WDOG_KEY:   usize = 0x1c;
+const SIFIVE_WDOG_CMP0:  usize = 0x20;
+
+const SIFIVE_WDOG_KEY_VALUE:  u32 = 0x51f15e;
+const SIFIVE_WDOG_FEED_VALUE: u32 = 0xd09f00d;
+
+/* SiFive watchdog configuration bits */
+const SIFIVE_WDOG_CFG_SCALE_MAX: u32 = 0xf;      /* counter is compared in units of 2^scale ticks */
+const SIFIVE_WDOG_CFG_RSTEN:     u32 = 1 << 8;   /* reset the system when the counter reaches cmp0 */
+const SIFIVE_WDOG_CFG_ENALWAYS:  u32 = 1 << 12;  /* always count */
+const SIFIVE_WDOG_CMP_MAX:       u64 = 0xffff;
+
+/* the SiFive watchdog is clocked by the always-on block's low-frequency clock */
+const SIFIVE_WDOG_CLOCK: u64 = 32768;
+
+/* the hardware watchdog is given this many times the software timeout, so that the
+configured action is taken for a stuck core before the hardware resets the system */
+const HARDWARE_TIMEOUT_MULTIPLIER: u64 = 2;
+
+/* what to do when a CPU core stops responding */
+#[derive(Debug, Clone, Copy)]
+pub enum WatchdogAction
+{
+    LogAndDump,                         /* report the core and its trap counters on the crash console */
+    Reset,                              /* reset the system via the hardware watchdog, if there is one */
+    Callback(fn(cpu::CPUcount))         /* call the hypervisor with the linear ID of the stuck core, from run_callbacks() */
+}
+
+/* the watchdog's configuration */
+#[derive(Debug, Clone, Copy)]
+struct Watchdog
+{
+    timeout: u64,               /* exact timer ticks a core may go without petting the watchdog */
+    action: WatchdogAction
+}
+
+/* a liveness check sent to a CPU core that has stopped petting the watchdog */
+struct Ping
+{
+    sent: u64,                  /* exact timer value when the check was sent */
+    acted: bool                 /* true if the action has been taken for this core */
+}
+
+/* describe a SiFive watchdog, as found in the always-on (AON) block */
+#[derive(Debug, Clone, Copy)]
+pub struct SiFiveWatchdog
+{
+    base: physmem::PhysMemBase,
+    size: physmem::PhysMemSize
+}
+
+impl SiFiveWatchdog
+{
+    /* create a new watchdog object. the watchdog isn't started
+       => base, size = base address and size of the watchdog's MMIO area
+       <= watchdog object */
+    pub fn new(base: physmem::PhysMemBase, size: physmem::PhysMemSize) -> SiFiveWatchdog
+    {
+        SiFiveWatchdog { base, size }
+    }
+
+    /* register this watchdog as the pinned watchdog, allowing other platform code to find it */
+    pub fn pin(&self)
+    {
+        *(PINNED_WDOG.lock()) = Some(self.clone());
+    }
+
+    /* return base MMIO address and size of the watchdog */
+    pub fn get_mmio_base(&self) -> physmem::PhysMemBase { self.base }
+    pub fn get_mmio_size(&self) -> physmem::PhysMemSize { self.size }
+
+    /* start the watchdog so that it resets the system if not fed in time
+       => timeout = reset after this duration without being fed */
+    pub fn start(&self, timeout: core::time::Duration)
+    {
+        /* find the finest scale at which the timeout fits in the 16-bit comparator */
+        let ticks = timer::scale(timeout.as_nanos() as u64, SIFIVE_WDOG_CLOCK, 1000 * 1000 * 1000, Rounding::Up);
+        let mut scale = 0;
+        while (ticks >> scale) > SIFIVE_WDOG_CMP_MAX && scale < SIFIVE_WDOG_CFG_SCALE_MAX
+        {
+            scale = scale + 1;
+        }
+        let cmp = core::cmp::min(core::cmp::max(ticks >> scale, 1), SIFIVE_WDOG_CMP_MAX) as u32;
+
+        self.write(SIFIVE_WDOG_CFG, 0);
+        self.write(SIFIVE_WDOG_COUNT, 0);
+        self.write(SIFIVE_WDOG_CMP0, cmp);
+        self.write(SIFIVE_WDOG_CFG, scale | SIFIVE_WDOG_CFG_RSTEN | SIFIVE_WDOG_CFG_ENALWAYS);
+    }
+
+    /* restart the watchdog's countdown */
+    pub fn feed(&self)
+    {
+        self.write(SIFIVE_WDOG_FEED, SIFIVE_WDOG_FEED_VALUE);
+    }
+
+    /* reset the system right away by dropping the comparator below the counter */
+    pub fn reset_now(&self)
+    {
+        self.write(SIFIVE_WDOG_CMP0, 0);
+        self.write(SIFIVE_WDOG_CFG, SIFIVE_WDOG_CFG_RSTEN | SIFIVE_WDOG_CFG_ENALWAYS);
+    }
+
+    /* unlock and write to one of the watchdog's registers */
+    fn write(&self, register: usize, value: u32)
+    {
+        unsafe
+        {
+            write_volatile((self.base + SIFIVE_WDOG_KEY) as *mut u32, SIFIVE_WDOG_KEY_VALUE);
+            write_volatile((self.base + register) as *mut u32, value);
+        }
+    }
+}
+
+/* start the watchdog. each CPU core is checked once it first pets the watchdog
+   => timeout = duration a core may go without petting the watchdog before it's checked
+      action = what to do if a checked core doesn't respond within the timeout
+   <= true for success, or false if there's no timer to measure time with,
+      or the action is to reset the system and there's no hardware watchdog to do it */
+pub fn start(timeout: TimerValue, action: WatchdogAction) -> bool
+{
+    let freq = match timer::get_pinned_timer_freq()
+    {
+        Some(f) => f,
+        None => return false
+    };
+    let timeout = timeout.to_exact(freq);
+
+    match (*(PINNED_WDOG.lock()), action)
+    {
+        (Some(wdog), _) =>
+        {
+            let hardware_timeout = TimerValue::Exact(timeout.saturating_mul(HARDWARE_TIMEOUT_MULTIPLIER));
+            wdog.start(hardware_timeout.to_duration(freq));
+        },
+        (None, WatchdogAction::Reset) => return false,
+        (None, _) => ()
+    }
+
+    NEXT_CHECK.store(0, Ordering::SeqCst);
+    *(WATCHDOG.lock()) = Some(Watchdog { timeout, action });
+    true
+}
+
+/* tell the watchdog this CPU core is alive, and queue a deadline so that this core comes
+   back to pet the watchdog even if its scheduler tick is stopped. if a check of the other
+   cores is due, and no other core has claimed it, check them too. call this from the
+   scheduler tick path */
+pub fn pet()
+{
+    let config = match *(WATCHDOG.lock())
+    {
+        Some(c) => c,
+        None => return
+    };
+
+    let now = match (timer::get_pinned_timer_now(), timer::get_pinned_timer_freq())
+    {
+        (Some(now), Some(freq)) => now.to_exact(freq),
+        (_, _) => return
+    };
+
+    touch(cpu::get_cpu_id(), now);
+    timerqueue::replace(TimerOwner::Watchdog, TimerValue::Exact(now.saturating_add(config.timeout / 2)));
+
+    /* claim the check if it's due, so that only one core carries it out, and not on every tick */
+    let due = NEXT_CHECK.load(Ordering::SeqCst);
+    let next = now.saturating_add(core::cmp::max(config.timeout / CHECKS_PER_TIMEOUT, 1));
+    if now < due || NEXT_CHECK.compare_exchange(due, next, Ordering::SeqCst, Ordering::SeqCst).is_err()
+    {
+        return;
+    }
+
+    /* keep feeding the hardware watchdog unless a stuck core is to be dealt with by a reset */
+    let responsive = check(&config, now);
+    if responsive == true || matches!(config.action, WatchdogAction::Reset) == false
+    {
+        if let Some(wdog) = *(PINNED_WDOG.lock())
+        {
+            wdog.feed();
+        }
+    }
+}
+
+/* pet the watchdog if its deadline is among those that fell due on this CPU core
+   => expired = deadlines that fell due */
+pub fn handle_expired(expired: &Vec<ExpiredTimer>)
+{
+    if expired.iter().any(|t| t.owner == TimerOwner::Watchdog) == true
+    {
+        pet();
+    }
+}
+
+/* record that a CPU core is alive
+   => cpu = linear CPU core ID of the core
+      now = exact timer value */
+fn touch(cpu: cpu::CPUcount, now: u64)
+{
+    /* zero means not started, so avoid it */
+    LAST_PET[cpu].store(core::cmp::max(now, 1), Ordering::SeqCst);
+}
+
+/* check the other CPU cores are responsive, pinging those that haven't petted the
+   watchdog recently, and taking action against those that don't respond in time
+   => config = watchdog configuration
+      now = exact timer value
+   <= true if all the cores are responsive, or false if any are stuck */
+fn check(config: &Watchdog, now: u64) -> bool
+{
+    let myself = cpu::get_cpu_id();
+    let mut responsive = true;
+
+    for cpu in 0..cpu::nr_booted_cpus()
+    {
+        let last = LAST_PET[cpu].load(Ordering::SeqCst);
+        if cpu == myself || last == 0
+        {
+            continue;
+        }
+
+        /* give up rather than spin if the core is being checked by another core */
+        let mut ping = match PINGS[cpu].try_lock()
+        {
+            Some(p) => p,
+            None => continue
+        };
+
+        /* cores that have petted the watchdog recently need no further checks */
+        let stalled = now.saturating_sub(last);
+        if stalled < config.timeout / 2
+        {
+            *ping = None;
+            continue;
+        }
+
+        match &mut *ping
+        {
+            /* ask the core to prove it's alive by responding to an IPI. if it does, it
+            calls alive(), and it'll be seen to have petted the watchdog recently */
+            None =>
+            {
+                ipi::send_ipi(cpu);
+                *ping = Some(Ping { sent: now, acted: false });
+            },
+
+            /* the core failed to respond in time */
+            Some(p) if now.saturating_sub(p.sent) >= config.timeout =>
+            {
+                responsive = false;
+                if p.acted == false
+                {
+                    p.acted = true;
+                    act(config.action, cpu, stalled);
+                }
+            },
+
+            /* still waiting for a response */
+            Some(_) => ()
+        }
+    }
+
+    responsive
+}
+
+/* record that this CPU core is alive. call this when the core takes an IPI, which
+   answers any liveness check sent to it */
+pub fn alive()
+{
+    if WATCHDOG.lock().is_none()
+    {
+        return;
+    }
+
+    if let (Some(now), Some(freq)) = (timer::get_pinned_timer_now(), timer::get_pinned_timer_freq())
+    {
+        touch(cpu::get_cpu_id(), now.to_exact(freq));
+    }
+}
+
+/* take action against a stuck CPU core
+   => action = what to do
+      cpu = linear CPU core ID of the stuck core
+      stalled = exact timer ticks since it last petted the watchdog */
+fn act(action: WatchdogAction, cpu: cpu::CPUcount, stalled: u64)
+{
+    let stalled = match timer::get_pinned_timer_freq()
+    {
+        Some(freq) => TimerValue::Exact(stalled).to_duration(freq),
+        None => core::time::Duration::from_secs(0)
+    };
+
+    match action
+    {
+        WatchdogAction::LogAndDump => crashdump::unresponsive(cpu, stalled),
+        WatchdogAction::Reset =>
+        {
+            /* start() makes sure there's a hardware watchdog to reset the system with */
+            crashdump::unresponsive(cpu, stalled);
+            if let Some(wdog) = *(PINNED_WDOG.lock())
+            {
+                wdog.reset_now();
+            }
+        },
+        WatchdogAction::Callback(_) =>
+        {
+            PENDING_CALLBACKS.fetch_or(1 << cpu, Ordering::SeqCst);
+        }
+    }
+}
+
+/* run the configured callback for each CPU core found stuck since the last call. the callback
+   isn't run from the IRQ context that found the core stuck, so call this regularly from the
+   hypervisor's main loop, outside of an IRQ context */
+pub fn run_callbacks()
+{
+    let stuck = PENDING_CALLBACKS.swap(0, Ordering::SeqCst);
+    if stuck == 0
+    {
+        return;
+    }
+
+    let callback = match *(WATCHDOG.lock())
+    {
+        Some(Watchdog { action: WatchdogAction::Callback(f), .. }) => f,
+        _ => return
+    };
+
+    for cpu in 0..cpu::MAX_CPUS
+    {
+        if stuck & (1 << cpu) != 0
+        {
+            callback(cpu);
+        }
+    }
+}